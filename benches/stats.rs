@@ -0,0 +1,45 @@
+use coin_data::{get_coin_info, rsi, volatility, CoinData, PriceData, RSI_PERIOD};
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+
+// About a year of minute prices, wandering deterministically so every run
+// measures the same series.
+const POINTS: u64 = 500_000;
+
+fn history() -> CoinData {
+    CoinData {
+        data: (0..POINTS)
+            .map(|i| PriceData {
+                priceUsd: format!("{:.4}", 20_000.0 + 5_000.0 * ((i as f64) / 997.0).sin()),
+                time: 1_356_998_400_000 + i * 60_000,
+            })
+            .collect(),
+    }
+}
+
+fn bench_stats(c: &mut Criterion) {
+    let data = history();
+    let prices: Vec<f64> = data
+        .data
+        .iter()
+        .map(|point| point.priceUsd.parse().unwrap())
+        .collect();
+
+    let info = futures::executor::block_on(get_coin_info(data.clone(), "bench")).unwrap();
+    assert_eq!(info.points, POINTS as usize);
+    c.bench_function("get_coin_info", |b| {
+        b.iter_batched(
+            || data.clone(),
+            |data| futures::executor::block_on(get_coin_info(data, "bench")).unwrap(),
+            BatchSize::LargeInput,
+        )
+    });
+
+    assert!(rsi(&prices, RSI_PERIOD).is_some());
+    c.bench_function("rsi", |b| b.iter(|| rsi(black_box(&prices), RSI_PERIOD)));
+
+    assert!(volatility(&prices).is_some());
+    c.bench_function("volatility", |b| b.iter(|| volatility(black_box(&prices))));
+}
+
+criterion_group!(benches, bench_stats);
+criterion_main!(benches);
@@ -0,0 +1,208 @@
+use crate::clock::{millis_since_epoch, Clock, SystemClock};
+use crate::CoinData;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const INDEX_FILE: &str = "index.json";
+const VALIDATORS_FILE: &str = "validators.json";
+pub const ASSETS_KEY: &str = "assets";
+pub const RATES_KEY: &str = "rates";
+
+/// Cache key -> unix millis of the last write.
+pub type CacheIndex = BTreeMap<String, u64>;
+
+/// The `ETag` and `Last-Modified` headers a cached body was served with,
+/// sent back as `If-None-Match` / `If-Modified-Since` to revalidate it.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+}
+
+/// A directory of JSON response bodies, one file per key, plus an
+/// `index.json` recording when each key was last written.
+#[derive(Debug)]
+pub struct Cache {
+    dir: PathBuf,
+    clock: Box<dyn Clock>,
+}
+
+/// Writes `contents` to a temporary file next to `path` and renames it into
+/// place, so an interrupted write never leaves a half-written `path`.
+pub fn write_atomic(path: &Path, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path)
+}
+
+pub fn history_key(id: &str, interval: &str) -> String {
+    format!("{}.{}", id, interval)
+}
+
+/// Appends a `fresh` fetch of a history's tail to the `cached` history,
+/// with the fresh copy of any overlapping point winning, and drops points
+/// from before `window_start_ms`.
+pub fn append_history(cached: CoinData, fresh: CoinData, window_start_ms: u64) -> CoinData {
+    let mut data = cached.data;
+    data.retain(|point| point.time >= window_start_ms);
+    data.extend(fresh.data);
+    CoinData {
+        data: crate::dedupe_by_time(data),
+    }
+}
+
+impl Cache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Cache::with_clock(dir, Box::new(SystemClock))
+    }
+
+    /// A cache that timestamps writes and measures ages with `clock`.
+    pub fn with_clock(dir: impl Into<PathBuf>, clock: Box<dyn Clock>) -> Self {
+        Cache {
+            dir: dir.into(),
+            clock,
+        }
+    }
+
+    fn entry_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", key))
+    }
+
+    fn index_path(&self) -> PathBuf {
+        self.dir.join(INDEX_FILE)
+    }
+
+    fn validators_path(&self) -> PathBuf {
+        self.dir.join(VALIDATORS_FILE)
+    }
+
+    pub fn read<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let body = fs::read_to_string(self.entry_path(key)).ok()?;
+        serde_json::from_str(&body).ok()
+    }
+
+    pub fn write<T: Serialize>(&self, key: &str, value: &T) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        write_atomic(&self.entry_path(key), serde_json::to_string(value)?)?;
+        let mut index = self.index();
+        index.insert(key.to_string(), millis_since_epoch(self.clock.now()));
+        write_atomic(&self.index_path(), serde_json::to_string_pretty(&index)?)
+    }
+
+    /// The validators stored for `key`, if its response had any.
+    pub fn validators(&self, key: &str) -> Option<Validators> {
+        self.all_validators().remove(key)
+    }
+
+    pub fn write_validators(&self, key: &str, validators: &Validators) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let mut all = self.all_validators();
+        if validators.is_empty() {
+            all.remove(key);
+        } else {
+            all.insert(key.to_string(), validators.clone());
+        }
+        write_atomic(&self.validators_path(), serde_json::to_string_pretty(&all)?)
+    }
+
+    fn all_validators(&self) -> BTreeMap<String, Validators> {
+        fs::read_to_string(self.validators_path())
+            .ok()
+            .and_then(|body| serde_json::from_str(&body).ok())
+            .unwrap_or_default()
+    }
+
+    /// How long ago `key` was written, if it is in the index.
+    pub fn age(&self, key: &str) -> Option<Duration> {
+        let now = millis_since_epoch(self.clock.now());
+        let fetched_at = *self.index().get(key)?;
+        Some(Duration::from_millis(now.saturating_sub(fetched_at)))
+    }
+
+    /// True when `key` was written less than `ttl` ago.
+    pub fn is_fresh(&self, key: &str, ttl: Duration) -> bool {
+        self.age(key).is_some_and(|age| age < ttl)
+    }
+
+    /// Loads the index, rebuilding it from the entry files' modification
+    /// times when it is missing or can't be parsed.
+    pub fn index(&self) -> CacheIndex {
+        fs::read_to_string(self.index_path())
+            .ok()
+            .and_then(|body| serde_json::from_str(&body).ok())
+            .unwrap_or_else(|| self.rebuild_index())
+    }
+
+    fn rebuild_index(&self) -> CacheIndex {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(_) => return CacheIndex::new(),
+        };
+        entries
+            .filter_map(Result::ok)
+            .filter_map(|entry| {
+                let path = entry.path();
+                let name = path.file_name()?;
+                if path.extension()? != "json" || name == INDEX_FILE || name == VALIDATORS_FILE {
+                    return None;
+                }
+                let key = path.file_stem()?.to_str()?.to_string();
+                let modified = entry.metadata().ok()?.modified().ok()?;
+                Some((key, millis_since_epoch(modified)))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FixedClock;
+    use std::time::SystemTime;
+
+    #[test]
+    fn test_index_updates_after_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::new(dir.path());
+        assert!(cache.index().is_empty());
+
+        let before = millis_since_epoch(SystemTime::now());
+        cache
+            .write(&history_key("bitcoin", "d1"), &[1, 2, 3])
+            .unwrap();
+        let index = cache.index();
+        assert_eq!(index.len(), 1);
+        assert!(index["bitcoin.d1"] >= before);
+        assert_eq!(cache.read::<Vec<i32>>("bitcoin.d1"), Some(vec![1, 2, 3]));
+        assert!(cache.is_fresh("bitcoin.d1", Duration::from_secs(60)));
+        assert!(!cache.is_fresh("ethereum.d1", Duration::from_secs(60)));
+
+        fs::write(dir.path().join(INDEX_FILE), "{not json").unwrap();
+        let rebuilt = cache.index();
+        assert_eq!(rebuilt.keys().collect::<Vec<_>>(), vec!["bitcoin.d1"]);
+    }
+
+    #[test]
+    fn test_age_uses_injected_clock() {
+        let dir = tempfile::tempdir().unwrap();
+        Cache::with_clock(dir.path(), Box::new(FixedClock::at_millis(1_000_000)))
+            .write("assets", &[1])
+            .unwrap();
+        let later = Cache::with_clock(dir.path(), Box::new(FixedClock::at_millis(1_090_000)));
+        assert_eq!(later.age("assets"), Some(Duration::from_secs(90)));
+        assert!(later.is_fresh("assets", Duration::from_secs(91)));
+        assert!(!later.is_fresh("assets", Duration::from_secs(90)));
+    }
+}
@@ -0,0 +1,47 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+/// What coins without an entry in the mapping are filed under.
+pub const UNCATEGORIZED: &str = "uncategorized";
+
+// CoinCap doesn't expose categories, so a hand-kept id -> category mapping
+// ships with the binary.
+const CATEGORIES_JSON: &str = include_str!("categories.json");
+
+fn categories() -> &'static HashMap<String, String> {
+    static CATEGORIES: OnceLock<HashMap<String, String>> = OnceLock::new();
+    CATEGORIES.get_or_init(|| {
+        serde_json::from_str(CATEGORIES_JSON).expect("categories.json is valid JSON")
+    })
+}
+
+/// The category of the coin with this id, like "DeFi" or "L1".
+pub fn category(id: &str) -> &'static str {
+    categories().get(id).map_or(UNCATEGORIZED, String::as_str)
+}
+
+/// True when the coin's category is `wanted`, ignoring case.
+pub fn in_category(id: &str, wanted: &str) -> bool {
+    category(id).eq_ignore_ascii_case(wanted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_mixed_list_by_category() {
+        assert!(!categories().is_empty());
+        let ids = ["bitcoin", "uniswap", "tether", "aave", "some-new-coin"];
+        let pick = |wanted: &str| -> Vec<&str> {
+            ids.iter()
+                .copied()
+                .filter(|id| in_category(id, wanted))
+                .collect()
+        };
+        assert_eq!(pick("defi"), vec!["uniswap", "aave"]);
+        assert_eq!(pick("L1"), vec!["bitcoin"]);
+        assert_eq!(pick("uncategorized"), vec!["some-new-coin"]);
+        assert!(pick("gaming").is_empty());
+    }
+}
@@ -0,0 +1,106 @@
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Where "now" comes from, so time-dependent logic can be tested with a
+/// fixed time instead of the system clock.
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Always reports the time it was created with.
+#[derive(Debug)]
+pub struct FixedClock(pub SystemTime);
+
+impl FixedClock {
+    pub fn at_millis(millis: u64) -> Self {
+        FixedClock(UNIX_EPOCH + Duration::from_millis(millis))
+    }
+}
+
+impl Clock for FixedClock {
+    fn now(&self) -> SystemTime {
+        self.0
+    }
+}
+
+pub fn millis_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// How long before `now` the unix millis `ts` was, in words: "just now",
+/// "5 minutes ago", "3 months ago" or, for a time after `now`, "in 2 days".
+/// Months are 30 days and years 365.
+pub fn humanize_duration(ts: u64, now: SystemTime) -> String {
+    let now = millis_since_epoch(now);
+    let secs = now.abs_diff(ts) / 1000;
+    let (count, unit) = match secs {
+        0..=59 => return "just now".to_string(),
+        60..=3_599 => (secs / 60, "minute"),
+        3_600..=86_399 => (secs / 3_600, "hour"),
+        86_400..=2_591_999 => (secs / 86_400, "day"),
+        2_592_000..=31_535_999 => (secs / 2_592_000, "month"),
+        _ => (secs / 31_536_000, "year"),
+    };
+    let plural = if count == 1 { "" } else { "s" };
+    if ts > now {
+        format!("in {} {}{}", count, unit, plural)
+    } else {
+        format!("{} {}{} ago", count, unit, plural)
+    }
+}
+
+/// Parses a span like "30m", "12h", "7d" or "2w".
+pub fn parse_span(spec: &str) -> Option<Duration> {
+    let spec = spec.trim();
+    let split = spec.len().checked_sub(1)?;
+    let count: u64 = spec.get(..split)?.parse().ok()?;
+    let unit = match spec.get(split..)? {
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    Some(Duration::from_secs(count.checked_mul(unit)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_span() {
+        assert_eq!(parse_span("30m"), Some(Duration::from_secs(30 * 60)));
+        assert_eq!(parse_span("7d"), Some(Duration::from_secs(7 * 86_400)));
+        assert_eq!(parse_span("2w"), Some(Duration::from_secs(14 * 86_400)));
+        assert_eq!(parse_span("7"), None);
+        assert_eq!(parse_span("d"), None);
+        assert_eq!(parse_span("7y"), None);
+    }
+
+    #[test]
+    fn test_humanize_duration() {
+        let now = FixedClock::at_millis(1_700_000_000_000).now();
+        let ago = |secs: u64| humanize_duration(1_700_000_000_000 - secs * 1000, now);
+        assert_eq!(ago(0), "just now");
+        assert_eq!(ago(90), "1 minute ago");
+        assert_eq!(ago(5 * 3_600), "5 hours ago");
+        assert_eq!(ago(100 * 86_400), "3 months ago");
+        assert_eq!(ago(800 * 86_400), "2 years ago");
+        assert_eq!(
+            humanize_duration(1_700_000_000_000 + 2 * 86_400_000, now),
+            "in 2 days"
+        );
+    }
+}
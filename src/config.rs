@@ -0,0 +1,56 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// Settings read from the TOML file given with --config, e.g.
+///
+/// ```toml
+/// [intervals]
+/// bitcoin = "d1"
+/// PEPE = "h1"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// History interval per coin id or symbol, used instead of the global
+    /// one for that coin.
+    #[serde(default)]
+    pub intervals: BTreeMap<String, String>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Config::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn parse(text: &str) -> Result<Config, String> {
+        let config: Config = toml::from_str(text).map_err(|e| e.to_string())?;
+        for (coin, interval) in &config.intervals {
+            if crate::interval_millis(interval).is_none() {
+                return Err(format!(
+                    "'{}' isn't a history interval (for {})",
+                    interval, coin
+                ));
+            }
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_intervals() {
+        let config = Config::parse("[intervals]\nbitcoin = \"d1\"\nPEPE = \"h1\"\n").unwrap();
+        assert_eq!(config.intervals["bitcoin"], "d1");
+        assert_eq!(config.intervals["PEPE"], "h1");
+        assert!(Config::parse("").unwrap().intervals.is_empty());
+        let err = Config::parse("[intervals]\nbitcoin = \"d7\"\n").unwrap_err();
+        assert!(err.contains("'d7'"), "{}", err);
+        assert!(Config::parse("interval = \"d1\"\n").is_err());
+    }
+}
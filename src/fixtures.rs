@@ -0,0 +1,72 @@
+use crate::net::FetchError;
+use serde::de::DeserializeOwned;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+pub const ASSETS_FILE: &str = "assets.json";
+pub const RATES_FILE: &str = "rates.json";
+
+// A few coins with two weeks of daily history, for --demo.
+const DEMO: &[(&str, &str)] = &[
+    (ASSETS_FILE, include_str!("demo/assets.json")),
+    (RATES_FILE, include_str!("demo/rates.json")),
+    ("bitcoin_d1.json", include_str!("demo/bitcoin_d1.json")),
+    ("ethereum_d1.json", include_str!("demo/ethereum_d1.json")),
+    ("tether_d1.json", include_str!("demo/tether_d1.json")),
+    ("dogecoin_d1.json", include_str!("demo/dogecoin_d1.json")),
+];
+
+/// The fixture holding a coin's history at one interval, e.g.
+/// `bitcoin_d1.json`.
+pub fn history_file(id: &str, interval: &str) -> String {
+    format!("{}_{}.json", id, interval)
+}
+
+/// Serves API responses from a directory of JSON files shaped exactly like
+/// the API's bodies, instead of the network: `assets.json`, `rates.json`
+/// and one `<id>_<interval>.json` per history. The same files can also be
+/// compiled in, see `demo`.
+#[derive(Debug)]
+pub struct FileProvider {
+    files: Files,
+}
+
+#[derive(Debug)]
+enum Files {
+    Dir(PathBuf),
+    Embedded(&'static [(&'static str, &'static str)]),
+}
+
+impl FileProvider {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        FileProvider {
+            files: Files::Dir(dir.into()),
+        }
+    }
+
+    /// The small dataset built into the binary, so demos need neither the
+    /// network nor fixture files. Histories are daily only.
+    pub fn demo() -> Self {
+        FileProvider {
+            files: Files::Embedded(DEMO),
+        }
+    }
+
+    pub(crate) fn read<T: DeserializeOwned>(&self, name: &str) -> Result<T, FetchError> {
+        let body = match &self.files {
+            Files::Dir(dir) => {
+                let path = dir.join(name);
+                fs::read(&path).map_err(|e| FetchError::Fixture(path, e))?
+            }
+            Files::Embedded(files) => match files.iter().find(|(file, _)| *file == name) {
+                Some((_, body)) => body.as_bytes().to_vec(),
+                None => {
+                    let e = io::Error::new(io::ErrorKind::NotFound, "not in the demo dataset");
+                    return Err(FetchError::Fixture(PathBuf::from(name), e));
+                }
+            },
+        };
+        serde_json::from_slice(&body).map_err(FetchError::Parse)
+    }
+}
@@ -0,0 +1,205 @@
+use crate::cache::write_atomic;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::fs;
+use std::path::PathBuf;
+
+/// Where CoinCap serves its asset icons, as <symbol>@2x.png.
+pub const ICON_URL: &str = "https://assets.coincap.io/assets/icons";
+// Kitty takes base64 payloads in chunks of at most this many bytes.
+const KITTY_CHUNK: usize = 4096;
+
+/// The inline image protocols a terminal may understand.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageProtocol {
+    None,
+    /// iTerm2's OSC 1337, also spoken by WezTerm.
+    Iterm,
+    Kitty,
+}
+
+impl ImageProtocol {
+    /// The protocol the current terminal speaks, going by its environment.
+    pub fn detect() -> Self {
+        ImageProtocol::from_env(|name| std::env::var(name).ok())
+    }
+
+    fn from_env(var: impl Fn(&str) -> Option<String>) -> Self {
+        if var("KITTY_WINDOW_ID").is_some()
+            || var("TERM").is_some_and(|term| term.contains("kitty"))
+        {
+            return ImageProtocol::Kitty;
+        }
+        match var("TERM_PROGRAM").as_deref() {
+            Some("iTerm.app" | "WezTerm") => ImageProtocol::Iterm,
+            _ => ImageProtocol::None,
+        }
+    }
+}
+
+/// Where logos are fetched from, and the directory they are kept in.
+pub struct IconSource {
+    pub base_url: String,
+    pub cache_dir: Option<PathBuf>,
+}
+
+impl IconSource {
+    fn cached_path(&self, symbol: &str) -> Option<PathBuf> {
+        let dir = self.cache_dir.as_ref()?;
+        Some(dir.join("icons").join(format!("{}.png", symbol)))
+    }
+
+    /// The PNG logo for `symbol`, from the cache or else fetched and cached.
+    /// Logos are decoration, so any failure just means there is none.
+    pub async fn fetch(&self, client: &reqwest::Client, symbol: &str) -> Option<Vec<u8>> {
+        let symbol = symbol.to_lowercase();
+        // The symbol ends up in a path and a url.
+        if symbol.is_empty() || !symbol.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return None;
+        }
+        let path = self.cached_path(&symbol);
+        if let Some(png) = path.as_ref().and_then(|path| fs::read(path).ok()) {
+            return Some(png);
+        }
+        let png = client
+            .get(format!("{}/{}@2x.png", self.base_url, symbol))
+            .send()
+            .await
+            .ok()?
+            .error_for_status()
+            .ok()?
+            .bytes()
+            .await
+            .ok()?
+            .to_vec();
+        if let Some(path) = path {
+            let written = path
+                .parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .and_then(|_| write_atomic(&path, &png));
+            if let Err(e) = written {
+                eprintln!("Warning: failed to cache icon {}: {}", path.display(), e);
+            }
+        }
+        Some(png)
+    }
+}
+
+/// The escape sequence that draws `png` two cells wide and one high.
+pub fn inline_image(protocol: ImageProtocol, png: &[u8]) -> String {
+    let data = STANDARD.encode(png);
+    match protocol {
+        ImageProtocol::None => String::new(),
+        ImageProtocol::Iterm => format!(
+            "\x1b]1337;File=inline=1;size={};width=2;height=1;preserveAspectRatio=1:{}\x07",
+            png.len(),
+            data
+        ),
+        ImageProtocol::Kitty => {
+            // base64 is ASCII, so the chunks split on char boundaries.
+            let chunks: Vec<&str> = (0..data.len())
+                .step_by(KITTY_CHUNK)
+                .map(|start| &data[start..(start + KITTY_CHUNK).min(data.len())])
+                .collect();
+            let mut out = String::new();
+            for (i, chunk) in chunks.iter().enumerate() {
+                let more = u8::from(i + 1 < chunks.len());
+                if i == 0 {
+                    out.push_str(&format!(
+                        "\x1b_Gf=100,a=T,c=2,r=1,m={};{}\x1b\\",
+                        more, chunk
+                    ));
+                } else {
+                    out.push_str(&format!("\x1b_Gm={};{}\x1b\\", more, chunk));
+                }
+            }
+            out
+        }
+    }
+}
+
+/// What to print before a coin's line: its logo and a space, or nothing
+/// when the terminal can't draw images or the logo can't be had.
+pub async fn icon_prefix(
+    protocol: ImageProtocol,
+    source: &IconSource,
+    client: &reqwest::Client,
+    symbol: Option<&str>,
+) -> String {
+    if protocol == ImageProtocol::None {
+        return String::new();
+    }
+    let png = match symbol {
+        Some(symbol) => source.fetch(client, symbol).await,
+        None => None,
+    };
+    match png {
+        Some(png) => inline_image(protocol, &png) + " ",
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httpmock::Method::GET;
+    use httpmock::MockServer;
+
+    #[tokio::test]
+    async fn test_icons_skipped_when_disabled_or_missing() {
+        let server = MockServer::start();
+        let missing = server.mock(|when, then| {
+            when.method(GET).path("/icons/btc@2x.png");
+            then.status(404);
+        });
+        let found = server.mock(|when, then| {
+            when.method(GET).path("/icons/eth@2x.png");
+            then.status(200).body(b"\x89PNG\r\n\x1a\n");
+        });
+        let dir = tempfile::tempdir().unwrap();
+        let source = IconSource {
+            base_url: server.url("/icons"),
+            cache_dir: Some(dir.path().to_path_buf()),
+        };
+        let client = reqwest::Client::new();
+        let prefix =
+            |protocol, symbol: &'static str| icon_prefix(protocol, &source, &client, Some(symbol));
+
+        assert_eq!(prefix(ImageProtocol::None, "BTC").await, "");
+        missing.assert_hits(0);
+        assert_eq!(prefix(ImageProtocol::Iterm, "BTC").await, "");
+        missing.assert_hits(1);
+        assert!(!dir.path().join("icons/btc.png").exists());
+
+        let drawn = prefix(ImageProtocol::Iterm, "ETH").await;
+        assert!(
+            drawn.starts_with("\x1b]1337;File=inline=1;size=8;"),
+            "{:?}",
+            drawn
+        );
+        assert!(dir.path().join("icons/eth.png").exists());
+        let kitty = prefix(ImageProtocol::Kitty, "eth").await;
+        assert!(
+            kitty.starts_with("\x1b_Gf=100,a=T,c=2,r=1,m=0;"),
+            "{:?}",
+            kitty
+        );
+        found.assert_hits(1);
+        assert_eq!(prefix(ImageProtocol::Iterm, "../eth").await, "");
+    }
+
+    #[test]
+    fn test_protocol_from_env() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            ImageProtocol::from_env(move |name| {
+                vars.iter()
+                    .find(|(var, _)| *var == name)
+                    .map(|(_, value)| value.to_string())
+            })
+        };
+        assert_eq!(env(&[("TERM_PROGRAM", "iTerm.app")]), ImageProtocol::Iterm);
+        assert_eq!(env(&[("TERM", "xterm-kitty")]), ImageProtocol::Kitty);
+        assert_eq!(env(&[("TERM", "xterm-256color")]), ImageProtocol::None);
+        assert_eq!(env(&[]), ImageProtocol::None);
+    }
+}
@@ -0,0 +1,1265 @@
+pub mod cache;
+pub mod category;
+pub mod clock;
+pub mod config;
+#[cfg(feature = "net")]
+pub mod fixtures;
+#[cfg(feature = "icons")]
+pub mod icons;
+// Everything that talks to CoinCap, re-exported below. Without the net
+// feature the crate is just the stats and renderers.
+#[cfg(feature = "net")]
+mod net;
+#[cfg(feature = "png")]
+pub mod png;
+pub mod render;
+
+#[cfg(feature = "net")]
+pub use net::*;
+
+use cache::Cache;
+use chrono::{TimeZone, Utc};
+use clock::{millis_since_epoch, Clock};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::time::Duration;
+
+pub const API_URL: &str = "https://api.coincap.io/v2";
+const WINDOW_START_MS: u64 = 1356931594000;
+const WINDOW_END_MS: u64 = 1675817253000;
+/// The history intervals CoinCap serves.
+pub const INTERVALS: [&str; 9] = ["m1", "m5", "m15", "m30", "h1", "h2", "h6", "h12", "d1"];
+const DEFAULT_CONCURRENCY: usize = 8;
+// CoinCap rate limits per client, so more parallel requests than this only
+// buys 429s.
+const MAX_CONCURRENCY: usize = 32;
+pub const RSI_PERIOD: usize = 14;
+const RECENT_WINDOW_MS: u64 = 30 * 24 * 60 * 60 * 1000;
+const TREND_WINDOW: usize = 20;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[allow(non_snake_case)]
+pub struct PriceData {
+    pub priceUsd: String,
+    #[serde(deserialize_with = "u64_from_number_or_str")]
+    pub time: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CoinData {
+    pub data: Vec<PriceData>,
+}
+
+// Parses a price string, tolerating surrounding whitespace and thousands
+// separators ("1,234.56"). Commas anywhere but between groups of three
+// integer digits still fail.
+fn parse_price(raw: &str) -> Option<f64> {
+    let trimmed = raw.trim();
+    if !trimmed.contains(',') {
+        return trimmed.parse().ok();
+    }
+    let (integer, fraction) = match trimmed.split_once('.') {
+        Some((integer, fraction)) => (integer, fraction),
+        None => (trimmed, ""),
+    };
+    let integer = integer.strip_prefix('-').unwrap_or(integer);
+    let mut groups = integer.split(',');
+    let leading = groups.next()?;
+    let grouped = (1..=3).contains(&leading.len()) && groups.all(|group| group.len() == 3);
+    if !grouped || fraction.contains(',') {
+        return None;
+    }
+    trimmed.replace(',', "").parse().ok()
+}
+
+// Some API variants send timestamps as numeric strings instead of numbers.
+fn u64_from_number_or_str<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum NumberOrString {
+        Number(u64),
+        String(String),
+    }
+    match NumberOrString::deserialize(deserializer)? {
+        NumberOrString::Number(number) => Ok(number),
+        NumberOrString::String(value) => value
+            .trim()
+            .parse()
+            .map_err(|_| serde::de::Error::custom(format!("invalid timestamp '{}'", value))),
+    }
+}
+
+// Missing fields default so a run saved with --fields can be read back.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct CoinInfo {
+    pub name: String,
+    pub all_time_high: f64,
+    pub all_time_low: f64,
+    pub current_price: f64,
+    pub window_change_percent: f64,
+    pub coverage: Option<f64>,
+    pub points: usize,
+    pub volume_usd_24h: Option<f64>,
+    pub relative_volume: Option<f64>,
+    pub rank: Option<u32>,
+    pub average_price: Option<f64>,
+    pub all_time_high_time: Option<u64>,
+    pub all_time_low_time: Option<u64>,
+    /// Highest and lowest prices over the last 30 days of the window.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recent_high: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recent_low: Option<f64>,
+    /// Circulating supply and the cap on it, from the coin list. Coins
+    /// with no cap have no max_supply.
+    pub supply: Option<f64>,
+    pub max_supply: Option<f64>,
+    /// Circulating supply as a percentage of max_supply.
+    pub supply_percent: Option<f64>,
+    /// Window change minus the benchmark's, in percentage points, once
+    /// relative_to_benchmark has run.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub relative_change_percent: Option<f64>,
+    /// Window change per point of volatility, a crude risk-adjusted return.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sharpe_ratio: Option<f64>,
+    /// The window, in unix millis, that the all_time_* fields are really
+    /// the extremes of, once a run has fetched the coin.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub window_start: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub window_end: Option<u64>,
+    /// Ticker symbol from the coin list, when the coin was listed.
+    #[serde(skip)]
+    pub symbol: Option<String>,
+    /// Parsed prices in time order, for renderers that plot the series.
+    #[serde(skip)]
+    pub history: Vec<f64>,
+    /// The history exactly as fetched, when RunOptions::keep_raw is set.
+    #[serde(skip)]
+    pub raw: Option<CoinData>,
+    /// Where the history came from.
+    #[serde(skip)]
+    pub source: Option<Source>,
+}
+
+/// The span of history fetched for each coin, in unix millis.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Window {
+    pub start_ms: u64,
+    pub end_ms: u64,
+}
+
+impl Window {
+    /// The `span` leading up to the clock's current time.
+    pub fn last(span: Duration, clock: &dyn Clock) -> Self {
+        let end_ms = millis_since_epoch(clock.now());
+        Window {
+            start_ms: end_ms.saturating_sub(span.as_millis() as u64),
+            end_ms,
+        }
+    }
+}
+
+impl Default for Window {
+    fn default() -> Self {
+        Window {
+            start_ms: WINDOW_START_MS,
+            end_ms: WINDOW_END_MS,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum StablecoinMode {
+    #[default]
+    Include,
+    Exclude,
+    Only,
+}
+
+/// Which coins to keep based on whether they look like stablecoins: a
+/// price within `band` of $1 and a volatility (in percent) under
+/// `max_volatility`.
+#[derive(Clone, Copy, Debug)]
+pub struct StablecoinFilter {
+    pub mode: StablecoinMode,
+    pub band: f64,
+    pub max_volatility: f64,
+}
+
+impl Default for StablecoinFilter {
+    fn default() -> Self {
+        StablecoinFilter {
+            mode: StablecoinMode::Include,
+            band: 0.02,
+            max_volatility: 0.5,
+        }
+    }
+}
+
+impl StablecoinFilter {
+    pub fn is_stablecoin(&self, info: &CoinInfo) -> bool {
+        (info.current_price - 1.0).abs() <= self.band
+            && volatility(&info.history).is_some_and(|v| v < self.max_volatility)
+    }
+
+    pub fn keeps(&self, info: &CoinInfo) -> bool {
+        match self.mode {
+            StablecoinMode::Include => true,
+            StablecoinMode::Exclude => !self.is_stablecoin(info),
+            StablecoinMode::Only => self.is_stablecoin(info),
+        }
+    }
+}
+
+/// The error a run ends with when its cancel token fires.
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the run was cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Where a coin's history came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    Cache,
+    Network,
+    Fixture,
+}
+
+// CoinCap occasionally repeats a timestamp. Points are put in time order and
+// for a repeated timestamp only the last entry returned is kept.
+fn dedupe_by_time(mut prices: Vec<PriceData>) -> Vec<PriceData> {
+    prices.sort_by_key(|price| price.time);
+    let mut deduped: Vec<PriceData> = Vec::with_capacity(prices.len());
+    for price in prices {
+        match deduped.last_mut() {
+            Some(last) if last.time == price.time => *last = price,
+            _ => deduped.push(price),
+        }
+    }
+    deduped
+}
+
+/// Computes a coin's stats from its fetched history.
+pub async fn get_coin_info(
+    coin_data: CoinData,
+    name: &str,
+) -> Result<CoinInfo, Box<dyn std::error::Error>> {
+    coin_info(
+        PriceStats::from_prices(&dedupe_by_time(coin_data.data)),
+        name,
+    )
+}
+
+fn coin_info(stats: PriceStats, name: &str) -> Result<CoinInfo, Box<dyn std::error::Error>> {
+    // Without this the fold seeds would be reported as the extremes.
+    let current_price = match stats.last {
+        Some(last) => last,
+        None => return Err(format!("no parseable prices for {}", name).into()),
+    };
+
+    let recent = stats.recent_range(RECENT_WINDOW_MS);
+    Ok(CoinInfo {
+        name: name.to_string(),
+        recent_low: recent.map(|(low, _)| low),
+        recent_high: recent.map(|(_, high)| high),
+        all_time_high: stats.max,
+        all_time_low: stats.min,
+        current_price,
+        window_change_percent: stats.change_percent(),
+        sharpe_ratio: sharpe_ratio(stats.change_percent(), volatility(&stats.series)),
+        points: stats.count,
+        average_price: stats.mean(),
+        all_time_high_time: stats.max_time,
+        all_time_low_time: stats.min_time,
+        history: stats.series,
+        ..Default::default()
+    })
+}
+
+// CoinCap's history intervals, in milliseconds.
+fn interval_millis(interval: &str) -> Option<u64> {
+    let minute = 60 * 1000;
+    let millis = match interval {
+        "m1" => minute,
+        "m5" => 5 * minute,
+        "m15" => 15 * minute,
+        "m30" => 30 * minute,
+        "h1" => 60 * minute,
+        "h2" => 2 * 60 * minute,
+        "h6" => 6 * 60 * minute,
+        "h12" => 12 * 60 * minute,
+        "d1" => 24 * 60 * minute,
+        _ => return None,
+    };
+    Some(millis)
+}
+
+// Everything get_coin_info needs from a series, gathered in one pass that
+// parses each price once. Unparseable prices are skipped.
+#[derive(Debug, PartialEq)]
+struct PriceStats {
+    min: f64,
+    max: f64,
+    sum: f64,
+    count: usize,
+    first: Option<f64>,
+    last: Option<f64>,
+    min_time: Option<u64>,
+    max_time: Option<u64>,
+    series: Vec<f64>,
+    // When each price in the series was recorded.
+    times: Vec<u64>,
+}
+
+impl Default for PriceStats {
+    fn default() -> Self {
+        PriceStats {
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            sum: 0.0,
+            count: 0,
+            first: None,
+            last: None,
+            min_time: None,
+            max_time: None,
+            series: Vec::new(),
+            times: Vec::new(),
+        }
+    }
+}
+
+impl PriceStats {
+    fn from_prices(prices: &[PriceData]) -> Self {
+        let mut stats = PriceStats::default();
+        stats.series.reserve(prices.len());
+        stats.times.reserve(prices.len());
+        for (time, price) in prices
+            .iter()
+            .filter_map(|x| Some((x.time, parse_price(&x.priceUsd)?)))
+        {
+            stats.push(time, price);
+        }
+        stats
+    }
+
+    fn push(&mut self, time: u64, price: f64) {
+        // Ties keep the earliest time the extreme was reached.
+        if price < self.min {
+            self.min = price;
+            self.min_time = Some(time);
+        }
+        if price > self.max {
+            self.max = price;
+            self.max_time = Some(time);
+        }
+        self.sum += price;
+        self.count += 1;
+        self.first.get_or_insert(price);
+        self.last = Some(price);
+        self.series.push(price);
+        self.times.push(time);
+    }
+
+    // Lowest and highest prices within `span` of the last point.
+    fn recent_range(&self, span: u64) -> Option<(f64, f64)> {
+        let since = self.times.last()?.saturating_sub(span);
+        let start = self.times.partition_point(|time| *time < since);
+        self.series[start..]
+            .iter()
+            .fold(None, |range, &price| match range {
+                None => Some((price, price)),
+                Some((low, high)) => Some((f64::min(low, price), f64::max(high, price))),
+            })
+    }
+
+    fn mean(&self) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        Some(self.sum / self.count as f64)
+    }
+
+    // Change from the first to the last parseable price in the fetched
+    // window, 0.0 when there are fewer than two points to compare. Dividing
+    // by the magnitude keeps a rise positive when the series is negative.
+    fn change_percent(&self) -> f64 {
+        match (self.first, self.last) {
+            (Some(first), Some(last)) if self.count > 1 && first != 0.0 => {
+                (last - first) * 100.0 / first.abs()
+            }
+            _ => 0.0,
+        }
+    }
+}
+
+/// Sample standard deviation of the point to point percentage returns.
+pub fn volatility(prices: &[f64]) -> Option<f64> {
+    let returns: Vec<f64> = prices
+        .windows(2)
+        .filter(|pair| pair[0] != 0.0)
+        .map(|pair| (pair[1] - pair[0]) * 100.0 / pair[0])
+        .collect();
+    if returns.len() < 2 {
+        return None;
+    }
+    let mean = returns.iter().sum::<f64>() / returns.len() as f64;
+    let variance =
+        returns.iter().map(|r| (r - mean).powi(2)).sum::<f64>() / (returns.len() - 1) as f64;
+    Some(variance.sqrt())
+}
+
+/// `change_percent / volatility`, or None when there is no volatility to
+/// divide by.
+pub fn sharpe_ratio(change_percent: f64, volatility: Option<f64>) -> Option<f64> {
+    volatility
+        .filter(|v| *v != 0.0 && v.is_finite())
+        .map(|v| change_percent / v)
+}
+
+/// Pearson correlation of the two histories' prices at the times both
+/// have a point. None when fewer than two times line up, or when either
+/// price is flat over them.
+pub fn correlation(a: &CoinData, b: &CoinData) -> Option<f64> {
+    let b_prices: HashMap<u64, f64> = b
+        .data
+        .iter()
+        .filter_map(|point| Some((point.time, parse_price(&point.priceUsd)?)))
+        .collect();
+    let pairs: Vec<(f64, f64)> = a
+        .data
+        .iter()
+        .filter_map(|point| {
+            let x = parse_price(&point.priceUsd)?;
+            Some((x, *b_prices.get(&point.time)?))
+        })
+        .collect();
+    if pairs.len() < 2 {
+        return None;
+    }
+    let n = pairs.len() as f64;
+    let mean_x = pairs.iter().map(|(x, _)| x).sum::<f64>() / n;
+    let mean_y = pairs.iter().map(|(_, y)| y).sum::<f64>() / n;
+    let (mut covariance, mut var_x, mut var_y) = (0.0, 0.0, 0.0);
+    for (x, y) in &pairs {
+        covariance += (x - mean_x) * (y - mean_y);
+        var_x += (x - mean_x).powi(2);
+        var_y += (y - mean_y).powi(2);
+    }
+    if var_x == 0.0 || var_y == 0.0 {
+        return None;
+    }
+    Some(covariance / (var_x * var_y).sqrt())
+}
+
+/// Relative strength index over the last `period` changes, using plain
+/// averages of the gains and losses rather than Wilder's smoothing.
+pub fn rsi(prices: &[f64], period: usize) -> Option<f64> {
+    if period == 0 || prices.len() <= period {
+        return None;
+    }
+    let (gains, losses) = prices[prices.len() - period - 1..]
+        .windows(2)
+        .map(|pair| pair[1] - pair[0])
+        .fold((0.0, 0.0), |(gains, losses), change| {
+            if change > 0.0 {
+                (gains + change, losses)
+            } else {
+                (gains, losses - change)
+            }
+        });
+    if gains + losses == 0.0 {
+        return Some(50.0);
+    }
+    Some(100.0 * gains / (gains + losses))
+}
+
+/// "up" or "down" when the last price is more than 1% away from the mean of
+/// the last few prices, "sideways" otherwise.
+pub fn trend(prices: &[f64]) -> Option<&'static str> {
+    let last = *prices.last()?;
+    let recent = &prices[prices.len().saturating_sub(TREND_WINDOW)..];
+    let mean = recent.iter().sum::<f64>() / recent.len() as f64;
+    Some(if last > mean * 1.01 {
+        "up"
+    } else if last < mean * 0.99 {
+        "down"
+    } else {
+        "sideways"
+    })
+}
+
+/// Circulating supply as a percentage of the maximum, or None for an
+/// uncapped coin.
+pub fn supply_percent(supply: f64, max_supply: Option<f64>) -> Option<f64> {
+    max_supply
+        .filter(|max| *max > 0.0)
+        .map(|max| supply * 100.0 / max)
+}
+
+/// Sets each coin's relative_change_percent to its window change minus the
+/// benchmark's, and drops the benchmark (matched by id or symbol) itself.
+pub fn relative_to_benchmark(infos: &mut Vec<CoinInfo>, benchmark: &str) -> Result<(), String> {
+    let is_benchmark = |info: &CoinInfo| {
+        info.name.eq_ignore_ascii_case(benchmark)
+            || info
+                .symbol
+                .as_deref()
+                .is_some_and(|symbol| symbol.eq_ignore_ascii_case(benchmark))
+    };
+    let base = infos
+        .iter()
+        .find(|info| is_benchmark(info))
+        .map(|info| info.window_change_percent)
+        .ok_or_else(|| format!("the benchmark {} isn't among the fetched coins", benchmark))?;
+    infos.retain(|info| !is_benchmark(info));
+    for info in infos.iter_mut() {
+        info.relative_change_percent = Some(info.window_change_percent - base);
+    }
+    Ok(())
+}
+
+/// How far, in percent, the current price sits below the window's high.
+pub fn drawdown(info: &CoinInfo) -> Option<f64> {
+    if info.all_time_high <= 0.0 {
+        return None;
+    }
+    Some((info.all_time_high - info.current_price) * 100.0 / info.all_time_high)
+}
+
+/// A stat the rendered coins can be ordered by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum SortKey {
+    Price,
+    Change,
+    Volatility,
+    Drawdown,
+    /// Window change per point of volatility
+    Sharpe,
+}
+
+impl SortKey {
+    fn value(self, info: &CoinInfo) -> Option<f64> {
+        match self {
+            SortKey::Price => Some(info.current_price),
+            SortKey::Change => Some(info.window_change_percent),
+            SortKey::Volatility => volatility(&info.history),
+            SortKey::Drawdown => drawdown(info),
+            SortKey::Sharpe => info.sharpe_ratio,
+        }
+    }
+}
+
+/// Orders `infos` by `key`, lowest first unless `descending`. Coins without
+/// a value for the stat go last either way, and ties keep their order.
+pub fn sort_infos(infos: &mut [CoinInfo], key: SortKey, descending: bool) {
+    infos.sort_by(|a, b| match (key.value(a), key.value(b)) {
+        (Some(a), Some(b)) if descending => b.total_cmp(&a),
+        (Some(a), Some(b)) => a.total_cmp(&b),
+        (Some(_), None) => std::cmp::Ordering::Less,
+        (None, Some(_)) => std::cmp::Ordering::Greater,
+        (None, None) => std::cmp::Ordering::Equal,
+    });
+}
+
+#[derive(Debug, PartialEq)]
+pub struct MarketSummary {
+    pub up: usize,
+    pub down: usize,
+    pub average_change_percent: f64,
+    pub strongest: Option<(String, f64)>,
+    pub weakest: Option<(String, f64)>,
+}
+
+// Coins with fewer than two prices have no window change to speak of and are
+// left out.
+pub fn summarize(infos: &[CoinInfo]) -> MarketSummary {
+    let changes: Vec<(&str, f64)> = infos
+        .iter()
+        .filter(|info| info.points >= 2)
+        .map(|info| (info.name.as_str(), info.window_change_percent))
+        .collect();
+    let strongest = changes.iter().max_by(|a, b| a.1.total_cmp(&b.1));
+    let weakest = changes.iter().min_by(|a, b| a.1.total_cmp(&b.1));
+    MarketSummary {
+        up: changes.iter().filter(|(_, change)| *change > 0.0).count(),
+        down: changes.iter().filter(|(_, change)| *change < 0.0).count(),
+        average_change_percent: if changes.is_empty() {
+            0.0
+        } else {
+            changes.iter().map(|(_, change)| change).sum::<f64>() / changes.len() as f64
+        },
+        strongest: strongest.map(|(name, change)| (name.to_string(), *change)),
+        weakest: weakest.map(|(name, change)| (name.to_string(), *change)),
+    }
+}
+
+/// How many coins a run handled and how it went, written by --summary for
+/// CI to read. Fields are only ever added, never renamed.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct RunSummary {
+    /// Coins that were shown plus those that failed or were skipped.
+    pub total_coins: usize,
+    pub succeeded: usize,
+    /// Coins left out because of an error or, offline, a cache miss.
+    pub failed: usize,
+    pub cache_hit_rate: Option<f64>,
+    pub elapsed_ms: u64,
+    /// The biggest window changes either way, largest first.
+    pub top_movers: Vec<Mover>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Mover {
+    pub name: String,
+    pub change_percent: f64,
+}
+
+const TOP_MOVERS: usize = 3;
+
+/// How one coin differs between a saved run and the current one.
+#[derive(Debug, PartialEq)]
+pub enum CoinDelta {
+    /// In the current run only.
+    Added(String),
+    /// In the saved run only.
+    Removed(String),
+    Changed {
+        name: String,
+        price_change: f64,
+        /// None when the saved price was zero.
+        price_change_percent: Option<f64>,
+        /// Places climbed, so negative when the coin fell; None unless both
+        /// runs know its rank.
+        rank_change: Option<i64>,
+    },
+}
+
+/// Matches coins by id between `previous` and `current`. Coins come in
+/// current's order, followed by those that have since gone.
+pub fn diff_runs(previous: &[CoinInfo], current: &[CoinInfo]) -> Vec<CoinDelta> {
+    let before: HashMap<&str, &CoinInfo> = previous
+        .iter()
+        .map(|info| (info.name.as_str(), info))
+        .collect();
+    let mut deltas: Vec<CoinDelta> = current
+        .iter()
+        .map(|info| match before.get(info.name.as_str()) {
+            None => CoinDelta::Added(info.name.clone()),
+            Some(old) => {
+                let price_change = info.current_price - old.current_price;
+                CoinDelta::Changed {
+                    name: info.name.clone(),
+                    price_change,
+                    price_change_percent: (old.current_price != 0.0)
+                        .then_some(price_change * 100.0 / old.current_price.abs()),
+                    rank_change: old
+                        .rank
+                        .zip(info.rank)
+                        .map(|(old, new)| i64::from(old) - i64::from(new)),
+                }
+            }
+        })
+        .collect();
+    let now: Vec<&str> = current.iter().map(|info| info.name.as_str()).collect();
+    deltas.extend(
+        previous
+            .iter()
+            .filter(|info| !now.contains(&info.name.as_str()))
+            .map(|info| CoinDelta::Removed(info.name.clone())),
+    );
+    deltas
+}
+
+/// One holding valued at its coin's current price.
+#[derive(Debug, PartialEq)]
+pub struct Position {
+    pub name: String,
+    pub amount: f64,
+    pub price: f64,
+    pub value: f64,
+}
+
+/// What a set of holdings is worth.
+#[derive(Debug, PartialEq)]
+pub struct Portfolio {
+    /// In the order the holdings were given.
+    pub positions: Vec<Position>,
+    pub total: f64,
+    /// Holdings that matched none of the coins.
+    pub unknown: Vec<String>,
+}
+
+/// Values each (coin id or symbol, amount) holding at the current price of
+/// its coin in `infos`.
+pub fn portfolio(infos: &[CoinInfo], holdings: &[(String, f64)]) -> Portfolio {
+    let mut positions = Vec::new();
+    let mut unknown = Vec::new();
+    for (coin, amount) in holdings {
+        let info = infos.iter().find(|info| {
+            info.name.eq_ignore_ascii_case(coin)
+                || info
+                    .symbol
+                    .as_ref()
+                    .is_some_and(|symbol| symbol.eq_ignore_ascii_case(coin))
+        });
+        match info {
+            Some(info) => positions.push(Position {
+                name: info.name.clone(),
+                amount: *amount,
+                price: info.current_price,
+                value: amount * info.current_price,
+            }),
+            None => unknown.push(coin.clone()),
+        }
+    }
+    Portfolio {
+        total: positions.iter().map(|position| position.value).sum(),
+        positions,
+        unknown,
+    }
+}
+
+/// Sums up a run that produced `infos` and reported `failed` coins.
+pub fn run_summary(infos: &[CoinInfo], failed: usize, elapsed: Duration) -> RunSummary {
+    let mut movers: Vec<&CoinInfo> = infos.iter().filter(|info| info.points >= 2).collect();
+    movers.sort_by(|a, b| {
+        let magnitude = |info: &CoinInfo| info.window_change_percent.abs();
+        magnitude(b).total_cmp(&magnitude(a))
+    });
+    RunSummary {
+        total_coins: infos.len() + failed,
+        succeeded: infos.len(),
+        failed,
+        cache_hit_rate: cache_hit_rate(infos),
+        elapsed_ms: elapsed.as_millis() as u64,
+        top_movers: movers
+            .into_iter()
+            .take(TOP_MOVERS)
+            .map(|info| Mover {
+                name: info.name.clone(),
+                change_percent: info.window_change_percent,
+            })
+            .collect(),
+    }
+}
+
+pub fn render_summary(summary: &MarketSummary) -> String {
+    let mut line = format!(
+        "{} up, {} down, average change {:+.2}%",
+        summary.up, summary.down, summary.average_change_percent
+    );
+    if let Some((name, change)) = &summary.strongest {
+        line.push_str(&format!(", strongest {} {:+.2}%", name, change));
+    }
+    if let Some((name, change)) = &summary.weakest {
+        line.push_str(&format!(", weakest {} {:+.2}%", name, change));
+    }
+    line
+}
+
+/// The share of coins whose history was served from the cache, or None
+/// when none of them say where theirs came from.
+pub fn cache_hit_rate(infos: &[CoinInfo]) -> Option<f64> {
+    let sources: Vec<Source> = infos.iter().filter_map(|info| info.source).collect();
+    if sources.is_empty() {
+        return None;
+    }
+    let hits = sources
+        .iter()
+        .filter(|source| **source == Source::Cache)
+        .count();
+    Some(hits as f64 / sources.len() as f64)
+}
+
+/// How many histories to fetch at once: `requested` clamped to
+/// 1..=MAX_CONCURRENCY, or the CPU count capped at DEFAULT_CONCURRENCY.
+pub fn concurrency_limit(requested: Option<usize>) -> usize {
+    match requested {
+        Some(n) => n.clamp(1, MAX_CONCURRENCY),
+        None => std::thread::available_parallelism()
+            .map_or(1, usize::from)
+            .min(DEFAULT_CONCURRENCY),
+    }
+}
+
+pub fn print_cache_index<W: Write>(cache: &Cache, out: &mut W) -> io::Result<()> {
+    for (key, fetched_at) in cache.index() {
+        let fetched_at = Utc
+            .timestamp_millis_opt(fetched_at as i64)
+            .single()
+            .map(|time| time.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+            .unwrap_or_default();
+        writeln!(out, "{:<30} {}", key, fetched_at)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    // write a test for the function get_coin_info which doesn't use a mock server as the function doesn't need to make any HTTP requests
+    async fn test_get_coin_info() {
+        // mock a CoinData struct
+        let coin_data = CoinData {
+            data: vec![
+                PriceData {
+                    time: 1356998400000,
+                    priceUsd: "13.8".to_string(),
+                },
+                PriceData {
+                    time: 1357084800000,
+                    priceUsd: "13.98".to_string(),
+                },
+                PriceData {
+                    time: 1357084800000,
+                    priceUsd: "13.9".to_string(),
+                },
+            ],
+        };
+        let coin_info = get_coin_info(coin_data, "bitcoin").await;
+        assert!(coin_info.is_ok());
+        let coin_info = coin_info.unwrap();
+        assert_eq!(coin_info.name, "bitcoin");
+        // 13.98 shares its timestamp with the later 13.9, which replaces it
+        assert_eq!(coin_info.all_time_high, 13.9);
+        assert_eq!(coin_info.all_time_low, 13.8);
+        assert_eq!(coin_info.current_price, 13.9);
+    }
+
+    #[test]
+    fn test_window_change_percent() {
+        let series = |prices: &[&str]| -> Vec<PriceData> {
+            prices
+                .iter()
+                .enumerate()
+                .map(|(i, p)| PriceData {
+                    time: i as u64,
+                    priceUsd: p.to_string(),
+                })
+                .collect()
+        };
+        let change = |prices: &[&str]| PriceStats::from_prices(&series(prices)).change_percent();
+        assert_eq!(change(&["10", "12", "11", "15"]), 50.0);
+        assert_eq!(change(&["bad", "20", "25", "bad"]), 25.0);
+        assert_eq!(change(&["10"]), 0.0);
+        assert_eq!(change(&[]), 0.0);
+    }
+
+    #[test]
+    fn test_sort_infos_by_price_and_drawdown() {
+        let coin = |name: &str, current_price: f64, all_time_high: f64| CoinInfo {
+            name: name.to_string(),
+            current_price,
+            all_time_high,
+            ..Default::default()
+        };
+        let mut infos = vec![
+            coin("a", 50.0, 100.0),
+            coin("b", 10.0, 10.0),
+            coin("c", 30.0, 120.0),
+            coin("d", 5.0, 0.0),
+        ];
+        let names = |infos: &[CoinInfo]| -> String {
+            infos.iter().map(|info| info.name.as_str()).collect()
+        };
+        sort_infos(&mut infos, SortKey::Price, false);
+        assert_eq!(names(&infos), "dbca");
+        sort_infos(&mut infos, SortKey::Price, true);
+        assert_eq!(names(&infos), "acbd");
+        // d has no high to draw down from, so it stays last.
+        sort_infos(&mut infos, SortKey::Drawdown, true);
+        assert_eq!(names(&infos), "cabd");
+        sort_infos(&mut infos, SortKey::Drawdown, false);
+        assert_eq!(names(&infos), "bacd");
+    }
+
+    #[test]
+    fn test_recent_range_covers_last_30_days() {
+        let day = 24 * 60 * 60 * 1000;
+        let prices: Vec<PriceData> = [(0, "50"), (10, "5"), (60, "20"), (80, "30"), (90, "25")]
+            .iter()
+            .map(|(days, price)| PriceData {
+                priceUsd: price.to_string(),
+                time: days * day,
+            })
+            .collect();
+        let stats = PriceStats::from_prices(&prices);
+        assert_eq!(stats.recent_range(RECENT_WINDOW_MS), Some((20.0, 30.0)));
+        assert_eq!(stats.recent_range(100 * day), Some((5.0, 50.0)));
+        assert_eq!(PriceStats::default().recent_range(RECENT_WINDOW_MS), None);
+    }
+
+    #[test]
+    fn test_dedupe_by_time_keeps_last() {
+        let point = |time: u64, price: &str| PriceData {
+            time,
+            priceUsd: price.to_string(),
+        };
+        let deduped = dedupe_by_time(vec![
+            point(2, "20"),
+            point(1, "10"),
+            point(2, "21"),
+            point(3, "30"),
+            point(3, "31"),
+        ]);
+        let deduped: Vec<(u64, &str)> = deduped
+            .iter()
+            .map(|p| (p.time, p.priceUsd.as_str()))
+            .collect();
+        assert_eq!(deduped, vec![(1, "10"), (2, "21"), (3, "31")]);
+    }
+
+    #[test]
+    fn test_summarize_batch() {
+        let coin = |name: &str, change: f64, points: usize| CoinInfo {
+            name: name.to_string(),
+            window_change_percent: change,
+            points,
+            ..Default::default()
+        };
+        let infos = vec![
+            coin("bitcoin", 40.0, 10),
+            coin("ethereum", -10.0, 10),
+            coin("litecoin", 0.0, 10),
+            coin("dogecoin", 30.0, 10),
+            coin("new-coin", 500.0, 1),
+        ];
+        let summary = summarize(&infos);
+        assert_eq!(
+            summary,
+            MarketSummary {
+                up: 2,
+                down: 1,
+                average_change_percent: 15.0,
+                strongest: Some(("bitcoin".to_string(), 40.0)),
+                weakest: Some(("ethereum".to_string(), -10.0)),
+            }
+        );
+        assert_eq!(
+            render_summary(&summary),
+            "2 up, 1 down, average change +15.00%, strongest bitcoin +40.00%, weakest ethereum -10.00%"
+        );
+        assert_eq!(
+            render_summary(&summarize(&[])),
+            "0 up, 0 down, average change +0.00%"
+        );
+    }
+
+    #[test]
+    fn test_parse_price_tolerates_separators() {
+        assert_eq!(parse_price("1,234.56"), Some(1234.56));
+        assert_eq!(parse_price(" 1,234,567 "), Some(1234567.0));
+        assert_eq!(parse_price("-12,000.5"), Some(-12000.5));
+        assert_eq!(parse_price(" 13.8\n"), Some(13.8));
+        assert_eq!(parse_price("abc"), None);
+        assert_eq!(parse_price("1,23.4"), None);
+        assert_eq!(parse_price("1234,567"), None);
+        assert_eq!(parse_price("1.234,5"), None);
+        assert_eq!(parse_price(""), None);
+    }
+
+    #[tokio::test]
+    async fn test_get_coin_info_rejects_unparseable_prices() {
+        let coin_data = CoinData {
+            data: vec![
+                PriceData {
+                    time: 1,
+                    priceUsd: "n/a".to_string(),
+                },
+                PriceData {
+                    time: 2,
+                    priceUsd: "".to_string(),
+                },
+            ],
+        };
+        let err = get_coin_info(coin_data, "bitcoin").await.unwrap_err();
+        assert!(err.to_string().contains("no parseable prices for bitcoin"));
+    }
+
+    #[test]
+    fn test_price_stats_match_separate_folds() {
+        let prices: Vec<PriceData> = ["13.8", "14.2", "bad", "12.9", "13.5"]
+            .iter()
+            .enumerate()
+            .map(|(i, price)| PriceData {
+                time: i as u64,
+                priceUsd: price.to_string(),
+            })
+            .collect();
+        let parsed = || prices.iter().filter_map(|x| parse_price(&x.priceUsd));
+        let stats = PriceStats::from_prices(&prices);
+        assert_eq!(stats.max, parsed().fold(f64::MIN, |acc, x| acc.max(x)));
+        assert_eq!(stats.min, parsed().fold(f64::INFINITY, |acc, x| acc.min(x)));
+        assert_eq!(stats.sum, parsed().sum::<f64>());
+        assert_eq!(stats.count, 4);
+        assert_eq!(stats.mean(), Some(parsed().sum::<f64>() / 4.0));
+        assert_eq!((stats.first, stats.last), (Some(13.8), Some(13.5)));
+        assert_eq!((stats.min_time, stats.max_time), (Some(3), Some(1)));
+        assert_eq!(stats.series, parsed().collect::<Vec<_>>());
+        assert_eq!(stats.change_percent(), (13.5 - 13.8) * 100.0 / 13.8);
+    }
+
+    #[test]
+    fn test_volatility_rsi_and_trend() {
+        assert_eq!(volatility(&[10.0, 11.0]), None);
+        // returns of +10% and -10%
+        let swing = volatility(&[10.0, 11.0, 9.9]).unwrap();
+        assert!((swing - 200f64.sqrt()).abs() < 1e-9);
+        assert_eq!(rsi(&[1.0, 2.0], RSI_PERIOD), None);
+        assert_eq!(rsi(&[1.0, 2.0, 3.0], 2), Some(100.0));
+        assert_eq!(rsi(&[3.0, 2.0, 3.0], 2), Some(50.0));
+        assert_eq!(trend(&[10.0, 10.0, 12.0]), Some("up"));
+        assert_eq!(trend(&[10.0, 10.0, 10.0]), Some("sideways"));
+        assert_eq!(trend(&[]), None);
+    }
+
+    #[test]
+    fn test_sharpe_ratio_guards_flat_series() {
+        // returns of +10% and -10%, and a 1% change over the window
+        let swing = volatility(&[10.0, 11.0, 9.9]);
+        let ratio = sharpe_ratio(-1.0, swing).unwrap();
+        assert!((ratio + 1.0 / 200f64.sqrt()).abs() < 1e-9);
+        assert_eq!(sharpe_ratio(5.0, volatility(&[10.0, 10.0, 10.0])), None);
+        assert_eq!(sharpe_ratio(5.0, Some(0.0)), None);
+        assert_eq!(sharpe_ratio(5.0, None), None);
+
+        let prices: Vec<PriceData> = [10.0, 11.0, 9.9]
+            .iter()
+            .enumerate()
+            .map(|(i, price)| PriceData {
+                time: i as u64,
+                priceUsd: price.to_string(),
+            })
+            .collect();
+        let info = coin_info(PriceStats::from_prices(&prices), "coin").unwrap();
+        assert_eq!(
+            info.sharpe_ratio,
+            sharpe_ratio(info.window_change_percent, swing)
+        );
+        let mut infos = vec![
+            info,
+            CoinInfo {
+                name: "flat".to_string(),
+                ..Default::default()
+            },
+            CoinInfo {
+                name: "steady".to_string(),
+                sharpe_ratio: Some(2.0),
+                ..Default::default()
+            },
+        ];
+        sort_infos(&mut infos, SortKey::Sharpe, true);
+        let order: Vec<&str> = infos.iter().map(|info| info.name.as_str()).collect();
+        assert_eq!(order, ["steady", "coin", "flat"]);
+    }
+
+    #[test]
+    fn test_concurrency_limit_clamps() {
+        assert_eq!(concurrency_limit(Some(0)), 1);
+        assert_eq!(concurrency_limit(Some(4)), 4);
+        assert_eq!(concurrency_limit(Some(10_000)), MAX_CONCURRENCY);
+        let default = concurrency_limit(None);
+        assert!((1..=DEFAULT_CONCURRENCY).contains(&default));
+    }
+
+    #[test]
+    fn test_window_last_span_from_fixed_clock() {
+        let now = 1_700_000_000_000;
+        let fixed = clock::FixedClock::at_millis(now);
+        let window = Window::last(clock::parse_span("7d").unwrap(), &fixed);
+        assert_eq!(window.end_ms, now);
+        assert_eq!(window.start_ms, now - 7 * 24 * 60 * 60 * 1000);
+    }
+
+    #[test]
+    fn test_stablecoin_classification() {
+        let coin = |current_price: f64, history: Vec<f64>| CoinInfo {
+            current_price,
+            history,
+            ..Default::default()
+        };
+        let flat = coin(1.0, vec![1.0, 1.001, 0.999, 1.0, 1.002]);
+        let volatile = coin(1.0, vec![0.6, 1.3, 0.8, 1.4, 1.0]);
+        let pricey = coin(60_000.0, vec![60_000.0, 60_010.0, 60_000.0]);
+        let filter = StablecoinFilter::default();
+        assert!(filter.is_stablecoin(&flat));
+        assert!(!filter.is_stablecoin(&volatile));
+        assert!(!filter.is_stablecoin(&pricey));
+
+        let exclude = StablecoinFilter {
+            mode: StablecoinMode::Exclude,
+            ..filter
+        };
+        assert!(!exclude.keeps(&flat) && exclude.keeps(&volatile));
+        let only = StablecoinFilter {
+            mode: StablecoinMode::Only,
+            ..filter
+        };
+        assert!(only.keeps(&flat) && !only.keeps(&volatile));
+        let loose = StablecoinFilter {
+            max_volatility: 100.0,
+            ..filter
+        };
+        assert!(loose.is_stablecoin(&volatile));
+    }
+
+    #[test]
+    fn test_correlation_over_shared_times() {
+        let history = |points: &[(u64, f64)]| CoinData {
+            data: points
+                .iter()
+                .map(|(time, price)| PriceData {
+                    priceUsd: price.to_string(),
+                    time: *time,
+                })
+                .collect(),
+        };
+        let a = history(&[(1, 10.0), (2, 12.0), (3, 11.0), (4, 15.0), (5, 99.0)]);
+        // Same moves at twice the price, missing time 5 and with an extra time 6.
+        let b = history(&[(1, 20.0), (2, 24.0), (3, 22.0), (4, 30.0), (6, 1.0)]);
+        assert!((correlation(&a, &b).unwrap() - 1.0).abs() < 1e-9);
+        let inverse = history(&[(1, 5.0), (2, 3.0), (3, 4.0), (4, 0.0)]);
+        assert!((correlation(&a, &inverse).unwrap() + 1.0).abs() < 1e-9);
+        assert_eq!(correlation(&a, &history(&[(1, 20.0), (7, 30.0)])), None);
+        assert_eq!(correlation(&a, &history(&[(1, 5.0), (2, 5.0)])), None);
+    }
+
+    #[test]
+    fn test_relative_to_benchmark() {
+        let coin = |name: &str, symbol: &str, change: f64| CoinInfo {
+            name: name.to_string(),
+            symbol: Some(symbol.to_string()),
+            window_change_percent: change,
+            ..Default::default()
+        };
+        let mut infos = vec![
+            coin("bitcoin", "BTC", 10.0),
+            coin("ethereum", "ETH", 25.0),
+            coin("dogecoin", "DOGE", -5.0),
+        ];
+        relative_to_benchmark(&mut infos, "btc").unwrap();
+        let spreads: Vec<(&str, Option<f64>)> = infos
+            .iter()
+            .map(|info| (info.name.as_str(), info.relative_change_percent))
+            .collect();
+        assert_eq!(
+            spreads,
+            [("ethereum", Some(15.0)), ("dogecoin", Some(-15.0))]
+        );
+        let err = relative_to_benchmark(&mut infos, "bitcoin").unwrap_err();
+        assert!(err.contains("bitcoin"), "{}", err);
+    }
+
+    #[test]
+    fn test_time_accepts_numeric_string() {
+        let data: CoinData = serde_json::from_str(
+            r#"{"data": [{"priceUsd": "1", "time": "1672531200000"}, {"priceUsd": "2", "time": 1672617600000}]}"#,
+        )
+        .unwrap();
+        let times: Vec<u64> = data.data.iter().map(|point| point.time).collect();
+        assert_eq!(times, [1_672_531_200_000, 1_672_617_600_000]);
+        assert!(serde_json::from_str::<PriceData>(r#"{"priceUsd": "1", "time": "soon"}"#).is_err());
+    }
+
+    #[test]
+    fn test_price_stats_negative_series() {
+        let prices: Vec<PriceData> = ["-0.05", "-0.01", "-0.08", "-0.03"]
+            .iter()
+            .enumerate()
+            .map(|(i, price)| PriceData {
+                time: i as u64,
+                priceUsd: price.to_string(),
+            })
+            .collect();
+        let info = coin_info(PriceStats::from_prices(&prices), "funding").unwrap();
+        assert_eq!((info.all_time_low, info.all_time_high), (-0.08, -0.01));
+        assert_eq!(
+            (info.all_time_low_time, info.all_time_high_time),
+            (Some(2), Some(1))
+        );
+        assert_eq!(info.current_price, -0.03);
+        // -0.05 to -0.03 is a rise
+        assert!((info.window_change_percent - 40.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_diff_runs_added_removed_changed() {
+        let coin = |name: &str, price: f64, rank: Option<u32>| CoinInfo {
+            name: name.to_string(),
+            current_price: price,
+            rank,
+            ..Default::default()
+        };
+        let previous = vec![
+            coin("bitcoin", 20_000.0, Some(1)),
+            coin("litecoin", 80.0, Some(12)),
+            coin("solana", 20.0, Some(9)),
+        ];
+        let current = vec![
+            coin("bitcoin", 21_000.0, Some(1)),
+            coin("solana", 18.0, Some(11)),
+            coin("pepe", 0.001, None),
+        ];
+        assert_eq!(
+            diff_runs(&previous, &current),
+            vec![
+                CoinDelta::Changed {
+                    name: "bitcoin".to_string(),
+                    price_change: 1_000.0,
+                    price_change_percent: Some(5.0),
+                    rank_change: Some(0),
+                },
+                CoinDelta::Changed {
+                    name: "solana".to_string(),
+                    price_change: -2.0,
+                    price_change_percent: Some(-10.0),
+                    rank_change: Some(-2),
+                },
+                CoinDelta::Added("pepe".to_string()),
+                CoinDelta::Removed("litecoin".to_string()),
+            ]
+        );
+        // A snapshot saved with only some --fields still reads back.
+        let saved: Vec<CoinInfo> =
+            serde_json::from_str(r#"[{"name": "bitcoin", "current_price": 1.5}]"#).unwrap();
+        assert_eq!(saved[0].current_price, 1.5);
+    }
+
+    #[test]
+    fn test_portfolio_values_holdings() {
+        let coin = |name: &str, symbol: &str, price: f64| CoinInfo {
+            name: name.to_string(),
+            symbol: Some(symbol.to_string()),
+            current_price: price,
+            ..Default::default()
+        };
+        let infos = [
+            coin("bitcoin", "BTC", 30000.0),
+            coin("ethereum", "ETH", 2000.0),
+        ];
+        let holdings = [("btc", 0.5), ("ethereum", 3.0), ("doge", 100.0)]
+            .map(|(coin, amount)| (coin.to_string(), amount));
+        let portfolio = portfolio(&infos, &holdings);
+        let values: Vec<(&str, f64)> = portfolio
+            .positions
+            .iter()
+            .map(|position| (position.name.as_str(), position.value))
+            .collect();
+        assert_eq!(values, [("bitcoin", 15000.0), ("ethereum", 6000.0)]);
+        assert_eq!(portfolio.total, 21000.0);
+        assert_eq!(portfolio.unknown, ["doge"]);
+    }
+}
@@ -1,144 +1,1059 @@
-use serde::{Deserialize, Serialize};
+use clap::builder::PossibleValuesParser;
+use clap::{Parser, Subcommand, ValueEnum};
+use coin_data::cache::{self, Cache};
+use coin_data::clock::{self, Clock, FixedClock, SystemClock};
+use coin_data::config::Config;
+use coin_data::fixtures::FileProvider;
+use coin_data::render::{
+    self, BarRenderer, BarStyle, CsvRenderer, JsonRenderer, NdjsonStatsRenderer,
+    PrometheusRenderer, RelativeRenderer, Renderer, SvgRenderer, TableRenderer, Template,
+    TemplateRenderer, Theme,
+};
+use coin_data::{
+    build_client, cache_hit_rate, concurrency_limit, correlation, diff_runs, healthcheck,
+    print_cache_index, push_metrics, relative_to_benchmark, render_summary, run, run_summary,
+    sort_infos, summarize, BodyLimits, CandleSource, ConnectionOptions, HostLimit, PriceSource,
+    Retry, RunOptions, RunReport, SortKey, StablecoinFilter, StablecoinMode, Throttle, Window,
+    API_URL, DEFAULT_CONNECTIONS_PER_HOST, DEFAULT_MAX_RESPONSE_BYTES, INTERVALS,
+};
+use std::fs;
+use std::io::{self, BufWriter, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::EnvFilter;
 
-const API_URL: &str = "https://api.coincap.io/v2";
-const START_AND_END: &str = "start=1356931594000&end=1675817253000";
+const HEALTHCHECK_TIMEOUT_SECS: u64 = 5;
+// How far back show --recent looks.
+const RECENT_SPAN: Duration = Duration::from_secs(7 * 24 * 60 * 60);
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Cryptocurrency {
-    id: String,
-    rank: String,
-    symbol: String,
-    name: String,
+#[derive(Parser, Debug)]
+#[command(about = "Shows where each coin's current price sits between its low and high")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Base URL of the CoinCap API
+    #[arg(long, global = true, default_value = API_URL)]
+    base_url: String,
+    /// CoinCap API key, sent as a bearer token
+    #[arg(long, global = true)]
+    api_key: Option<String>,
+    /// Seconds to wait for each request before giving up
+    #[arg(long, global = true)]
+    timeout: Option<u64>,
+    /// Output format
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Bar)]
+    format: OutputFormat,
+    /// Comma-separated list of fields to include in json/csv output
+    #[arg(long, global = true, value_delimiter = ',')]
+    fields: Option<Vec<String>>,
+    /// Add window_start and window_end, the span the high and low cover,
+    /// to json/csv output
+    #[arg(long, global = true)]
+    window_bounds: bool,
+    /// Comma-separated table columns in the order to show them, from
+    /// rank, name, symbol, price, low, high, change and bar
+    #[arg(long, global = true, value_delimiter = ',')]
+    columns: Option<Vec<String>>,
+    /// Comma-separated coin ids or symbols to show instead of the whole list
+    #[arg(long, global = true, value_delimiter = ',')]
+    coins: Vec<String>,
+    /// Directory to cache fetched histories in
+    #[arg(long, global = true)]
+    cache_dir: Option<PathBuf>,
+    /// Seconds a cached history is used for before it is fetched again
+    #[arg(long, global = true, default_value_t = 3600)]
+    cache_ttl: u64,
+    /// Print the cached coins and when they were fetched, then exit
+    #[arg(long, global = true, requires = "cache_dir")]
+    list_cache: bool,
+    /// Use closing prices of this exchange's candles instead of /history
+    #[arg(long, global = true)]
+    candles: Option<String>,
+    /// Quote asset id for --candles
+    #[arg(long, global = true, default_value = "tether")]
+    quote: String,
+    /// Which candle price the stats use with --candles [default: close]
+    #[arg(long, global = true, value_enum, requires = "candles")]
+    price_source: Option<PriceSource>,
+    /// Only use cached data and fail instead of making any request
+    #[arg(long, global = true)]
+    offline: bool,
+    /// Stop at the first coin that fails instead of reporting it and going
+    /// on with the rest
+    #[arg(long, global = true)]
+    fail_fast: bool,
+    /// With --offline, skip coins that aren't cached with a warning instead
+    /// of reporting them as errors
+    #[arg(long, global = true, requires = "offline")]
+    best_effort: bool,
+    /// Hours after which a cached coin list is too stale to use offline
+    #[arg(long, global = true, default_value_t = 24)]
+    max_list_age: u64,
+    /// Decimals to show prices with, instead of choosing by magnitude
+    #[arg(long, global = true)]
+    price_decimals: Option<usize>,
+    /// Shade each bar by its coin's 24h volume relative to the other coins
+    #[arg(long, global = true)]
+    shade_by_volume: bool,
+    /// Seed the retry jitter so backoff delays are reproducible
+    #[arg(long, global = true)]
+    seed: Option<u64>,
+    /// Directory to write one <coin>.svg per coin to with --format svg,
+    /// instead of printing a combined chart
+    #[arg(long, global = true)]
+    output: Option<PathBuf>,
+    /// Directory to write a small <coin>.png chart per coin to (needs the
+    /// png feature)
+    #[arg(long, global = true)]
+    png: Option<PathBuf>,
+    /// Draw only every Nth history point in svg and png charts; this only
+    /// thins the drawing, the stats still use every point
+    #[arg(long, global = true, default_value_t = 1, value_name = "N")]
+    sample: usize,
+    /// Draw each coin's logo before its bar in iTerm2, WezTerm or kitty,
+    /// caching logos under --cache-dir (needs the icons feature)
+    #[arg(long, global = true)]
+    icons: bool,
+    /// Pick the history interval that gives about 500 points over the window
+    #[arg(long, global = true)]
+    interval_auto: bool,
+    /// Show each coin's symbol as a link to its CoinCap page, when stdout is
+    /// a terminal
+    #[arg(long, global = true)]
+    links: bool,
+    /// Prefix each bar or table row with the coin's rank (tables whose
+    /// --columns include rank already show it)
+    #[arg(long, global = true)]
+    show_rank: bool,
+    /// Show prices in this currency (e.g. EUR) instead of USD
+    #[arg(long, global = true)]
+    convert: Option<String>,
+    /// Only fetch this much recent history, e.g. 7d or 12h, instead of the
+    /// default window
+    #[arg(long, global = true, value_parser = parse_window)]
+    window: Option<Duration>,
+    /// Keep, drop or only show coins that look like stablecoins
+    #[arg(long, global = true, value_enum, default_value_t = StablecoinMode::Include)]
+    stablecoins: StablecoinMode,
+    /// How far from $1 a stablecoin's price may be
+    #[arg(long, global = true, default_value_t = 0.02)]
+    stable_band: f64,
+    /// Highest volatility, in percent, a stablecoin may have
+    #[arg(long, global = true, default_value_t = 0.5)]
+    stable_volatility: f64,
+    /// Print each coin's history as fetched instead of the computed stats, as
+    /// CSV with --format csv and JSON otherwise
+    #[arg(long, global = true)]
+    raw: bool,
+    /// Histories to fetch at once, from 1 to 32 (default: CPUs, at most 8)
+    #[arg(long, global = true)]
+    concurrency: Option<usize>,
+    /// Draw a coin's bar against your entry price, as coin=price, showing
+    /// the gain or loss since (repeatable)
+    #[arg(long, global = true, value_parser = parse_baseline)]
+    baseline: Vec<(String, f64)>,
+    /// Coins held, as comma-separated coin=amount pairs, e.g. btc=0.5,eth=3
+    #[arg(long, global = true, value_delimiter = ',', value_parser = parse_holding)]
+    holdings: Vec<(String, f64)>,
+    /// Show what each of --holdings is worth and their total instead of
+    /// the list
+    #[arg(long, global = true, requires = "holdings")]
+    group_profit: bool,
+    /// Group bars or table rows into rank tiers, each under its own header
+    #[arg(long, global = true)]
+    group_by_rank_tier: bool,
+    /// Comma-separated ranks each tier ends at, for --group-by-rank-tier
+    #[arg(long, global = true, value_delimiter = ',', default_values_t = render::DEFAULT_TIERS)]
+    tiers: Vec<u32>,
+    /// Fetch a coin's history once more when it comes back empty, in case
+    /// the API is having a transient problem
+    #[arg(long, global = true)]
+    retry_empty: bool,
+    /// Only show coins whose symbol matches this regular expression, e.g.
+    /// ^USD
+    #[arg(long, global = true, value_parser = parse_coins_regex)]
+    coins_regex: Option<regex::Regex>,
+    /// Let --coins-regex match coin names as well as symbols
+    #[arg(long, global = true, requires = "coins_regex")]
+    regex_names: bool,
+    /// Only show coins in this category, e.g. defi, l1 or stablecoin
+    /// ("uncategorized" for coins without one)
+    #[arg(long, global = true)]
+    category: Option<String>,
+    /// Order the output by this stat instead of by rank, lowest first
+    #[arg(long, global = true, value_enum)]
+    sort_by: Option<SortKey>,
+    /// Sort highest first with --sort-by
+    #[arg(long, global = true, requires = "sort_by")]
+    desc: bool,
+    /// Print each coin as this line instead, e.g. "{rank} {symbol} {price}
+    /// {bar} {change}"; see render::TEMPLATE_FIELDS for the placeholders
+    #[arg(long, global = true, value_parser = Template::parse)]
+    template: Option<Template>,
+    /// Also write the list to this file, as CSV for a .csv path and JSON
+    /// otherwise, alongside the --format output
+    #[arg(long = "export", global = true)]
+    export_path: Option<PathBuf>,
+    /// After the output, say where each coin's price sits between its low
+    /// and high; bar and table output only
+    #[arg(long, global = true)]
+    explain: bool,
+    /// Log fetch spans and their timings to stderr at this level or with
+    /// this filter, e.g. debug or coin_data=debug
+    #[arg(long, global = true, value_parser = parse_log_filter)]
+    log: Option<String>,
+    /// After the run, write a JSON summary of it (coin counts, cache hit
+    /// rate, elapsed time and top movers) to this file
+    #[arg(long = "summary", global = true)]
+    summary_path: Option<PathBuf>,
+    /// Show what changed since a run saved with --format json: price
+    /// changes, rank moves and coins that came or went
+    #[arg(long, global = true)]
+    diff: Option<PathBuf>,
+    /// Also say how long ago the high and low were in show, e.g.
+    /// "3 months ago", measured from --as-of when given
+    #[arg(long, global = true)]
+    relative_dates: bool,
+    /// Indent JSON output for reading; it is compact by default
+    #[arg(long, global = true)]
+    json_pretty: bool,
+    /// Show each coin's circulating supply and how much of its maximum
+    /// supply that is
+    #[arg(long, global = true)]
+    show_supply: bool,
+    /// How bars are drawn: classic, dots, blocks or heat
+    #[arg(long, global = true, default_value = "classic", value_parser = parse_theme)]
+    theme: Theme,
+    /// Mark where the last 30 days' low and high sit on each bar, as [ and ],
+    /// and add recent_low and recent_high to json/csv output
+    #[arg(long, global = true)]
+    ticks: bool,
+    /// Treat this RFC 3339 time as now: the window ends there and later
+    /// points are ignored, so runs over cached data are reproducible
+    #[arg(long, global = true, value_parser = parse_as_of)]
+    as_of: Option<SystemTime>,
+    /// Read assets.json, rates.json and <id>_<interval>.json from this
+    /// directory instead of calling the API
+    #[arg(long, global = true)]
+    fixtures: Option<PathBuf>,
+    /// Use a small dataset built into the binary instead of calling the API
+    #[arg(long, global = true, conflicts_with = "fixtures")]
+    demo: bool,
+    /// Seconds an idle connection is kept open for reuse
+    #[arg(long, global = true, default_value_t = 90)]
+    pool_idle_secs: u64,
+    /// Idle connections kept open per host
+    #[arg(long, global = true, default_value_t = DEFAULT_CONNECTIONS_PER_HOST)]
+    pool_max_idle: usize,
+    /// Requests in flight to one host at a time, whatever --concurrency is
+    #[arg(long, global = true, default_value_t = DEFAULT_CONNECTIONS_PER_HOST)]
+    max_per_host: usize,
+    /// Redirects followed per request before it fails
+    #[arg(long, global = true, default_value_t = 5)]
+    max_redirects: usize,
+    /// Fail instead of following a redirect to a different host
+    #[arg(long, global = true)]
+    same_host_redirects: bool,
+    /// Download no faster than this many KB/s, over all requests together
+    #[arg(long, global = true)]
+    limit_bandwidth: Option<u64>,
+    /// Show each coin's window change minus the benchmark's, leaving the
+    /// benchmark itself out, and add relative_change_percent to json/csv
+    /// output
+    #[arg(long, global = true)]
+    relative_to_market: bool,
+    /// Coin id or symbol --relative-to-market compares against
+    #[arg(long, global = true, default_value = "bitcoin")]
+    benchmark: String,
+    /// TOML file with per-coin settings, e.g. an [intervals] table of
+    /// coin = "h1" overrides
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    /// Fail any response whose body is larger than this many MB
+    #[arg(long, global = true, default_value_t = DEFAULT_MAX_RESPONSE_BYTES / (1024 * 1024))]
+    max_response_mb: usize,
+    /// Pushgateway base URL for --format prometheus-pushgateway
+    #[arg(long, global = true)]
+    pushgateway: Option<String>,
+    /// Job label to push metrics under
+    #[arg(long, global = true, default_value = "coin_data")]
+    job: String,
+    /// Print how closely two coins' prices moved together over the window,
+    /// instead of rendering the list
+    #[arg(long, global = true, num_args = 2, value_names = ["COIN", "OTHER"])]
+    correlate: Option<Vec<String>>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct CryptoList {
-    data: Vec<Cryptocurrency>,
+#[derive(Subcommand, Debug, PartialEq)]
+enum Command {
+    /// Render every listed coin, or those picked with --coins (the default)
+    List,
+    /// Print every stat for a single coin
+    Show {
+        /// Coin id or symbol
+        coin: String,
+        /// Also fetch the last week at this finer interval, e.g. h1, and
+        /// draw it under the long range
+        #[arg(long, value_parser = PossibleValuesParser::new(INTERVALS))]
+        recent: Option<String>,
+    },
+    /// Render the list again every few seconds
+    Watch {
+        /// Seconds between refreshes
+        #[arg(long, default_value_t = 60)]
+        every: u64,
+        /// Stop after this many refreshes instead of running until killed
+        #[arg(long)]
+        count: Option<u64>,
+    },
+    /// Write the list to a file, as CSV for a .csv path and JSON otherwise
+    Export {
+        /// File to write
+        path: PathBuf,
+    },
+    /// Check that the API answers with valid JSON in time, exiting non-zero
+    /// when it doesn't
+    Healthcheck,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-#[allow(non_snake_case)]
-struct PriceData {
-    priceUsd: String,
-    time: u64,
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    #[value(alias = "text")]
+    Bar,
+    Table,
+    Json,
+    Csv,
+    Svg,
+    NdjsonStats,
+    Prometheus,
+    /// The Prometheus gauges in the OpenMetrics text format
+    Openmetrics,
+    /// Prometheus gauges POSTed to --pushgateway instead of printed
+    PrometheusPushgateway,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct CoinData {
-    data: Vec<PriceData>,
+fn parse_window(spec: &str) -> Result<Duration, String> {
+    clock::parse_span(spec)
+        .ok_or_else(|| format!("'{}' isn't a span like 30m, 12h, 7d or 2w", spec))
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct CoinInfo {
-    name: String,
-    all_time_high: f64,
-    all_time_low: f64,
-    current_price: f64,
+fn parse_baseline(spec: &str) -> Result<(String, f64), String> {
+    let (coin, price) = spec
+        .split_once('=')
+        .ok_or_else(|| format!("'{}' isn't coin=price", spec))?;
+    match price.trim().parse::<f64>() {
+        Ok(price) if price > 0.0 && !coin.trim().is_empty() => Ok((coin.trim().to_string(), price)),
+        _ => Err(format!("'{}' isn't coin=price with a positive price", spec)),
+    }
 }
 
-async fn get_coin_data(url: &str, name: &str, interval: &str) -> Result<CoinData, reqwest::Error> {
-    let url = format!(
-        "{}/assets/{}/history?interval={}&{}",
-        url, name, interval, START_AND_END
-    );
-    let resp = reqwest::get(&url)
-        .await?
-        .json::<CoinData>()
-        .await?;
-    Ok(resp)
-}
-
-async fn get_coins(url: &str) -> Result<CryptoList, reqwest::Error> {
-    let url = format!("{}/assets",url).to_string();
-    let resp = reqwest::get(&url).await?.json::<CryptoList>().await?;
-    Ok(resp)
-}
-
-async fn get_coin_info(
-    coin_data: CoinData,
-    name: &str,
-) -> Result<CoinInfo, Box<dyn std::error::Error>> {
-    let prices = &coin_data.data;
-
-    let all_time_high = prices
-        .iter()
-        .filter_map(|x| x.priceUsd.parse::<f64>().ok())
-        .fold(f64::MIN, |acc, x| acc.max(x));
-
-    let all_time_low = prices
-        .iter()
-        .filter_map(|x| x.priceUsd.parse::<f64>().ok())
-        .fold(f64::INFINITY, |acc, x| acc.min(x));
-
-    let current_price = match prices.last() {
-        Some(last_price) => last_price
-            .priceUsd
-            .parse::<f64>()
-            .map_err(|_| "Failed to parse priceUsd".to_owned()),
-        None => Ok(0.0),
-    }?;
-
-    Ok(CoinInfo {
-        name: name.to_string(),
-        all_time_high,
-        all_time_low,
-        current_price,
+fn parse_holding(spec: &str) -> Result<(String, f64), String> {
+    let (coin, amount) = spec
+        .split_once('=')
+        .ok_or_else(|| format!("'{}' isn't coin=amount", spec))?;
+    match amount.trim().parse::<f64>() {
+        Ok(amount) if amount > 0.0 && !coin.trim().is_empty() => {
+            Ok((coin.trim().to_string(), amount))
+        }
+        _ => Err(format!(
+            "'{}' isn't coin=amount with a positive amount",
+            spec
+        )),
+    }
+}
+
+fn parse_theme(name: &str) -> Result<Theme, String> {
+    render::theme(name).ok_or_else(|| {
+        let names: Vec<&str> = render::THEMES.iter().map(|theme| theme.name).collect();
+        format!("'{}' isn't a theme; pick one of {}", name, names.join(", "))
+    })
+}
+
+fn parse_coins_regex(pattern: &str) -> Result<regex::Regex, String> {
+    regex::Regex::new(pattern).map_err(|e| format!("'{}' isn't a valid pattern\n{}", pattern, e))
+}
+
+fn parse_log_filter(spec: &str) -> Result<String, String> {
+    EnvFilter::try_new(spec)
+        .map(|_| spec.to_string())
+        .map_err(|e| format!("'{}' isn't a log filter ({})", spec, e))
+}
+
+fn parse_as_of(spec: &str) -> Result<SystemTime, String> {
+    chrono::DateTime::parse_from_rfc3339(spec)
+        .map(SystemTime::from)
+        .map_err(|e| format!("'{}' isn't an RFC 3339 time ({})", spec, e))
+}
+
+// The coins list renders, plus the benchmark when comparing against one
+// that wasn't picked.
+fn listed_coins(cli: &Cli) -> Vec<String> {
+    let mut coins = cli.coins.clone();
+    if cli.group_profit && coins.is_empty() {
+        coins = cli.holdings.iter().map(|(coin, _)| coin.clone()).collect();
+    }
+    let picked = |coin: &String| coin.eq_ignore_ascii_case(&cli.benchmark);
+    if cli.relative_to_market && !coins.is_empty() && !coins.iter().any(picked) {
+        coins.push(cli.benchmark.clone());
+    }
+    coins
+}
+
+fn run_options(cli: &Cli, coins: Vec<String>) -> Result<RunOptions, Box<dyn std::error::Error>> {
+    let config = match &cli.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    Ok(RunOptions {
+        coins,
+        cache: cli.cache_dir.as_ref().map(Cache::new),
+        cache_ttl: Duration::from_secs(cli.cache_ttl),
+        candles: cli.candles.as_ref().map(|exchange| CandleSource {
+            exchange: exchange.clone(),
+            quote_id: cli.quote.clone(),
+            price: cli.price_source.unwrap_or_default(),
+        }),
+        offline: cli.offline,
+        best_effort_offline: cli.best_effort,
+        fail_fast: cli.fail_fast,
+        max_list_age: Some(Duration::from_secs(cli.max_list_age.saturating_mul(3600))),
+        retry: Retry::new(cli.seed),
+        convert: cli.convert.clone(),
+        interval_auto: cli.interval_auto,
+        concurrency: concurrency_limit(cli.concurrency),
+        window: window(cli, &SystemClock),
+        stablecoins: StablecoinFilter {
+            mode: cli.stablecoins,
+            band: cli.stable_band,
+            max_volatility: cli.stable_volatility,
+        },
+        keep_raw: cli.raw,
+        retry_empty: cli.retry_empty,
+        category: cli.category.clone(),
+        coins_regex: cli.coins_regex.clone(),
+        regex_matches_names: cli.regex_names,
+        fixtures: match &cli.fixtures {
+            _ if cli.demo => Some(FileProvider::demo()),
+            dir => dir.as_ref().map(FileProvider::new),
+        },
+        intervals: config.intervals,
+        // Each refresh only needs what arrived since the last one.
+        incremental: matches!(cli.command, Some(Command::Watch { .. })),
+        body_limits: BodyLimits {
+            max_bytes: cli.max_response_mb.saturating_mul(1024 * 1024),
+            throttle: cli
+                .limit_bandwidth
+                .map(|kb_per_sec| Throttle::new(kb_per_sec.saturating_mul(1024))),
+            per_host: Some(HostLimit::new(cli.max_per_host)),
+        },
+        client: build_client(
+            cli.api_key.as_deref(),
+            cli.timeout.map(Duration::from_secs),
+            &connection_options(cli),
+        )?,
+        ..Default::default()
+    })
+}
+
+// A --window span ends at `clock`'s now, unless --as-of pins it.
+fn window(cli: &Cli, clock: &dyn Clock) -> Window {
+    match (cli.window, cli.as_of) {
+        (Some(span), Some(as_of)) => Window::last(span, &FixedClock(as_of)),
+        (Some(span), None) => Window::last(span, clock),
+        (None, Some(as_of)) => Window {
+            end_ms: clock::millis_since_epoch(as_of),
+            ..Window::default()
+        },
+        (None, None) => Window::default(),
+    }
+}
+
+fn connection_options(cli: &Cli) -> ConnectionOptions {
+    ConnectionOptions {
+        idle_timeout: Duration::from_secs(cli.pool_idle_secs),
+        max_idle_per_host: cli.pool_max_idle,
+        max_redirects: cli.max_redirects,
+        same_host_redirects: cli.same_host_redirects,
+        ..ConnectionOptions::default()
+    }
+}
+
+fn fields(cli: &Cli) -> Result<Vec<String>, String> {
+    match &cli.fields {
+        Some(requested) => render::select_fields(requested),
+        None => {
+            let mut extra = Vec::new();
+            if cli.ticks {
+                extra.extend(["recent_high", "recent_low"]);
+            }
+            if cli.relative_to_market {
+                extra.push("relative_change_percent");
+            }
+            if cli.sort_by == Some(SortKey::Sharpe) {
+                extra.push("sharpe_ratio");
+            }
+            if cli.window_bounds {
+                extra.extend(["window_start", "window_end"]);
+            }
+            Ok(render::default_fields(&extra))
+        }
+    }
+}
+
+// CSV for a .csv path and JSON otherwise.
+fn file_renderer(cli: &Cli, path: &Path) -> Result<Box<dyn Renderer>, String> {
+    let fields = fields(cli)?;
+    Ok(match path.extension() {
+        Some(extension) if extension == "csv" => Box::new(CsvRenderer { fields }),
+        _ => Box::new(JsonRenderer {
+            fields,
+            pretty: cli.json_pretty,
+        }),
+    })
+}
+
+// --theme, with its colour only when stdout is a terminal to show it, so
+// pipes and files get plain characters.
+fn theme(cli: &Cli) -> Theme {
+    if io::stdout().is_terminal() {
+        cli.theme
+    } else {
+        cli.theme.plain()
+    }
+}
+
+// `currency` is what the run converted prices to, if anything, for the
+// renderers that round and label prices.
+fn renderer(
+    cli: &Cli,
+    format: OutputFormat,
+    currency: Option<&str>,
+) -> Result<Box<dyn Renderer>, String> {
+    let fields = fields(cli)?;
+    let currency = currency.map(String::from);
+    if let Some(template) = &cli.template {
+        return Ok(Box::new(TemplateRenderer {
+            template: template.clone(),
+            price_decimals: cli.price_decimals,
+            currency: currency.clone(),
+            theme: theme(cli),
+        }));
+    }
+    Ok(match format {
+        OutputFormat::Bar | OutputFormat::Table if cli.relative_to_market => {
+            Box::new(RelativeRenderer {
+                benchmark: cli.benchmark.clone(),
+            })
+        }
+        OutputFormat::Bar => Box::new(BarRenderer {
+            style: BarStyle {
+                price_decimals: cli.price_decimals,
+                currency,
+                shade_by_volume: cli.shade_by_volume,
+                show_rank: cli.show_rank,
+                links: cli.links && io::stdout().is_terminal(),
+                baselines: cli.baseline.clone(),
+                ticks: cli.ticks,
+                theme: theme(cli),
+                show_supply: cli.show_supply,
+            },
+        }),
+        OutputFormat::Table => Box::new(TableRenderer {
+            price_decimals: cli.price_decimals,
+            currency,
+            show_rank: cli.show_rank,
+            columns: match &cli.columns {
+                Some(requested) => render::select_columns(requested)?,
+                None => render::DEFAULT_COLUMNS.to_vec(),
+            },
+            theme: theme(cli),
+        }),
+        OutputFormat::Json => Box::new(JsonRenderer {
+            fields,
+            pretty: cli.json_pretty,
+        }),
+        OutputFormat::Csv => Box::new(CsvRenderer { fields }),
+        OutputFormat::Svg => Box::new(SvgRenderer { sample: cli.sample }),
+        OutputFormat::NdjsonStats => Box::new(NdjsonStatsRenderer),
+        OutputFormat::Prometheus | OutputFormat::PrometheusPushgateway => {
+            Box::new(PrometheusRenderer { openmetrics: false })
+        }
+        OutputFormat::Openmetrics => Box::new(PrometheusRenderer { openmetrics: true }),
+    })
+}
+
+fn healthcheck_exit_code<W: Write>(
+    result: &Result<Duration, reqwest::Error>,
+    out: &mut W,
+) -> io::Result<i32> {
+    match result {
+        Ok(latency) => {
+            writeln!(out, "ok: API responded in {}ms", latency.as_millis())?;
+            Ok(0)
+        }
+        Err(e) => {
+            writeln!(out, "unhealthy: {}", e)?;
+            Ok(1)
+        }
+    }
+}
+
+async fn list(cli: &Cli, opts: &RunOptions) -> Result<(), Box<dyn std::error::Error>> {
+    list_to(cli, opts, &mut BufWriter::new(io::stdout().lock())).await
+}
+
+// Renders the list to `out`, and also to the --export file when one is set.
+async fn list_to<W: Write>(
+    cli: &Cli,
+    opts: &RunOptions,
+    out: &mut W,
+) -> Result<(), Box<dyn std::error::Error>> {
+    #[cfg(not(feature = "png"))]
+    if cli.png.is_some() {
+        return Err("--png needs a build with the png feature".into());
+    }
+    #[cfg(not(feature = "icons"))]
+    if cli.icons {
+        return Err("--icons needs a build with the icons feature".into());
+    }
+    if cli.list_cache {
+        if let Some(cache) = &opts.cache {
+            print_cache_index(cache, out)?;
+        }
+        return Ok(out.flush()?);
+    }
+    if cli.raw {
+        let infos = run(&cli.base_url, opts, &mut |_| Ok(())).await?.infos;
+        let raw = match cli.format {
+            OutputFormat::Csv => render::render_raw_csv(&infos),
+            _ => render::render_raw_json(&infos),
+        };
+        out.write_all(raw.as_bytes())?;
+        return Ok(out.flush()?);
+    }
+    let grouped = cli.group_by_rank_tier;
+    if grouped && !matches!(cli.format, OutputFormat::Bar | OutputFormat::Table) {
+        return Err("--group-by-rank-tier only works with bar and table output".into());
+    }
+    // The prose would break json, csv and the metrics formats.
+    if cli.explain && !matches!(cli.format, OutputFormat::Bar | OutputFormat::Table) {
+        return Err("--explain only works with bar and table output".into());
+    }
+    let pushgateway = match (cli.format, &cli.pushgateway) {
+        (OutputFormat::PrometheusPushgateway, None) => {
+            return Err("--format prometheus-pushgateway needs --pushgateway".into());
+        }
+        (OutputFormat::PrometheusPushgateway, Some(url)) => Some(url),
+        _ => None,
+    };
+    // Whether --convert's rate could be had is only known once the run is
+    // done, so converted prices aren't streamed.
+    let streaming = renderer(cli, cli.format, None)?;
+    let streams = streaming.streams()
+        && cli.convert.is_none()
+        && !grouped
+        && cli.sort_by.is_none()
+        && !cli.relative_to_market
+        && !cli.icons
+        && !cli.group_profit
+        && cli.diff.is_none();
+    let exporter = cli
+        .export_path
+        .as_deref()
+        .map(|path| file_renderer(cli, path))
+        .transpose()?;
+    let started = Instant::now();
+    let report = run(&cli.base_url, opts, &mut |info| {
+        if streams {
+            render::write_coin(out, streaming.as_ref(), info)
+        } else {
+            Ok(())
+        }
     })
+    .await?;
+    let elapsed = started.elapsed();
+    let mut infos = report.infos;
+    let currency = report.currency.as_deref();
+    let renderer = renderer(cli, cli.format, currency)?;
+    if cli.relative_to_market {
+        relative_to_benchmark(&mut infos, &cli.benchmark)?;
+    }
+    if let Some(sort_by) = cli.sort_by {
+        sort_infos(&mut infos, sort_by, cli.desc);
+    }
+    if let (OutputFormat::Svg, Some(dir)) = (cli.format, &cli.output) {
+        fs::create_dir_all(dir)?;
+        for info in &infos {
+            let path = dir.join(format!("{}.svg", info.name));
+            fs::write(&path, renderer.render(std::slice::from_ref(info)))?;
+            writeln!(out, "{}", path.display())?;
+        }
+    } else if grouped {
+        for (label, range) in render::rank_tiers(&mut infos, &cli.tiers) {
+            writeln!(out, "{}", label)?;
+            write!(out, "{}", renderer.render(&infos[range]))?;
+        }
+    } else if let Some(gateway) = pushgateway {
+        push_metrics(&opts.client, gateway, &cli.job, renderer.render(&infos)).await?;
+    } else if cli.group_profit {
+        let portfolio = coin_data::portfolio(&infos, &cli.holdings);
+        for coin in &portfolio.unknown {
+            eprintln!(
+                "Warning: no price for {}, leaving it out of the total",
+                coin
+            );
+        }
+        write!(out, "{}", render::render_portfolio(&portfolio, currency))?;
+    } else if let Some(path) = &cli.diff {
+        let previous: Vec<coin_data::CoinInfo> =
+            serde_json::from_str(&fs::read_to_string(path)?)
+                .map_err(|e| format!("{} isn't a saved run ({})", path.display(), e))?;
+        write!(
+            out,
+            "{}",
+            render::render_diff(&diff_runs(&previous, &infos))
+        )?;
+    } else if !streams {
+        let rendered = renderer.render(&infos);
+        write!(out, "{}", with_icons(cli, opts, &infos, rendered).await)?;
+    }
+    #[cfg(feature = "png")]
+    if let Some(dir) = &cli.png {
+        fs::create_dir_all(dir)?;
+        for info in &infos {
+            let path = dir.join(format!("{}.png", info.name));
+            coin_data::png::write_chart(info, &path, cli.sample)?;
+            writeln!(out, "{}", path.display())?;
+        }
+    }
+    if matches!(cli.format, OutputFormat::Bar | OutputFormat::Table) && !cli.group_profit {
+        writeln!(out, "{}", render_summary(&summarize(&infos)))?;
+    }
+    if cli.explain {
+        for info in &infos {
+            let line = render::explain(info, opts.window, currency);
+            writeln!(out, "{}", line)?;
+        }
+    }
+    out.flush()?;
+    if let (Some(path), Some(exporter)) = (&cli.export_path, exporter) {
+        cache::write_atomic(path, exporter.render(&infos))?;
+    }
+    if let Some(path) = &cli.summary_path {
+        let failed = report.failed.len() + report.skipped_offline.len();
+        let summary = run_summary(&infos, failed, elapsed);
+        cache::write_atomic(path, serde_json::to_string_pretty(&summary)?)?;
+    }
+    if let (Some(_), Some(rate)) = (&opts.cache, cache_hit_rate(&infos)) {
+        eprintln!("Cache hit rate: {:.0}%", rate * 100.0);
+    }
+    Ok(())
+}
+
+// Puts each coin's logo in front of its bar, when --icons is set and the
+// terminal can draw images. Other formats aren't one line per coin, and
+// --offline, --fixtures and --demo runs never touch the network, logos
+// included.
+#[cfg(feature = "icons")]
+async fn with_icons(
+    cli: &Cli,
+    opts: &RunOptions,
+    infos: &[coin_data::CoinInfo],
+    rendered: String,
+) -> String {
+    use coin_data::icons::{icon_prefix, IconSource, ImageProtocol, ICON_URL};
+    use futures::StreamExt;
+    let protocol = ImageProtocol::detect();
+    let bars = matches!(cli.format, OutputFormat::Bar)
+        && !cli.relative_to_market
+        && cli.template.is_none();
+    let local = opts.offline || opts.fixtures.is_some();
+    if !cli.icons
+        || !bars
+        || local
+        || protocol == ImageProtocol::None
+        || !io::stdout().is_terminal()
+    {
+        return rendered;
+    }
+    let source = IconSource {
+        base_url: ICON_URL.to_string(),
+        cache_dir: cli.cache_dir.clone(),
+    };
+    // As many at once as histories are fetched.
+    let prefixes: Vec<String> = futures::stream::iter(infos)
+        .map(|info| icon_prefix(protocol, &source, &opts.client, info.symbol.as_deref()))
+        .buffered(opts.concurrency.max(1))
+        .collect()
+        .await;
+    rendered
+        .lines()
+        .zip(prefixes.into_iter().chain(std::iter::repeat(String::new())))
+        .map(|(line, prefix)| format!("{}{}\n", prefix, line))
+        .collect()
 }
 
-fn draw_bar_graph(upper: f64, lower: f64, current: f64, symbol: String) {
-    let range = upper - lower;
-    if range == 0.0 {
-        println!("Upper and lower value are the same.");
-        return;
-    }
-    let percentage = (current - lower) * 100.0 / range;
-    let formatted_percentage = format!("{:.2}", percentage);
-    let formatted_percentage = formatted_percentage.parse::<f64>().unwrap();
-    if !(0.0..=100.0).contains(&formatted_percentage) {
-        println!("Current value is not within the specified range.");
-        return;
-    }
-    let bar = (formatted_percentage as i32) / 2;
-    let formatted_percentage = format!("{:>10}", formatted_percentage);
-    let padding = 50 - bar;
-    println!(
-        "{}|{}{}|{}",
-        format_args!("{}%", formatted_percentage),
-        "█".repeat(bar as usize),
-        "░".repeat(padding as usize),
-        symbol
+#[cfg(not(feature = "icons"))]
+async fn with_icons(
+    _cli: &Cli,
+    _opts: &RunOptions,
+    _infos: &[coin_data::CoinInfo],
+    rendered: String,
+) -> String {
+    rendered
+}
+
+async fn show(
+    cli: &Cli,
+    coin: &str,
+    recent: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    show_to(cli, coin, recent, &mut io::stdout().lock()).await
+}
+
+async fn show_to<W: Write>(
+    cli: &Cli,
+    coin: &str,
+    recent: Option<&str>,
+    out: &mut W,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // The detail view is its own layout, so there is no other format to
+    // honour.
+    if cli.format != OutputFormat::Bar {
+        return Err("show only prints its detail view; drop --format".into());
+    }
+    let opts = run_options(cli, vec![coin.to_string()])?;
+    let now: Option<Box<dyn Clock>> = match cli.as_of {
+        _ if !cli.relative_dates => None,
+        Some(as_of) => Some(Box::new(FixedClock(as_of))),
+        None => Some(Box::new(SystemClock)),
+    };
+    let clock = now.as_deref();
+    let Some(interval) = recent else {
+        let report = run(&cli.base_url, &opts, &mut |_| Ok(())).await?;
+        let currency = report.currency.as_deref();
+        for info in &report.infos {
+            let detail = render::render_detail(info, cli.price_decimals, currency, clock);
+            write!(out, "{}", detail)?;
+        }
+        return Ok(());
+    };
+    let recent_opts = RunOptions {
+        intervals: [(coin.to_string(), interval.to_string())].into(),
+        window: Window {
+            start_ms: opts
+                .window
+                .end_ms
+                .saturating_sub(RECENT_SPAN.as_millis() as u64),
+            end_ms: opts.window.end_ms,
+        },
+        ..run_options(cli, vec![coin.to_string()])?
+    };
+    let (mut skip_long, mut skip_recent) = (|_: &_| Ok(()), |_: &_| Ok(()));
+    let (long, recent) = tokio::join!(
+        run(&cli.base_url, &opts, &mut skip_long),
+        run(&cli.base_url, &recent_opts, &mut skip_recent)
     );
+    // Either view is worth showing without the other.
+    // Each view keeps the currency its own run applied.
+    let first = |result: Result<RunReport, Box<dyn std::error::Error>>| {
+        result.and_then(|report| {
+            let currency = report.currency;
+            report
+                .infos
+                .into_iter()
+                .next()
+                .map(|info| (info, currency))
+                .ok_or_else(|| format!("no history for {}", coin).into())
+        })
+    };
+    let decimals = cli.price_decimals;
+    let detail = match (first(long), first(recent)) {
+        (Ok((long, currency)), Ok((recent, _))) => render::render_overlay(
+            &long,
+            interval,
+            &recent,
+            decimals,
+            currency.as_deref(),
+            clock,
+        ),
+        (Ok((long, currency)), Err(e)) => {
+            eprintln!(
+                "Warning: no {} history ({}), showing the long range only",
+                interval, e
+            );
+            render::render_detail(&long, decimals, currency.as_deref(), clock)
+        }
+        (Err(e), Ok((recent, currency))) => {
+            eprintln!(
+                "Warning: no long range ({}), showing the {} history only",
+                e, interval
+            );
+            render::render_detail(&recent, decimals, currency.as_deref(), clock)
+        }
+        (Err(e), Err(_)) => return Err(e),
+    };
+    write!(out, "{}", detail)?;
+    Ok(())
+}
+
+// Fetches each coin's history on its own, so a symbol can't end up paired
+// with the other coin's data.
+async fn correlate(cli: &Cli, a: &str, b: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut histories = Vec::new();
+    for coin in [a, b] {
+        let opts = RunOptions {
+            keep_raw: true,
+            ..run_options(cli, vec![coin.to_string()])?
+        };
+        let info = run(&cli.base_url, &opts, &mut |_| Ok(()))
+            .await?
+            .infos
+            .into_iter()
+            .next()
+            .ok_or_else(|| format!("no history for {}", coin))?;
+        let raw = info.raw.ok_or_else(|| format!("no history for {}", coin))?;
+        histories.push((info.name, raw));
+    }
+    let (a, b) = (&histories[0], &histories[1]);
+    match correlation(&a.1, &b.1) {
+        Some(r) => writeln!(io::stdout(), "{} / {}: correlation {:.3}", a.0, b.0, r)?,
+        None => return Err(format!("{} and {} don't share enough points", a.0, b.0).into()),
+    }
+    Ok(())
+}
+
+// Clears the screen between refreshes when writing to a terminal, so the
+// latest list replaces the previous one.
+async fn watch(
+    cli: &Cli,
+    every: u64,
+    count: Option<u64>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut out = BufWriter::new(io::stdout().lock());
+    watch_to(cli, every, count, &SystemClock, &mut out).await
+}
+
+// Each refresh's window ends at that refresh's now, so a --window span
+// moves along with the clock.
+async fn watch_to<W: Write>(
+    cli: &Cli,
+    every: u64,
+    count: Option<u64>,
+    clock: &dyn Clock,
+    out: &mut W,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut opts = run_options(cli, listed_coins(cli))?;
+    let mut refreshes = 0;
+    loop {
+        if io::stdout().is_terminal() {
+            write!(out, "\x1b[2J\x1b[H")?;
+        }
+        opts.window = window(cli, clock);
+        list_to(cli, &opts, out).await?;
+        refreshes += 1;
+        if count.is_some_and(|count| refreshes >= count) {
+            return Ok(());
+        }
+        tokio::time::sleep(Duration::from_secs(every)).await;
+    }
+}
+
+async fn export(cli: &Cli, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let renderer = file_renderer(cli, path)?;
+    let opts = run_options(cli, cli.coins.clone())?;
+    let infos = run(&cli.base_url, &opts, &mut |_| Ok(())).await?.infos;
+    cache::write_atomic(path, renderer.render(&infos))?;
+    Ok(())
 }
 
+// Older Windows consoles print escape sequences literally unless virtual
+// terminal processing is switched on; everywhere else they just work.
+#[cfg(windows)]
+fn enable_ansi() -> Result<(), String> {
+    enable_ansi_support::enable_ansi_support()
+        .map_err(|code| format!("couldn't enable ANSI escapes (error {})", code))
+}
+
+#[cfg(not(windows))]
+fn enable_ansi() -> Result<(), String> {
+    Ok(())
+}
+
+// Runs the subcommand, returning the process exit code.
+async fn dispatch(cli: &Cli) -> Result<i32, Box<dyn std::error::Error>> {
+    if let Some(pair) = &cli.correlate {
+        correlate(cli, &pair[0], &pair[1]).await?;
+        return Ok(0);
+    }
+    match &cli.command {
+        None | Some(Command::List) => list(cli, &run_options(cli, listed_coins(cli))?).await?,
+        Some(Command::Show { coin, recent }) => show(cli, coin, recent.as_deref()).await?,
+        Some(Command::Watch { every, count }) => watch(cli, *every, *count).await?,
+        Some(Command::Export { path }) => export(cli, path).await?,
+        Some(Command::Healthcheck) => {
+            let timeout = Duration::from_secs(cli.timeout.unwrap_or(HEALTHCHECK_TIMEOUT_SECS));
+            let client = build_client(
+                cli.api_key.as_deref(),
+                Some(timeout),
+                &connection_options(cli),
+            )?;
+            let result = healthcheck(&client, &cli.base_url).await;
+            return Ok(healthcheck_exit_code(&result, &mut io::stdout().lock())?);
+        }
+    }
+    Ok(0)
+}
+
+// True when `e`, or an error it wraps, is a write to a closed pipe, as when
+// the output is piped into `head`.
+fn is_broken_pipe(e: &(dyn std::error::Error + 'static)) -> bool {
+    std::iter::successors(Some(e), |e| e.source()).any(|e| {
+        e.downcast_ref::<io::Error>()
+            .is_some_and(|e| e.kind() == io::ErrorKind::BrokenPipe)
+    })
+}
+
+// The conventional 128 + SIGINT.
+const INTERRUPTED_EXIT_CODE: i32 = 130;
+
 #[tokio::main]
 async fn main() {
-    let coin_list = get_coins(API_URL).await;
-    match coin_list {
-        Ok(coin_list) => {
-            for coin in coin_list.data {
-                let coin_data = get_coin_data(API_URL, &coin.id, "d1").await;
-                match coin_data {
-                    Ok(data) => {
-                        let coin_info = get_coin_info(data, &coin.id).await;
-                        match coin_info {
-                            Ok(data) => {
-                                let (upper, lower, current, name) = (
-                                    data.all_time_high,
-                                    data.all_time_low,
-                                    data.current_price,
-                                    data.name,
-                                );
-                                draw_bar_graph(upper, lower, current, name);
-                            }
-                            Err(e) => println!("Error: {}", e),
-                        }
-                    }
-                    Err(e) => println!("Error: {}", e),
-                }
-            }
+    let cli = Cli::parse();
+    if let Some(filter) = &cli.log {
+        tracing_subscriber::fmt()
+            .with_env_filter(EnvFilter::new(filter))
+            .with_span_events(FmtSpan::CLOSE)
+            .with_writer(io::stderr)
+            .init();
+    }
+    let colored = (cli.theme.color)(0.0).is_some();
+    let uses_escapes = cli.links || colored || matches!(cli.command, Some(Command::Watch { .. }));
+    if uses_escapes && io::stdout().is_terminal() {
+        if let Err(e) = enable_ansi() {
+            eprintln!("Warning: {}", e);
+        }
+    }
+    // Racing Ctrl-C against the command drops it on interrupt, so buffered
+    // output is flushed on the way out. Cache and export files are written
+    // atomically and are either complete or untouched.
+    let result = tokio::select! {
+        result = dispatch(&cli) => result,
+        _ = tokio::signal::ctrl_c() => {
+            eprintln!("Interrupted");
+            Ok(INTERRUPTED_EXIT_CODE)
+        }
+    };
+    match result {
+        Ok(0) => {}
+        Ok(code) => std::process::exit(code),
+        // Whoever reads the output has all they wanted.
+        Err(e) if is_broken_pipe(&*e) => {}
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
         }
-        Err(e) => println!("Error: {}", e),
     }
 }
 
@@ -146,119 +1061,589 @@ async fn main() {
 mod tests {
     use super::*;
     use httpmock::Method::GET;
-    use httpmock::MockServer;
+    use httpmock::{Mock, MockServer};
 
-    #[tokio::test]
-    async fn test_get_coins() {
-        let mock_server = MockServer::start();
-        let mock = mock_server.mock(|when, then| {
+    // Serves bitcoin and ethereum with a two point history each.
+    fn mock_api(server: &MockServer) -> [Mock<'_>; 3] {
+        let assets = server.mock(|when, then| {
             when.method(GET).path("/v2/assets");
             then.status(200)
                 .header("content-type", "application/json")
                 .body(
-                    r#"{
-                "data": [
-                    {
-                        "id": "bitcoin",
-                        "rank": "1",
-                        "symbol": "BTC",
-                        "name": "Bitcoin"
-                    },
-                    {
-                        "id": "ethereum",
-                        "rank": "2",
-                        "symbol": "ETH",
-                        "name": "Ethereum"
-                    }
-                ]
-            }"#,
+                    r#"{"data": [
+                        {"id": "bitcoin", "rank": "1", "symbol": "BTC", "name": "Bitcoin"},
+                        {"id": "ethereum", "rank": "2", "symbol": "ETH", "name": "Ethereum"}
+                    ]}"#,
                 );
         });
-        let coin_list = get_coins(&mock_server.url("/v2")).await;
-        match &coin_list {
-            Ok(_) => {}
-            Err(e) => println!("Error: {}", e),
-        }
-        assert!(coin_list.is_ok());
-        let coin_list = coin_list.unwrap();
-        assert_eq!(coin_list.data.len(), 2);
-        assert_eq!(coin_list.data[0].id, "bitcoin");
-        assert_eq!(coin_list.data[0].rank, "1");
-        assert_eq!(coin_list.data[0].symbol, "BTC");
-        assert_eq!(coin_list.data[0].name, "Bitcoin");
-        assert_eq!(coin_list.data[1].id, "ethereum");
-        assert_eq!(coin_list.data[1].rank, "2");
-        assert_eq!(coin_list.data[1].symbol, "ETH");
-        assert_eq!(coin_list.data[1].name, "Ethereum");
-        mock.assert();
+        let history = |id: &str| {
+            server.mock(|when, then| {
+                when.method(GET).path(format!("/v2/assets/{}/history", id));
+                then.status(200)
+                    .header("content-type", "application/json")
+                    .body(r#"{"data": [{"priceUsd": "10.0", "time": 1}, {"priceUsd": "20.0", "time": 2}]}"#);
+            })
+        };
+        [assets, history("bitcoin"), history("ethereum")]
+    }
+
+    fn parse(server: &MockServer, args: &[&str]) -> Cli {
+        let base_url = server.url("/v2");
+        let mut argv = vec!["coin-data", "--base-url", &base_url];
+        argv.extend_from_slice(args);
+        Cli::try_parse_from(argv).unwrap()
+    }
+
+    #[test]
+    fn test_subcommands_parse() {
+        let parse = |args: &[&str]| Cli::try_parse_from(args).unwrap().command;
+        assert_eq!(parse(&["coin-data"]), None);
+        assert_eq!(parse(&["coin-data", "list"]), Some(Command::List));
+        assert_eq!(
+            parse(&["coin-data", "show", "bitcoin", "--format", "table"]),
+            Some(Command::Show {
+                coin: "bitcoin".to_string(),
+                recent: None
+            })
+        );
+        assert_eq!(
+            parse(&["coin-data", "watch", "--every", "5"]),
+            Some(Command::Watch {
+                every: 5,
+                count: None
+            })
+        );
+        assert_eq!(
+            parse(&["coin-data", "export", "out.csv"]),
+            Some(Command::Export {
+                path: PathBuf::from("out.csv")
+            })
+        );
+        assert_eq!(
+            parse(&["coin-data", "healthcheck", "--timeout", "2"]),
+            Some(Command::Healthcheck)
+        );
+        let cli = Cli::try_parse_from(["coin-data", "show", "eth", "--api-key", "k"]).unwrap();
+        assert_eq!(cli.api_key.as_deref(), Some("k"));
+        assert_eq!(cli.base_url, API_URL);
+
+        let cli = Cli::try_parse_from([
+            "coin-data",
+            "--baseline",
+            "btc=30000",
+            "--baseline",
+            "eth=1500.5",
+        ])
+        .unwrap();
+        assert_eq!(
+            cli.baseline,
+            vec![("btc".to_string(), 30000.0), ("eth".to_string(), 1500.5)]
+        );
+        assert!(parse_baseline("btc").is_err());
+        assert!(parse_baseline("btc=-1").is_err());
+
+        let cli = Cli::try_parse_from(["coin-data", "--as-of", "2023-01-01T00:00:00Z"]).unwrap();
+        let opts = run_options(&cli, Vec::new()).unwrap();
+        assert_eq!(opts.window.end_ms, 1672531200000);
+        assert!(parse_as_of("yesterday").is_err());
+        assert!(parse_log_filter("coin_data=debug").is_ok());
+        assert!(parse_log_filter("coin_data=loud").is_err());
+        assert!(parse_coins_regex("^USD").is_ok());
+        assert!(parse_coins_regex("(USD")
+            .unwrap_err()
+            .starts_with("'(USD' isn't a valid pattern"));
+        // Plain histories have one price per point, so there is nothing to pick.
+        assert!(Cli::try_parse_from(["coin-data", "--price-source", "high"]).is_err());
+        let cli = Cli::try_parse_from([
+            "coin-data",
+            "--candles",
+            "binance",
+            "--price-source",
+            "high",
+        ])
+        .unwrap();
+        let opts = run_options(&cli, Vec::new()).unwrap();
+        assert_eq!(opts.candles.unwrap().price, PriceSource::High);
+    }
+
+    #[test]
+    fn test_default_fields_follow_flags() {
+        let default_fields = |args: &[&str]| {
+            let argv = ["coin-data"].iter().chain(args);
+            fields(&Cli::try_parse_from(argv).unwrap()).unwrap()
+        };
+        assert!(!default_fields(&[]).contains(&"recent_high".to_string()));
+        assert!(default_fields(&["--ticks"]).contains(&"recent_high".to_string()));
+        assert!(!default_fields(&[]).contains(&"relative_change_percent".to_string()));
+        assert!(default_fields(&["--relative-to-market"])
+            .contains(&"relative_change_percent".to_string()));
+        assert!(!default_fields(&[]).contains(&"sharpe_ratio".to_string()));
+        assert!(default_fields(&["--sort-by", "sharpe"]).contains(&"sharpe_ratio".to_string()));
+        assert!(!default_fields(&[]).contains(&"window_start".to_string()));
+        assert!(default_fields(&["--window-bounds"])
+            .ends_with(&["window_start".to_string(), "window_end".to_string()]));
+        assert_eq!(
+            default_fields(&["--fields", "name,recent_low"]),
+            ["name", "recent_low"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_list_and_show_dispatch() {
+        let server = MockServer::start();
+        let [assets, bitcoin, ethereum] = mock_api(&server);
+        assert_eq!(dispatch(&parse(&server, &["list"])).await.unwrap(), 0);
+        assert_eq!(
+            dispatch(&parse(&server, &["show", "ETH"])).await.unwrap(),
+            0
+        );
+        assets.assert_hits(2);
+        bitcoin.assert_hits(1);
+        ethereum.assert_hits(2);
+    }
+
+    #[tokio::test]
+    async fn test_watch_dispatch_refreshes() {
+        let server = MockServer::start();
+        let [assets, _, _] = mock_api(&server);
+        let cli = parse(&server, &["watch", "--every", "0", "--count", "2"]);
+        assert_eq!(dispatch(&cli).await.unwrap(), 0);
+        assets.assert_hits(2);
+    }
+
+    #[tokio::test]
+    async fn test_export_dispatch_writes_file() {
+        let server = MockServer::start();
+        mock_api(&server);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("coins.csv");
+        let cli = parse(
+            &server,
+            &["export", path.to_str().unwrap(), "--fields", "name"],
+        );
+        assert_eq!(dispatch(&cli).await.unwrap(), 0);
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "name\nbitcoin\nethereum\n"
+        );
     }
 
     #[tokio::test]
-    async fn test_get_coin_data() {
+    async fn test_healthcheck_exit_code() {
         let mock_server = MockServer::start();
-        let mock = mock_server.mock(|when, then| {
-            when.method(GET).path_contains("/v2/assets/bitcoin/history");
+        let fast = mock_server.mock(|when, then| {
+            when.method(GET)
+                .path("/fast/assets")
+                .query_param("limit", "1");
             then.status(200)
                 .header("content-type", "application/json")
-                .body(
-                    r#"{
-                "data": [
-                    {
-                        "time": 1356998400000,
-                        "priceUsd": "13.8"
-                    },
-                    {
-                        "time": 1357084800000,
-                        "priceUsd": "13.98"
-                    }
-                ]
-            }"#,
-                );
+                .body(r#"{"data": []}"#);
+        });
+        let slow = mock_server.mock(|when, then| {
+            when.method(GET).path("/slow/assets");
+            then.status(200)
+                .header("content-type", "application/json")
+                .delay(Duration::from_millis(500))
+                .body(r#"{"data": []}"#);
+        });
+        let broken = mock_server.mock(|when, then| {
+            when.method(GET).path("/broken/assets");
+            then.status(200).body("<html>maintenance</html>");
+        });
+        let timeout = Duration::from_millis(200);
+        let client = build_client(None, Some(timeout), &ConnectionOptions::default()).unwrap();
+        let exit_code = |result| healthcheck_exit_code(result, &mut Vec::new()).unwrap();
+        let result = healthcheck(&client, &mock_server.url("/fast")).await;
+        assert!(result.as_ref().is_ok_and(|latency| *latency < timeout));
+        assert_eq!(exit_code(&result), 0);
+        let result = healthcheck(&client, &mock_server.url("/slow")).await;
+        assert!(result.as_ref().is_err_and(|e| e.is_timeout()));
+        assert_eq!(exit_code(&result), 1);
+        let result = healthcheck(&client, &mock_server.url("/broken")).await;
+        assert_eq!(exit_code(&result), 1);
+        let mut out = Vec::new();
+        healthcheck_exit_code(&result, &mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().starts_with("unhealthy: "));
+        let cli = Cli::try_parse_from([
+            "coin-data",
+            "healthcheck",
+            "--base-url",
+            &mock_server.url("/broken"),
+        ])
+        .unwrap();
+        assert_eq!(dispatch(&cli).await.unwrap(), 1);
+        fast.assert();
+        slow.assert();
+        broken.assert_hits(2);
+    }
+
+    #[test]
+    fn test_enable_ansi() {
+        let result = enable_ansi();
+        // Under the test harness stdout may not be a console on Windows
+        #[cfg(not(windows))]
+        assert!(result.is_ok());
+        #[cfg(windows)]
+        let _ = result;
+    }
+
+    #[tokio::test]
+    async fn test_list_writes_stdout_and_export_file() {
+        let server = MockServer::start();
+        mock_api(&server);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("report.json");
+        let cli = parse(
+            &server,
+            &["--format", "text", "--export", path.to_str().unwrap()],
+        );
+        let opts = run_options(&cli, listed_coins(&cli)).unwrap();
+        let mut out = Vec::new();
+        list_to(&cli, &opts, &mut out).await.unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("bitcoin"), "{}", out);
+        assert!(out.contains("ethereum"), "{}", out);
+        let exported: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        let names: Vec<&str> = exported
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|coin| coin["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, ["bitcoin", "ethereum"]);
+    }
+
+    #[tokio::test]
+    async fn test_summary_file_describes_run() {
+        let server = MockServer::start();
+        mock_api(&server);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("summary.json");
+        let cli = parse(
+            &server,
+            &[
+                "--coins",
+                "bitcoin,ethereum,dogecoin",
+                "--summary",
+                path.to_str().unwrap(),
+            ],
+        );
+        let opts = run_options(&cli, listed_coins(&cli)).unwrap();
+        list_to(&cli, &opts, &mut Vec::new()).await.unwrap();
+        let summary: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(summary["total_coins"], 3);
+        assert_eq!(summary["succeeded"], 2);
+        assert_eq!(summary["failed"], 1);
+        assert_eq!(summary["cache_hit_rate"], 0.0);
+        assert!(summary["elapsed_ms"].is_u64());
+        assert_eq!(
+            summary["top_movers"],
+            serde_json::json!([
+                {"name": "bitcoin", "change_percent": 100.0},
+                {"name": "ethereum", "change_percent": 100.0}
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_demo_runs_without_network() {
+        let server = MockServer::start();
+        let any = server.mock(|_, then| {
+            then.status(500);
         });
-        println!(
+        let cli = parse(&server, &["--demo", "--format", "csv", "--fields", "name"]);
+        let opts = run_options(&cli, listed_coins(&cli)).unwrap();
+        let mut out = Vec::new();
+        list_to(&cli, &opts, &mut out).await.unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "name\nbitcoin\nethereum\ntether\ndogecoin\n"
+        );
+        any.assert_hits(0);
+    }
+
+    #[tokio::test]
+    async fn test_closed_output_is_a_broken_pipe() {
+        struct ClosedPipe;
+        impl Write for ClosedPipe {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::ErrorKind::BrokenPipe.into())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let server = MockServer::start();
+        mock_api(&server);
+        for format in ["bar", "json"] {
+            let cli = parse(&server, &["--format", format]);
+            let opts = run_options(&cli, listed_coins(&cli)).unwrap();
+            let err = list_to(&cli, &opts, &mut ClosedPipe).await.unwrap_err();
+            assert!(is_broken_pipe(&*err), "{}", err);
+        }
+        let healthy = Ok(Duration::from_millis(5));
+        let err = healthcheck_exit_code(&healthy, &mut ClosedPipe).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        let other: Box<dyn std::error::Error> = io::Error::from(io::ErrorKind::NotFound).into();
+        assert!(!is_broken_pipe(&*other));
+    }
+
+    #[tokio::test]
+    async fn test_show_recent_fetches_both_intervals() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/v2/assets");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{"data": [{"id": "bitcoin", "rank": "1", "symbol": "BTC", "name": "Bitcoin"}]}"#);
+        });
+        let history = |interval: &str, status: u16| {
+            server.mock(|when, then| {
+                when.method(GET)
+                    .path("/v2/assets/bitcoin/history")
+                    .query_param("interval", interval);
+                then.status(status)
+                    .header("content-type", "application/json")
+                    .body(r#"{"data": [{"priceUsd": "10.0", "time": 1}, {"priceUsd": "20.0", "time": 2}]}"#);
+            })
+        };
+        let daily = history("d1", 200);
+        let mut hourly = history("h1", 200);
+        let cli = parse(&server, &["show", "bitcoin", "--recent", "h1"]);
+        assert_eq!(dispatch(&cli).await.unwrap(), 0);
+        daily.assert_hits(1);
+        hourly.assert_hits(1);
+
+        // A failed recent fetch still shows the long range.
+        hourly.delete();
+        let missing = history("h1", 404);
+        assert_eq!(dispatch(&cli).await.unwrap(), 0);
+        daily.assert_hits(2);
+        missing.assert_hits(1);
+        assert!(Cli::try_parse_from(["coin-data", "show", "btc", "--recent", "h3"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_group_profit_totals_holdings() {
+        let server = MockServer::start();
+        mock_api(&server);
+        let cli = parse(
+            &server,
+            &["--group-profit", "--holdings", "btc=0.5,eth=3,doge=10"],
+        );
+        let opts = run_options(&cli, listed_coins(&cli)).unwrap();
+        let mut out = Vec::new();
+        list_to(&cli, &opts, &mut out).await.unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "bitcoin   0.5  20.00  10.00\nethereum    3  20.00  60.00\ntotal                 70.00\n"
+        );
+        assert!(parse_holding("btc=0").is_err());
+        assert!(Cli::try_parse_from(["coin-data", "--group-profit"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_list_cache_writes_to_the_output() {
+        let server = MockServer::start();
+        let dir = tempfile::tempdir().unwrap();
+        let history = coin_data::CoinData { data: Vec::new() };
+        Cache::new(dir.path())
+            .write("bitcoin.d1", &history)
+            .unwrap();
+        let cli = parse(
+            &server,
+            &["--cache-dir", dir.path().to_str().unwrap(), "--list-cache"],
+        );
+        let opts = run_options(&cli, listed_coins(&cli)).unwrap();
+        let mut out = Vec::new();
+        list_to(&cli, &opts, &mut out).await.unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("bitcoin.d1 "), "{}", out);
+        assert!(out.trim_end().ends_with(" UTC"), "{}", out);
+    }
+
+    #[tokio::test]
+    async fn test_explain_is_refused_for_json() {
+        let server = MockServer::start();
+        let [assets, ..] = mock_api(&server);
+        let cli = parse(&server, &["--format", "json", "--explain"]);
+        let opts = run_options(&cli, listed_coins(&cli)).unwrap();
+        let mut out = Vec::new();
+        let err = list_to(&cli, &opts, &mut out).await.unwrap_err();
+        assert!(err.to_string().contains("--explain"), "{}", err);
+        assert!(out.is_empty());
+        assert_eq!(assets.hits(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_prices_without_a_rate_are_labelled_usd() {
+        let server = MockServer::start();
+        mock_api(&server);
+        let rates = |body: &'static str| {
+            server.mock(move |when, then| {
+                when.method(GET).path("/v2/rates");
+                then.status(200)
+                    .header("content-type", "application/json")
+                    .body(body);
+            })
+        };
+        let list = || {
+            let cli = parse(
+                &server,
+                &[
+                    "--coins",
+                    "bitcoin",
+                    "--convert",
+                    "jpy",
+                    "--format",
+                    "table",
+                    "--explain",
+                ],
+            );
+            let opts = run_options(&cli, listed_coins(&cli)).unwrap();
+            async move {
+                let mut out = Vec::new();
+                list_to(&cli, &opts, &mut out).await.unwrap();
+                String::from_utf8(out).unwrap()
+            }
+        };
+        let mut converted =
+            rates(r#"{"data": [{"id": "japanese-yen", "symbol": "JPY", "rateUsd": "0.01"}]}"#);
+        let out = list().await;
+        assert!(
+            out.contains("2000 JPY") && !out.contains("2000.00"),
             "{}",
-            mock_server.url(format!(
-                "/v2/assets/bitcoin/history?interval=d1&{}",
-                START_AND_END
-            ))
+            out
         );
-        let coin_data = get_coin_data(&mock_server.url("/v2"), "bitcoin", "d1").await;
-        assert!(coin_data.is_ok());
-        let coin_data = coin_data.unwrap();
-        assert_eq!(coin_data.data.len(), 2);
-        assert_eq!(coin_data.data[0].time, 1356998400000);
-        assert_eq!(coin_data.data[0].priceUsd, "13.8");
-        assert_eq!(coin_data.data[1].time, 1357084800000);
-        assert_eq!(coin_data.data[1].priceUsd, "13.98");
-        mock.assert();
+        converted.delete();
+
+        // The rates lack JPY, so the prices stay in USD and say so.
+        rates(r#"{"data": []}"#);
+        let out = list().await;
+        assert!(!out.contains("JPY"), "{}", out);
+        assert!(out.contains("20.00"), "{}", out);
     }
 
     #[tokio::test]
-    // write a test for the function get_coin_info which doesn't use a mock server as the function doesn't need to make any HTTP requests
-    async fn test_get_coin_info() {
-        // mock a CoinData struct
-        let coin_data = CoinData {
-            data: vec![
-                PriceData {
-                    time: 1356998400000,
-                    priceUsd: "13.8".to_string(),
-                },
-                PriceData {
-                    time: 1357084800000,
-                    priceUsd: "13.98".to_string(),
-                },
-                PriceData {
-                    time: 1357084800000,
-                    priceUsd: "13.9".to_string(),
-                },
+    async fn test_show_formats_prices_like_the_list() {
+        let server = MockServer::start();
+        let [assets, ..] = mock_api(&server);
+        server.mock(|when, then| {
+            when.method(GET).path("/v2/rates");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{"data": [{"id": "japanese-yen", "symbol": "JPY", "rateUsd": "0.01"}]}"#);
+        });
+        let show = |args: &[&str]| {
+            let cli = parse(&server, args);
+            async move {
+                let mut out = Vec::new();
+                show_to(&cli, "bitcoin", None, &mut out)
+                    .await
+                    .map(|()| String::from_utf8(out).unwrap())
+                    .map_err(|e| e.to_string())
+            }
+        };
+        let out = show(&["--price-decimals", "3"]).await.unwrap();
+        assert!(out.contains("  price       20.000\n"), "{}", out);
+        let out = show(&["--convert", "jpy"]).await.unwrap();
+        assert!(out.contains("  price       2000\n"), "{}", out);
+
+        let hits = assets.hits();
+        let err = show(&["--format", "table"]).await.unwrap_err();
+        assert!(err.contains("--format"), "{}", err);
+        assets.assert_hits(hits);
+    }
+
+    // Steps a day forward each time it's read.
+    #[derive(Debug)]
+    struct Advancing(std::sync::atomic::AtomicU64);
+
+    impl Clock for Advancing {
+        fn now(&self) -> std::time::SystemTime {
+            let millis = self.0.fetch_add(DAY, std::sync::atomic::Ordering::SeqCst);
+            FixedClock::at_millis(millis).now()
+        }
+    }
+
+    const DAY: u64 = 86_400_000;
+
+    #[tokio::test]
+    async fn test_watch_moves_the_window_and_appends_the_tail() {
+        let server = MockServer::start();
+        let now = 1_700_000_000_000;
+        server.mock(|when, then| {
+            when.method(GET).path("/v2/assets");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{"data": [{"id": "bitcoin", "rank": "1", "symbol": "BTC", "name": "Bitcoin"}]}"#);
+        });
+        let full = server.mock(|when, then| {
+            when.method(GET)
+                .path("/v2/assets/bitcoin/history")
+                .query_param("start", (now - 7 * DAY).to_string())
+                .query_param("end", now.to_string());
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(format!(
+                    r#"{{"data": [{{"priceUsd": "10", "time": {}}}, {{"priceUsd": "20", "time": {}}}]}}"#,
+                    now - 7 * DAY,
+                    now - DAY
+                ));
+        });
+        let tail = server.mock(|when, then| {
+            when.method(GET)
+                .path("/v2/assets/bitcoin/history")
+                .query_param("start", (now - DAY).to_string())
+                .query_param("end", (now + DAY).to_string());
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(format!(
+                    r#"{{"data": [{{"priceUsd": "20", "time": {}}}, {{"priceUsd": "30", "time": {}}}]}}"#,
+                    now - DAY,
+                    now + DAY
+                ));
+        });
+        let dir = tempfile::tempdir().unwrap();
+        let cli = parse(
+            &server,
+            &[
+                "--window",
+                "7d",
+                "--cache-dir",
+                dir.path().to_str().unwrap(),
+                "--cache-ttl",
+                "0",
+                "--format",
+                "json",
+                "watch",
+                "--every",
+                "0",
+                "--count",
+                "2",
             ],
+        );
+        let Some(Command::Watch { every, count }) = cli.command else {
+            panic!("not a watch");
         };
-        let coin_info = get_coin_info(coin_data, "bitcoin").await;
-        assert!(coin_info.is_ok());
-        let coin_info = coin_info.unwrap();
-        assert_eq!(coin_info.name, "bitcoin");
-        assert_eq!(coin_info.all_time_high, 13.98);
-        assert_eq!(coin_info.all_time_low, 13.8);
-        assert_eq!(coin_info.current_price, 13.9);
-    }
-}
\ No newline at end of file
+        let clock = Advancing(now.into());
+        let mut out = Vec::new();
+        watch_to(&cli, every, count, &clock, &mut out)
+            .await
+            .unwrap();
+        full.assert();
+        tail.assert();
+        let out = String::from_utf8(out).unwrap();
+        let runs: Vec<serde_json::Value> = serde_json::Deserializer::from_str(&out)
+            .into_iter()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(runs.len(), 2);
+        assert_eq!(runs[0][0]["current_price"], 20.0);
+        assert_eq!(runs[1][0]["current_price"], 30.0);
+        // The point from before the moved window's start is dropped.
+        assert_eq!(runs[1][0]["all_time_low"], 20.0);
+    }
+}
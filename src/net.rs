@@ -0,0 +1,3963 @@
+use super::*;
+use cache::Validators;
+use fixtures::FileProvider;
+use futures::stream::{self, StreamExt};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::de::DeserializeOwned;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+const INTERVAL: &str = "d1";
+const TARGET_POINTS: u64 = 500;
+// CoinCap cuts history responses short somewhere past this many points, so
+// wider windows are fetched in pieces of at most this many.
+const MAX_HISTORY_POINTS: u64 = 10_000;
+// Pieces one history is fetched in at most; past this only the most recent
+// part of the window is fetched. A ten year window of minute points would
+// otherwise take over 500 requests per coin.
+const MAX_HISTORY_PIECES: u64 = 24;
+const MAX_ATTEMPTS: u32 = 3;
+const BASE_RETRY_DELAY_MS: u64 = 250;
+const MAX_RETRY_DELAY_MS: u64 = 4_000;
+// Past this, a request's retries give up whatever attempts are left.
+const MAX_TOTAL_RETRY_MS: u64 = 30_000;
+/// Default for HostLimit: what browsers allow, and polite to CoinCap.
+pub const DEFAULT_CONNECTIONS_PER_HOST: usize = 6;
+const EMPTY_RETRY_DELAY_MS: u64 = 500;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[allow(non_snake_case)]
+struct Cryptocurrency {
+    id: String,
+    rank: String,
+    symbol: String,
+    name: String,
+    volumeUsd24Hr: Option<String>,
+    supply: Option<String>,
+    /// Missing or null for coins with no supply cap.
+    maxSupply: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct CryptoList {
+    data: Vec<Cryptocurrency>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[allow(non_snake_case)]
+struct Rate {
+    id: String,
+    symbol: String,
+    /// Null or missing for some listed currencies.
+    rateUsd: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct RateList {
+    data: Vec<Rate>,
+}
+
+// CoinCap sends candle prices and volume as strings.
+fn f64_from_str<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    parse_price(&value)
+        .ok_or_else(|| serde::de::Error::custom(format!("invalid number '{}'", value)))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Candle {
+    #[serde(deserialize_with = "f64_from_str")]
+    open: f64,
+    #[serde(deserialize_with = "f64_from_str")]
+    high: f64,
+    #[serde(deserialize_with = "f64_from_str")]
+    low: f64,
+    #[serde(deserialize_with = "f64_from_str")]
+    close: f64,
+    #[serde(deserialize_with = "f64_from_str")]
+    volume: f64,
+    period: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct CandleList {
+    data: Vec<Candle>,
+}
+
+#[derive(Debug)]
+pub struct CandleSource {
+    pub exchange: String,
+    pub quote_id: String,
+    /// The candle price the stats are computed from.
+    pub price: PriceSource,
+}
+
+/// Which of a candle's prices stands for it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum PriceSource {
+    Open,
+    High,
+    Low,
+    #[default]
+    Close,
+}
+
+#[derive(Debug, Default)]
+pub struct RunOptions {
+    pub coins: Vec<String>,
+    pub cache: Option<Cache>,
+    pub cache_ttl: Duration,
+    pub candles: Option<CandleSource>,
+    pub offline: bool,
+    /// When offline, skip coins whose history isn't cached with a warning
+    /// instead of reporting them as failed.
+    pub best_effort_offline: bool,
+    /// End the run with the first per-coin error instead of reporting it
+    /// and carrying on with the other coins.
+    pub fail_fast: bool,
+    /// Cancelling this ends the run with Cancelled, dropping any fetches
+    /// still in flight.
+    pub cancel: CancellationToken,
+    pub max_list_age: Option<Duration>,
+    pub retry: Retry,
+    pub convert: Option<String>,
+    pub interval_auto: bool,
+    /// Every request the run makes goes through this client, so callers can
+    /// bring their own TLS, proxy or middleware setup; build_client makes
+    /// the one the binary uses.
+    pub client: reqwest::Client,
+    /// Histories fetched at once; 0 is treated as 1.
+    pub concurrency: usize,
+    pub window: Window,
+    pub stablecoins: StablecoinFilter,
+    pub keep_raw: bool,
+    /// Fetch an empty history once more before believing it.
+    pub retry_empty: bool,
+    /// Only keep coins in this category (see the category module).
+    pub category: Option<String>,
+    /// Only fetch listed coins whose symbol matches this pattern.
+    pub coins_regex: Option<regex::Regex>,
+    /// Let coins_regex match the coin's name as well as its symbol.
+    pub regex_matches_names: bool,
+    /// Read the coin list, rates and histories from these files instead of
+    /// the API.
+    pub fixtures: Option<FileProvider>,
+    pub body_limits: BodyLimits,
+    /// History interval per coin id or symbol, overriding the global one.
+    pub intervals: BTreeMap<String, String>,
+    /// Extend a stale cached history with just the points after its last
+    /// one, instead of fetching the whole window again.
+    pub incremental: bool,
+}
+
+#[derive(Debug)]
+pub(crate) enum FetchError {
+    Http(reqwest::Error),
+    /// The body arrived but isn't the JSON we expected, possibly because
+    /// the connection dropped part way through it.
+    Parse(serde_json::Error),
+    OfflineCacheMiss(String),
+    Fixture(std::path::PathBuf, io::Error),
+    StaleCache {
+        key: String,
+        age: Duration,
+    },
+    ResponseTooLarge {
+        url: String,
+        limit: usize,
+    },
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            // reqwest leaves the redirect policy's reason out of its message.
+            FetchError::Http(e) if e.is_redirect() => match std::error::Error::source(e) {
+                Some(reason) => write!(f, "{}: {}", e, reason),
+                None => write!(f, "{}", e),
+            },
+            FetchError::Http(e) => write!(f, "{}", e),
+            FetchError::Parse(e) => write!(f, "unexpected response body: {}", e),
+            FetchError::OfflineCacheMiss(key) => {
+                write!(f, "running offline and {} is not in the cache", key)
+            }
+            FetchError::Fixture(path, e) => write!(f, "{}: {}", path.display(), e),
+            FetchError::StaleCache { key, age } => write!(
+                f,
+                "running offline and the cached {} is {} hours old",
+                key,
+                age.as_secs() / 3600
+            ),
+            FetchError::ResponseTooLarge { url, limit } => {
+                write!(f, "response from {} is over the {} byte limit", url, limit)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FetchError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FetchError::Http(e) => Some(e),
+            FetchError::Parse(e) => Some(e),
+            FetchError::Fixture(_, e) => Some(e),
+            FetchError::OfflineCacheMiss(_)
+            | FetchError::StaleCache { .. }
+            | FetchError::ResponseTooLarge { .. } => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for FetchError {
+    fn from(e: reqwest::Error) -> Self {
+        FetchError::Http(e)
+    }
+}
+
+#[tracing::instrument(
+    level = "debug",
+    skip_all,
+    fields(coin = %name, interval = %interval, url = tracing::field::Empty)
+)]
+async fn get_coin_data(
+    client: &reqwest::Client,
+    url: &str,
+    name: &str,
+    interval: &str,
+    window: Window,
+    retry: &Retry,
+    limits: &BodyLimits,
+) -> Result<CoinData, FetchError> {
+    let windows = history_windows(window, interval);
+    let mut data = Vec::new();
+    for piece in &windows {
+        let url = &history_url(url, name, interval, *piece);
+        tracing::Span::current().record("url", tracing::field::display(url));
+        let resp = with_retry(retry, || get_json::<CoinData>(client, url, limits)).await?;
+        data.extend(resp.data);
+    }
+    if windows.len() > 1 {
+        data = dedupe_by_time(data);
+    }
+    Ok(CoinData { data })
+}
+
+// The span one piece of a history covers at `interval`, if it's known.
+fn history_piece_span(interval: &str) -> Option<u64> {
+    interval_millis(interval).map(|millis| millis * MAX_HISTORY_POINTS)
+}
+
+// How many pieces the whole of `window` would take at `interval`.
+fn history_pieces_needed(window: Window, interval: &str) -> u64 {
+    history_piece_span(interval).map_or(1, |span| {
+        window
+            .end_ms
+            .saturating_sub(window.start_ms)
+            .div_ceil(span)
+            .max(1)
+    })
+}
+
+// `window` split into consecutive windows of at most MAX_HISTORY_POINTS
+// points at `interval`, no more than MAX_HISTORY_PIECES of them and those
+// ending with the window; just `window` when it fits or the interval is
+// unknown. Neighbouring pieces share their boundary, so a history joined
+// from them repeats a point at each.
+fn history_windows(window: Window, interval: &str) -> Vec<Window> {
+    let Some(span) = history_piece_span(interval) else {
+        return vec![window];
+    };
+    let earliest = window
+        .end_ms
+        .saturating_sub(span.saturating_mul(MAX_HISTORY_PIECES));
+    let mut windows = Vec::new();
+    let mut start_ms = window.start_ms.max(earliest);
+    loop {
+        let end_ms = start_ms.saturating_add(span).min(window.end_ms);
+        windows.push(Window { start_ms, end_ms });
+        if end_ms >= window.end_ms {
+            return windows;
+        }
+        start_ms = end_ms;
+    }
+}
+
+fn history_url(url: &str, name: &str, interval: &str, window: Window) -> String {
+    format!(
+        "{}/assets/{}/history?interval={}&start={}&end={}",
+        url, name, interval, window.start_ms, window.end_ms
+    )
+}
+
+// Parses the body ourselves rather than with Response::json so a bad body
+// surfaces as FetchError::Parse instead of a reqwest error.
+async fn get_json<T: DeserializeOwned>(
+    client: &reqwest::Client,
+    url: &str,
+    limits: &BodyLimits,
+) -> Result<T, FetchError> {
+    let _permit = host_permit(limits, url).await;
+    let resp = client.get(url).send().await?.error_for_status()?;
+    let body = read_body(resp, limits).await?;
+    serde_json::from_slice(&body).map_err(FetchError::Parse)
+}
+
+/// Default for BodyLimits::max_bytes: many times a year of minute history,
+/// but still a bound on what a broken server or proxy can make us buffer.
+pub const DEFAULT_MAX_RESPONSE_BYTES: usize = 256 * 1024 * 1024;
+
+/// Limits applied to each request and while its response body is
+/// downloaded.
+#[derive(Debug)]
+pub struct BodyLimits {
+    /// Buffered bodies larger than this fail with ResponseTooLarge.
+    pub max_bytes: usize,
+    /// Cap on how fast all response bodies together are downloaded.
+    pub throttle: Option<Throttle>,
+    /// Cap on the requests in flight to any one host.
+    pub per_host: Option<HostLimit>,
+}
+
+impl Default for BodyLimits {
+    fn default() -> Self {
+        BodyLimits {
+            max_bytes: DEFAULT_MAX_RESPONSE_BYTES,
+            throttle: None,
+            per_host: None,
+        }
+    }
+}
+
+/// Caps how many requests are in flight to each host at once, over every
+/// request that shares it. reqwest's pool only bounds idle connections, so
+/// without this a wide fan-out opens as many sockets as it has requests.
+#[derive(Debug)]
+pub struct HostLimit {
+    per_host: usize,
+    hosts: Mutex<HashMap<String, Arc<tokio::sync::Semaphore>>>,
+}
+
+impl HostLimit {
+    pub fn new(per_host: usize) -> Self {
+        HostLimit {
+            per_host: per_host.max(1),
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn acquire(&self, url: &str) -> Option<tokio::sync::OwnedSemaphorePermit> {
+        let url = reqwest::Url::parse(url).ok()?;
+        let host = format!("{}:{}", url.host_str()?, url.port_or_known_default()?);
+        let semaphore = self
+            .hosts
+            .lock()
+            .unwrap()
+            .entry(host)
+            .or_insert_with(|| Arc::new(tokio::sync::Semaphore::new(self.per_host)))
+            .clone();
+        semaphore.acquire_owned().await.ok()
+    }
+}
+
+// Held until the response has been read, since that's when the connection
+// is free again.
+async fn host_permit(limits: &BodyLimits, url: &str) -> Option<tokio::sync::OwnedSemaphorePermit> {
+    limits.per_host.as_ref()?.acquire(url).await
+}
+
+/// Caps how fast response bodies are downloaded, summed over every request
+/// that shares it.
+#[derive(Debug)]
+pub struct Throttle {
+    bytes_per_sec: u64,
+    // When the bytes read so far will have been paid for at the cap.
+    next_free: Mutex<Option<Instant>>,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Throttle {
+            bytes_per_sec: bytes_per_sec.max(1),
+            next_free: Mutex::new(None),
+        }
+    }
+
+    // How long to pause after reading `bytes` more. Unused time while idle
+    // isn't banked, so a run can't burst past the cap after a quiet spell.
+    fn delay_after(&self, bytes: usize) -> Duration {
+        let now = Instant::now();
+        let mut next_free = self.next_free.lock().unwrap();
+        let start = next_free.map_or(now, |free| free.max(now));
+        let due = start + Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec as f64);
+        *next_free = Some(due);
+        due - now
+    }
+}
+
+async fn read_body(
+    mut resp: reqwest::Response,
+    limits: &BodyLimits,
+) -> Result<Vec<u8>, FetchError> {
+    let too_large = |resp: &reqwest::Response| FetchError::ResponseTooLarge {
+        url: resp.url().to_string(),
+        limit: limits.max_bytes,
+    };
+    // Refuse up front when the server says how big the body is.
+    if resp
+        .content_length()
+        .is_some_and(|len| len > limits.max_bytes as u64)
+    {
+        return Err(too_large(&resp));
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = resp.chunk().await? {
+        if body.len() + chunk.len() > limits.max_bytes {
+            return Err(too_large(&resp));
+        }
+        body.extend_from_slice(&chunk);
+        if let Some(throttle) = &limits.throttle {
+            tokio::time::sleep(throttle.delay_after(chunk.len())).await;
+        }
+    }
+    Ok(body)
+}
+
+enum Conditional<T> {
+    Modified(T, Validators),
+    NotModified,
+}
+
+// Like get_json, but sends `validators` so an unchanged resource comes back
+// as an empty 304 instead of the whole body again.
+async fn get_json_conditional<T: DeserializeOwned>(
+    client: &reqwest::Client,
+    url: &str,
+    validators: Option<&Validators>,
+    limits: &BodyLimits,
+) -> Result<Conditional<T>, FetchError> {
+    let mut request = client.get(url);
+    if let Some(validators) = validators {
+        if let Some(etag) = &validators.etag {
+            request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &validators.last_modified {
+            request = request.header(reqwest::header::IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+    let _permit = host_permit(limits, url).await;
+    let resp = request.send().await?;
+    if resp.status() == reqwest::StatusCode::NOT_MODIFIED && validators.is_some() {
+        return Ok(Conditional::NotModified);
+    }
+    let resp = resp.error_for_status()?;
+    let header = |name| {
+        resp.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    };
+    let received = Validators {
+        etag: header(reqwest::header::ETAG),
+        last_modified: header(reqwest::header::LAST_MODIFIED),
+    };
+    let body = read_body(resp, limits).await?;
+    let data = serde_json::from_slice(&body).map_err(FetchError::Parse)?;
+    Ok(Conditional::Modified(data, received))
+}
+
+// Fetched data tagged with where it came from.
+#[derive(Debug)]
+struct Fetched<T> {
+    data: T,
+    source: Source,
+}
+
+impl<T> Fetched<T> {
+    fn from_cache(data: T) -> Self {
+        Fetched {
+            data,
+            source: Source::Cache,
+        }
+    }
+
+    fn from_network(data: T) -> Self {
+        Fetched {
+            data,
+            source: Source::Network,
+        }
+    }
+}
+
+// Serves `key` from `cache` while it is fresh. A stale entry is revalidated
+// with the validators stored alongside it and kept on a 304.
+async fn revalidated<T>(
+    cache: &Cache,
+    opts: &RunOptions,
+    key: &str,
+    url: &str,
+) -> Result<Fetched<T>, FetchError>
+where
+    T: Serialize + DeserializeOwned,
+{
+    if cache.is_fresh(key, opts.cache_ttl) {
+        if let Some(data) = cache.read(key) {
+            return Ok(Fetched::from_cache(data));
+        }
+    }
+    let stale = cache.read::<T>(key).zip(cache.validators(key));
+    let validators = stale.as_ref().map(|(_, validators)| validators);
+    let fetched = with_retry(&opts.retry, || {
+        get_json_conditional::<T>(&opts.client, url, validators, &opts.body_limits)
+    })
+    .await?;
+    let (fetched, validators) = match (fetched, stale) {
+        (Conditional::Modified(data, validators), _) => {
+            (Fetched::from_network(data), Some(validators))
+        }
+        // Rewriting the unchanged body resets its age.
+        (Conditional::NotModified, Some((data, _))) => (Fetched::from_cache(data), None),
+        (Conditional::NotModified, None) => {
+            unreachable!("a 304 is only accepted when validators were sent")
+        }
+    };
+    let stored = cache
+        .write(key, &fetched.data)
+        .and_then(|()| match &validators {
+            Some(validators) => cache.write_validators(key, validators),
+            None => Ok(()),
+        });
+    if let Err(e) = stored {
+        eprintln!("Warning: failed to cache {}: {}", key, e);
+    }
+    Ok(fetched)
+}
+
+// Serves `key` from the cache while it is fresh (or at any age when
+// offline), otherwise awaits `fetch` and stores the result.
+async fn cached<T, Fut>(opts: &RunOptions, key: &str, fetch: Fut) -> Result<Fetched<T>, FetchError>
+where
+    T: Serialize + DeserializeOwned,
+    Fut: Future<Output = Result<T, FetchError>>,
+{
+    if let Some(cache) = &opts.cache {
+        if opts.offline || cache.is_fresh(key, opts.cache_ttl) {
+            if let Some(data) = cache.read(key) {
+                return Ok(Fetched::from_cache(data));
+            }
+        }
+    }
+    if opts.offline {
+        return Err(FetchError::OfflineCacheMiss(key.to_string()));
+    }
+    let data = fetch.await?;
+    if let Some(cache) = &opts.cache {
+        if let Err(e) = cache.write(key, &data) {
+            eprintln!("Warning: failed to cache {}: {}", key, e);
+        }
+    }
+    Ok(Fetched::from_network(data))
+}
+
+fn history_cache_key(id: &str, interval: &str, opts: &RunOptions) -> String {
+    let key = cache::history_key(id, interval);
+    // Histories over other windows are different data, including windows
+    // of the same span pinned to different ends with --as-of. An
+    // incremental history follows its window as it moves, so it keeps one
+    // entry and is trimmed to the window instead.
+    if opts.window != Window::default() && !opts.incremental {
+        return window_cache_key(id, interval, opts.window);
+    }
+    key
+}
+
+fn window_cache_key(id: &str, interval: &str, window: Window) -> String {
+    format!(
+        "{}.{}-{}",
+        cache::history_key(id, interval),
+        window.start_ms,
+        window.end_ms
+    )
+}
+
+async fn get_coin_data_cached(
+    url: &str,
+    id: &str,
+    interval: &str,
+    opts: &RunOptions,
+) -> Result<Fetched<CoinData>, FetchError> {
+    if let Some(fixtures) = &opts.fixtures {
+        let data = fixtures.read(&fixtures::history_file(id, interval))?;
+        return Ok(Fetched {
+            data,
+            source: Source::Fixture,
+        });
+    }
+    let key = history_cache_key(id, interval, opts);
+    let pieces = history_windows(opts.window, interval);
+    match &opts.cache {
+        Some(cache) if !opts.offline && pieces.len() > 1 => {
+            revalidated_pieces(cache, url, id, interval, &key, &pieces, opts).await
+        }
+        Some(cache) if !opts.offline => {
+            if opts.incremental && !cache.is_fresh(&key, opts.cache_ttl) {
+                if let Some(data) = append_to_cached(cache, url, id, interval, &key, opts).await? {
+                    return Ok(Fetched::from_network(data));
+                }
+            }
+            let url = history_url(url, id, interval, opts.window);
+            revalidated(cache, opts, &key, &url).await
+        }
+        _ => {
+            cached(
+                opts,
+                &key,
+                get_coin_data(
+                    &opts.client,
+                    url,
+                    id,
+                    interval,
+                    opts.window,
+                    &opts.retry,
+                    &opts.body_limits,
+                ),
+            )
+            .await
+        }
+    }
+}
+
+// Conditional requests need one url per cache entry, so each piece of a
+// wide window is cached and revalidated on its own. The joined history is
+// stored under the whole window's key as well, for offline runs.
+async fn revalidated_pieces(
+    cache: &Cache,
+    url: &str,
+    id: &str,
+    interval: &str,
+    key: &str,
+    pieces: &[Window],
+    opts: &RunOptions,
+) -> Result<Fetched<CoinData>, FetchError> {
+    let mut data = Vec::new();
+    let mut source = Source::Cache;
+    for piece in pieces {
+        let piece_key = window_cache_key(id, interval, *piece);
+        let piece_url = history_url(url, id, interval, *piece);
+        let fetched: Fetched<CoinData> = revalidated(cache, opts, &piece_key, &piece_url).await?;
+        if fetched.source == Source::Network {
+            source = Source::Network;
+        }
+        data.extend(fetched.data.data);
+    }
+    let data = CoinData {
+        data: dedupe_by_time(data),
+    };
+    if let Err(e) = cache.write(key, &data) {
+        eprintln!("Warning: failed to cache {}: {}", key, e);
+    }
+    Ok(Fetched { data, source })
+}
+
+// Fetches only the part of the window after the last cached point and
+// appends it to the cached history. None when there's no cached history
+// inside the window to extend.
+async fn append_to_cached(
+    cache: &Cache,
+    url: &str,
+    id: &str,
+    interval: &str,
+    key: &str,
+    opts: &RunOptions,
+) -> Result<Option<CoinData>, FetchError> {
+    let Some(stored) = cache.read::<CoinData>(key) else {
+        return Ok(None);
+    };
+    let Some(last) = stored.data.last().map(|point| point.time) else {
+        return Ok(None);
+    };
+    if !(opts.window.start_ms..opts.window.end_ms).contains(&last) {
+        return Ok(None);
+    }
+    // A history cached for a narrower window is missing the window's start.
+    let step = interval_millis(interval).unwrap_or(0);
+    if stored.data[0].time > opts.window.start_ms.saturating_add(step) {
+        return Ok(None);
+    }
+    let tail = Window {
+        start_ms: last,
+        end_ms: opts.window.end_ms,
+    };
+    let fresh = get_coin_data(
+        &opts.client,
+        url,
+        id,
+        interval,
+        tail,
+        &opts.retry,
+        &opts.body_limits,
+    )
+    .await?;
+    let data = cache::append_history(stored, fresh, opts.window.start_ms);
+    // The stored validators were for the whole window as it used to be.
+    let stored = cache
+        .write(key, &data)
+        .and_then(|()| cache.write_validators(key, &Validators::default()));
+    if let Err(e) = stored {
+        eprintln!("Warning: failed to cache {}: {}", key, e);
+    }
+    Ok(Some(data))
+}
+
+// Rankings from an old cached list would be rendered as if they were
+// current, so offline runs refuse a list older than max_list_age.
+async fn get_coins_cached(url: &str, opts: &RunOptions) -> Result<CryptoList, FetchError> {
+    if let Some(fixtures) = &opts.fixtures {
+        return fixtures.read(fixtures::ASSETS_FILE);
+    }
+    if let (true, Some(store), Some(max_age)) = (opts.offline, &opts.cache, opts.max_list_age) {
+        if let Some(age) = store.age(cache::ASSETS_KEY).filter(|age| *age > max_age) {
+            return Err(FetchError::StaleCache {
+                key: cache::ASSETS_KEY.to_string(),
+                age,
+            });
+        }
+    }
+    let coins = cached(
+        opts,
+        cache::ASSETS_KEY,
+        get_coins(&opts.client, url, &opts.retry, &opts.body_limits),
+    )
+    .await?;
+    Ok(coins.data)
+}
+
+#[tracing::instrument(level = "debug", skip_all, fields(url = tracing::field::Empty))]
+async fn get_coins(
+    client: &reqwest::Client,
+    url: &str,
+    retry: &Retry,
+    limits: &BodyLimits,
+) -> Result<CryptoList, FetchError> {
+    let url = &format!("{}/assets", url);
+    tracing::Span::current().record("url", tracing::field::display(url));
+    let resp = with_retry(retry, || get_json::<CryptoList>(client, url, limits)).await?;
+    Ok(resp)
+}
+
+async fn get_rates(
+    client: &reqwest::Client,
+    url: &str,
+    retry: &Retry,
+    limits: &BodyLimits,
+) -> Result<RateList, FetchError> {
+    let url = &format!("{}/rates", url);
+    let resp = with_retry(retry, || get_json::<RateList>(client, url, limits)).await?;
+    Ok(resp)
+}
+
+async fn get_rates_cached(url: &str, opts: &RunOptions) -> Result<RateList, FetchError> {
+    if let Some(fixtures) = &opts.fixtures {
+        return fixtures.read(fixtures::RATES_FILE);
+    }
+    let rates = cached(
+        opts,
+        cache::RATES_KEY,
+        get_rates(&opts.client, url, &opts.retry, &opts.body_limits),
+    )
+    .await?;
+    Ok(rates.data)
+}
+
+// The USD value of one unit of `symbol`, or why there isn't one.
+fn usd_rate(rates: &RateList, symbol: &str) -> Result<f64, String> {
+    let rate = rates
+        .data
+        .iter()
+        .find(|rate| rate.symbol.eq_ignore_ascii_case(symbol))
+        .ok_or_else(|| format!("no rate for {}", symbol))?;
+    rate.rateUsd
+        .as_deref()
+        .and_then(parse_price)
+        .filter(|usd| *usd > 0.0)
+        .ok_or_else(|| format!("{} is listed without a usable rate", symbol))
+}
+
+fn convert_prices(info: &mut CoinInfo, usd_per_unit: f64) {
+    info.all_time_high /= usd_per_unit;
+    info.all_time_low /= usd_per_unit;
+    info.current_price /= usd_per_unit;
+    for recent in [&mut info.recent_high, &mut info.recent_low]
+        .into_iter()
+        .flatten()
+    {
+        *recent /= usd_per_unit;
+    }
+    for price in &mut info.history {
+        *price /= usd_per_unit;
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn get_candles(
+    client: &reqwest::Client,
+    url: &str,
+    exchange: &str,
+    interval: &str,
+    base_id: &str,
+    quote_id: &str,
+    window: Window,
+    retry: &Retry,
+    limits: &BodyLimits,
+) -> Result<Vec<Candle>, FetchError> {
+    let url = &format!(
+        "{}/candles?exchange={}&interval={}&baseId={}&quoteId={}&start={}&end={}",
+        url, exchange, interval, base_id, quote_id, window.start_ms, window.end_ms
+    );
+    let resp = with_retry(retry, || get_json::<CandleList>(client, url, limits)).await?;
+    Ok(resp.data)
+}
+
+fn candle_prices(candles: &[Candle], source: PriceSource) -> CoinData {
+    CoinData {
+        data: candles
+            .iter()
+            .map(|candle| PriceData {
+                priceUsd: match source {
+                    PriceSource::Open => candle.open,
+                    PriceSource::High => candle.high,
+                    PriceSource::Low => candle.low,
+                    PriceSource::Close => candle.close,
+                }
+                .to_string(),
+                time: candle.period,
+            })
+            .collect(),
+    }
+}
+
+/// A failed name lookup, which usually succeeds a moment later. The
+/// client's resolver returns it, so it can be found in a request error's
+/// source() chain rather than guessed from the message.
+#[derive(Debug)]
+struct DnsError(io::Error);
+
+impl fmt::Display for DnsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for DnsError {}
+
+// The system resolver, as reqwest uses by default, with failures wrapped in
+// DnsError.
+struct Resolver;
+
+impl reqwest::dns::Resolve for Resolver {
+    fn resolve(&self, name: hyper::client::connect::dns::Name) -> reqwest::dns::Resolving {
+        Box::pin(async move {
+            // hyper sets the port on the addresses afterwards.
+            match tokio::net::lookup_host((name.as_str(), 0)).await {
+                Ok(addrs) => {
+                    let addrs: Vec<_> = addrs.collect();
+                    Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+                }
+                Err(e) => Err(Box::new(DnsError(e)) as _),
+            }
+        })
+    }
+}
+
+fn is_dns_error(e: &reqwest::Error) -> bool {
+    std::iter::successors(std::error::Error::source(e), |e| e.source()).any(|e| e.is::<DnsError>())
+}
+
+impl FetchError {
+    // A truncated body is worth another attempt; well-formed JSON of the
+    // wrong shape will be just as wrong next time.
+    fn is_retryable(&self) -> bool {
+        match self {
+            FetchError::Http(e) => {
+                e.is_timeout()
+                    || e.is_connect()
+                    || is_dns_error(e)
+                    || e.is_body()
+                    || e.status().is_some_and(|s| s.is_server_error())
+            }
+            FetchError::Parse(e) => e.is_eof(),
+            FetchError::OfflineCacheMiss(_)
+            | FetchError::Fixture(..)
+            | FetchError::StaleCache { .. }
+            | FetchError::ResponseTooLarge { .. } => false,
+        }
+    }
+
+    // DNS failures surface from reqwest as connect errors too.
+    fn is_connect(&self) -> bool {
+        matches!(self, FetchError::Http(e) if e.is_connect())
+    }
+
+    fn is_not_found(&self) -> bool {
+        matches!(self, FetchError::Http(e) if e.status() == Some(reqwest::StatusCode::NOT_FOUND))
+    }
+}
+
+// Every other request would fail the same way, so a connect failure is
+// reported once as the reason for the whole run.
+fn no_network(e: FetchError) -> Box<dyn std::error::Error> {
+    if e.is_connect() {
+        format!("no network connectivity ({})", e).into()
+    } else {
+        e.into()
+    }
+}
+
+// Exponential backoff capped at MAX_RETRY_DELAY_MS: 250ms, 500ms, 1s, ...
+fn backoff_delay(attempt: u32) -> Duration {
+    let millis = BASE_RETRY_DELAY_MS
+        .saturating_mul(1 << attempt.min(16))
+        .min(MAX_RETRY_DELAY_MS);
+    Duration::from_millis(millis)
+}
+
+// "Full jitter": pick uniformly between zero and the backoff delay so that
+// concurrent fetches failing together don't all retry at the same instant.
+fn jittered_delay<R: Rng>(attempt: u32, rng: &mut R) -> Duration {
+    let max = backoff_delay(attempt).as_millis() as u64;
+    Duration::from_millis(rng.gen_range(0..=max))
+}
+
+/// Retry settings plus the jitter RNG, shared by every fetch in a run. A
+/// fixed seed makes the backoff sequence reproducible.
+#[derive(Debug)]
+pub struct Retry {
+    max_attempts: u32,
+    /// No retry is started that would end past this long after the first
+    /// attempt, counting the attempts and the backoff between them.
+    max_total_retry_duration: Duration,
+    rng: Mutex<StdRng>,
+}
+
+impl Retry {
+    pub fn new(seed: Option<u64>) -> Self {
+        let rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Retry {
+            max_attempts: MAX_ATTEMPTS,
+            max_total_retry_duration: Duration::from_millis(MAX_TOTAL_RETRY_MS),
+            rng: Mutex::new(rng),
+        }
+    }
+
+    /// Bounds the time spent on one request's retries, e.g. to keep a
+    /// watch refresh from overrunning its interval.
+    pub fn with_max_total_retry_duration(self, budget: Duration) -> Self {
+        Retry {
+            max_total_retry_duration: budget,
+            ..self
+        }
+    }
+
+    fn next_delay(&self, attempt: u32) -> Duration {
+        let mut rng = self.rng.lock().unwrap_or_else(|e| e.into_inner());
+        jittered_delay(attempt, &mut *rng)
+    }
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry::new(None)
+    }
+}
+
+async fn with_retry<T, F, Fut>(retry: &Retry, mut op: F) -> Result<T, FetchError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, FetchError>>,
+{
+    // Tokio's clock, so tests can step through the backoff with time paused.
+    let started = tokio::time::Instant::now();
+    let mut attempt = 0;
+    loop {
+        match op().await {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt + 1 < retry.max_attempts && e.is_retryable() => {
+                let delay = retry.next_delay(attempt);
+                if started.elapsed() + delay > retry.max_total_retry_duration {
+                    return Err(e);
+                }
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// How many points a history request over the window returns at `interval`.
+fn expected_points(start: u64, end: u64, interval: &str) -> Option<u64> {
+    Some(end.checked_sub(start)? / interval_millis(interval)?)
+}
+
+// The interval whose point count over the window is closest to `target`.
+fn auto_interval(start: u64, end: u64, target: u64) -> &'static str {
+    INTERVALS
+        .into_iter()
+        .min_by_key(|interval| {
+            expected_points(start, end, interval).map_or(u64::MAX, |points| points.abs_diff(target))
+        })
+        .unwrap_or(INTERVAL)
+}
+
+// Fraction of the points a full history over the window would have, so thin
+// data for young or illiquid coins can be told apart from a real range.
+fn coverage(points: usize, start: u64, end: u64, interval: &str) -> Option<f64> {
+    let expected = expected_points(start, end, interval)?;
+    if expected == 0 {
+        return None;
+    }
+    Some((points as f64 / expected as f64).min(1.0))
+}
+
+impl PriceStats {
+    // Adds the stats of the window that follows this one, and says how
+    // many of this one's points it replaced. As with dedupe_by_time, a time
+    // both have keeps the later price.
+    fn extend(&mut self, later: PriceStats) -> usize {
+        let Some(&from) = later.times.first() else {
+            return 0;
+        };
+        let kept = self.times.partition_point(|time| *time < from);
+        let repeated = self.times.len() - kept;
+        if repeated > 0 {
+            // The extremes and sum can't be taken back, so the kept points
+            // are folded again.
+            let earlier = std::mem::take(self);
+            for (time, price) in earlier.times.into_iter().zip(earlier.series).take(kept) {
+                self.push(time, price);
+            }
+        }
+        for (time, price) in later.times.into_iter().zip(later.series) {
+            self.push(time, price);
+        }
+        repeated
+    }
+
+    // What the kept series takes up: a price and a time per point.
+    fn held_bytes(&self) -> usize {
+        self.series.len() * (std::mem::size_of::<f64>() + std::mem::size_of::<u64>())
+    }
+}
+
+// Folds a `{"data": [...]}` history body into PriceStats as it arrives, so
+// the body's text is never held beyond the element being parsed. The
+// parsed prices and times are still kept for the renderers that plot or
+// window the series, at a fraction of the text's size; held() counts both.
+// Points must already be in time order; a repeated timestamp keeps its
+// last price, as dedupe_by_time does.
+#[derive(Default)]
+struct HistoryStream {
+    buf: Vec<u8>,
+    in_array: bool,
+    done: bool,
+    // The latest point, held back until a later timestamp shows it wasn't
+    // repeated.
+    pending: Option<(u64, Option<f64>)>,
+    points: usize,
+    stats: PriceStats,
+}
+
+impl HistoryStream {
+    fn feed(&mut self, chunk: &[u8]) -> Result<(), serde_json::Error> {
+        if self.done {
+            return Ok(());
+        }
+        self.buf.extend_from_slice(chunk);
+        if !self.in_array {
+            let Some(start) = self.buf.iter().position(|b| *b == b'[') else {
+                return Ok(());
+            };
+            self.buf.drain(..=start);
+            self.in_array = true;
+        }
+        loop {
+            let separators = self
+                .buf
+                .iter()
+                .take_while(|b| b.is_ascii_whitespace() || **b == b',')
+                .count();
+            self.buf.drain(..separators);
+            match self.buf.first() {
+                None => return Ok(()),
+                Some(b']') => {
+                    self.done = true;
+                    self.buf.clear();
+                    self.flush();
+                    return Ok(());
+                }
+                Some(_) => {}
+            }
+            let mut values =
+                serde_json::Deserializer::from_slice(&self.buf).into_iter::<PriceData>();
+            match values.next() {
+                Some(Ok(point)) => {
+                    let used = values.byte_offset();
+                    self.push(point);
+                    self.buf.drain(..used);
+                }
+                // The element continues in a later chunk.
+                Some(Err(e)) if e.is_eof() => return Ok(()),
+                Some(Err(e)) => return Err(e),
+                None => return Ok(()),
+            }
+        }
+    }
+
+    fn push(&mut self, point: PriceData) {
+        self.points += 1;
+        let price = parse_price(&point.priceUsd);
+        match &mut self.pending {
+            Some((time, pending)) if *time == point.time => *pending = price,
+            _ => {
+                self.flush();
+                self.pending = Some((point.time, price));
+            }
+        }
+    }
+
+    // Bytes held so far: the unparsed text plus the kept series.
+    fn held(&self) -> usize {
+        self.buf.len() + self.stats.held_bytes()
+    }
+
+    fn flush(&mut self) {
+        if let Some((time, Some(price))) = self.pending.take() {
+            self.stats.push(time, price);
+        }
+    }
+
+    // The stats and how many points the body held, or why it was cut short.
+    fn finish(mut self) -> Result<(PriceStats, usize), serde_json::Error> {
+        if !self.done {
+            // What's left can't be a whole body, so parsing it says why: an
+            // EOF for a truncated array, a missing field for anything else.
+            let err = if self.in_array {
+                serde_json::from_slice::<PriceData>(&self.buf).err()
+            } else {
+                serde_json::from_slice::<CoinData>(&self.buf).err()
+            };
+            if let Some(e) = err {
+                return Err(e);
+            }
+        }
+        self.flush();
+        Ok((self.stats, self.points))
+    }
+}
+
+async fn get_price_stats_streamed(
+    client: &reqwest::Client,
+    url: &str,
+    limits: &BodyLimits,
+) -> Result<(PriceStats, usize), FetchError> {
+    let _permit = host_permit(limits, url).await;
+    let mut resp = client.get(url).send().await?.error_for_status()?;
+    let mut history = HistoryStream::default();
+    while let Some(chunk) = resp.chunk().await? {
+        history.feed(&chunk).map_err(FetchError::Parse)?;
+        // The body itself isn't kept, so the cap is on what is: a history
+        // with too many points, a point bigger than the cap, or a body
+        // with no array.
+        if history.held() > limits.max_bytes {
+            return Err(FetchError::ResponseTooLarge {
+                url: url.to_string(),
+                limit: limits.max_bytes,
+            });
+        }
+        if let Some(throttle) = &limits.throttle {
+            tokio::time::sleep(throttle.delay_after(chunk.len())).await;
+        }
+    }
+    history.finish().map_err(FetchError::Parse)
+}
+
+// Streams each of the window's pieces in turn, each with its own retries.
+async fn get_price_stats_in_pieces(
+    url: &str,
+    id: &str,
+    interval: &str,
+    opts: &RunOptions,
+) -> Result<(PriceStats, usize), FetchError> {
+    let mut stats = PriceStats::default();
+    let mut points = 0;
+    for piece in history_windows(opts.window, interval) {
+        let url = &history_url(url, id, interval, piece);
+        let (piece_stats, piece_points) = with_retry(&opts.retry, || {
+            get_price_stats_streamed(&opts.client, url, &opts.body_limits)
+        })
+        .await?;
+        points += piece_points - stats.extend(piece_stats);
+        if stats.held_bytes() > opts.body_limits.max_bytes {
+            return Err(FetchError::ResponseTooLarge {
+                url: url.to_string(),
+                limit: opts.body_limits.max_bytes,
+            });
+        }
+    }
+    Ok((stats, points))
+}
+
+// Minute histories over a long window are by far the largest bodies, and
+// are streamed when nothing needs the fetched points themselves kept.
+fn streams_history(interval: &str, opts: &RunOptions) -> bool {
+    interval == "m1"
+        && opts.cache.is_none()
+        && opts.candles.is_none()
+        && opts.fixtures.is_none()
+        && !opts.offline
+        && !opts.keep_raw
+}
+
+// A coin's stats and the number of points fetched, plus the history as
+// fetched when keep_raw is set.
+async fn fetch_stats(
+    url: &str,
+    id: &str,
+    interval: &str,
+    opts: &RunOptions,
+) -> Result<Fetched<(PriceStats, usize, Option<CoinData>)>, FetchError> {
+    if streams_history(interval, opts) {
+        let fetch = || get_price_stats_in_pieces(url, id, interval, opts);
+        let (mut stats, mut points) = fetch().await?;
+        if points == 0 && opts.retry_empty {
+            tokio::time::sleep(Duration::from_millis(EMPTY_RETRY_DELAY_MS)).await;
+            (stats, points) = fetch().await?;
+        }
+        return Ok(Fetched::from_network((stats, points, None)));
+    }
+    let Fetched { data, source } = fetch_history(url, id, interval, opts).await?;
+    let raw = opts.keep_raw.then(|| data.clone());
+    let data = until_window_end(data, opts.window);
+    let points = data.data.len();
+    let stats = PriceStats::from_prices(&dedupe_by_time(data.data));
+    Ok(Fetched {
+        data: (stats, points, raw),
+        source,
+    })
+}
+
+// The API only returns points up to the window's end, but a cached history
+// may run past it when the end has been pinned with --as-of.
+fn until_window_end(mut data: CoinData, window: Window) -> CoinData {
+    data.data.retain(|point| point.time <= window.end_ms);
+    data
+}
+
+// Volumes span many orders of magnitude, so they are compared on a log scale:
+// the lowest volume in the batch maps to 0.0 and the highest to 1.0. Coins
+// without a positive volume get None.
+fn normalize_volumes(volumes: &[Option<f64>]) -> Vec<Option<f64>> {
+    let logs: Vec<Option<f64>> = volumes
+        .iter()
+        .map(|v| v.filter(|v| *v > 0.0).map(f64::log10))
+        .collect();
+    let known = logs.iter().flatten();
+    let min = known.clone().fold(f64::INFINITY, |acc, x| acc.min(*x));
+    let max = known.fold(f64::NEG_INFINITY, |acc, x| acc.max(*x));
+    logs.iter()
+        .map(|log| {
+            log.map(|log| {
+                if max > min {
+                    (log - min) / (max - min)
+                } else {
+                    1.0
+                }
+            })
+        })
+        .collect()
+}
+
+// Symbols aren't unique across coins, so the best ranked match wins.
+fn resolve_symbol<'a>(coin_list: &'a CryptoList, symbol: &str) -> Option<&'a str> {
+    coin_list
+        .data
+        .iter()
+        .filter(|coin| coin.symbol.eq_ignore_ascii_case(symbol))
+        .min_by_key(|coin| coin.rank.parse::<u32>().unwrap_or(u32::MAX))
+        .map(|coin| coin.id.as_str())
+}
+
+fn find_coin<'a>(coin_list: &'a CryptoList, requested: &str) -> Option<&'a Cryptocurrency> {
+    coin_list
+        .data
+        .iter()
+        .find(|coin| coin.id == requested)
+        .or_else(|| {
+            let id = resolve_symbol(coin_list, requested)?;
+            coin_list.data.iter().find(|coin| coin.id == id)
+        })
+}
+
+// Requested coins missing from the list are left out; run looks them up
+// by id directly.
+fn select_coins<'a>(coin_list: &'a CryptoList, wanted: &[String]) -> Vec<&'a Cryptocurrency> {
+    if wanted.is_empty() {
+        return coin_list.data.iter().collect();
+    }
+    wanted
+        .iter()
+        .filter_map(|requested| find_coin(coin_list, requested))
+        .collect()
+}
+
+// Coins always match when there is no coins_regex.
+fn matches_pattern(coin: &Cryptocurrency, opts: &RunOptions) -> bool {
+    match &opts.coins_regex {
+        Some(pattern) => {
+            pattern.is_match(&coin.symbol)
+                || (opts.regex_matches_names && pattern.is_match(&coin.name))
+        }
+        None => true,
+    }
+}
+
+// Unlisted ids are matched lowercased, the form the API files them under.
+fn in_wanted_category(id: &str, opts: &RunOptions) -> bool {
+    match &opts.category {
+        Some(wanted) => category::in_category(&id.to_lowercase(), wanted),
+        None => true,
+    }
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+// The listed id nearest to a mistyped one, if any is close enough to be a
+// plausible typo.
+fn closest_id<'a>(coin_list: &'a CryptoList, requested: &str) -> Option<&'a str> {
+    let requested = requested.to_lowercase();
+    let max_distance = (requested.chars().count() / 3).max(2);
+    coin_list
+        .data
+        .iter()
+        .map(|coin| (edit_distance(&requested, &coin.id), coin.id.as_str()))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, id)| id)
+}
+
+// The history endpoint is case sensitive, so an id that 404s is retried
+// in lowercase. Returns the id that worked alongside its history.
+async fn get_coin_data_any_case(
+    url: &str,
+    id: &str,
+    interval: &str,
+    opts: &RunOptions,
+) -> Result<(String, Fetched<CoinData>), FetchError> {
+    let lower = id.to_lowercase();
+    match get_coin_data_cached(url, id, interval, opts).await {
+        Err(e) if e.is_not_found() && lower != id => {
+            let data = get_coin_data_cached(url, &lower, interval, opts).await?;
+            Ok((lower, data))
+        }
+        result => result.map(|data| (id.to_string(), data)),
+    }
+}
+
+async fn fetch_history(
+    url: &str,
+    id: &str,
+    interval: &str,
+    opts: &RunOptions,
+) -> Result<Fetched<CoinData>, FetchError> {
+    match &opts.candles {
+        Some(_) if opts.offline => Err(FetchError::OfflineCacheMiss(format!("candles for {}", id))),
+        Some(source) => get_candles(
+            &opts.client,
+            url,
+            &source.exchange,
+            interval,
+            id,
+            &source.quote_id,
+            opts.window,
+            &opts.retry,
+            &opts.body_limits,
+        )
+        .await
+        .map(|candles| Fetched::from_network(candle_prices(&candles, source.price))),
+        None => {
+            let fetched = get_coin_data_cached(url, id, interval, opts).await?;
+            if fetched.data.data.is_empty() && opts.retry_empty && !opts.offline {
+                let data = refetch_empty(url, id, interval, opts).await?;
+                return Ok(Fetched::from_network(data));
+            }
+            Ok(fetched)
+        }
+    }
+}
+
+// /history sometimes answers 200 with no points during upstream trouble,
+// for coins that do have data. Waits a moment and asks once more, straight
+// from the API since the empty body may have just been cached.
+async fn refetch_empty(
+    url: &str,
+    id: &str,
+    interval: &str,
+    opts: &RunOptions,
+) -> Result<CoinData, FetchError> {
+    tokio::time::sleep(Duration::from_millis(EMPTY_RETRY_DELAY_MS)).await;
+    let data = get_coin_data(
+        &opts.client,
+        url,
+        id,
+        interval,
+        opts.window,
+        &opts.retry,
+        &opts.body_limits,
+    )
+    .await?;
+    if let (Some(cache), false) = (&opts.cache, data.data.is_empty()) {
+        let key = history_cache_key(id, interval, opts);
+        if let Err(e) = cache.write(&key, &data) {
+            eprintln!("Warning: failed to cache {}: {}", key, e);
+        }
+    }
+    Ok(data)
+}
+
+// The interval configured for a coin by id or symbol, if any.
+fn coin_interval<'a>(opts: &'a RunOptions, id: &str, symbol: Option<&str>) -> Option<&'a str> {
+    opts.intervals
+        .iter()
+        .find(|(key, _)| {
+            key.eq_ignore_ascii_case(id)
+                || symbol.is_some_and(|symbol| key.eq_ignore_ascii_case(symbol))
+        })
+        .map(|(_, interval)| interval.as_str())
+}
+
+/// What a run produced, and which coins it left out.
+#[derive(Debug, Default)]
+pub struct RunReport {
+    pub infos: Vec<CoinInfo>,
+    /// Skipped with a warning under best_effort_offline.
+    pub skipped_offline: Vec<String>,
+    /// Reported as errors and left out.
+    pub failed: Vec<String>,
+    /// The currency code prices were converted to, uppercased. None when
+    /// they are in USD, including when convert's rate couldn't be had.
+    pub currency: Option<String>,
+}
+
+#[tracing::instrument(level = "debug", skip_all, fields(url = %url, coins = opts.coins.len()))]
+pub async fn run(
+    url: &str,
+    opts: &RunOptions,
+    on_info: &mut dyn FnMut(&CoinInfo) -> io::Result<()>,
+) -> Result<RunReport, Box<dyn std::error::Error>> {
+    // Losing the race drops run_coins, and with it every pending request.
+    tokio::select! {
+        result = run_coins(url, opts, on_info) => result,
+        _ = opts.cancel.cancelled() => Err(Cancelled.into()),
+    }
+}
+
+async fn run_coins(
+    url: &str,
+    opts: &RunOptions,
+    on_info: &mut dyn FnMut(&CoinInfo) -> io::Result<()>,
+) -> Result<RunReport, Box<dyn std::error::Error>> {
+    // The rates don't depend on the coin list, so both are fetched at once.
+    let (coin_list, usd_per_unit) = match &opts.convert {
+        Some(symbol) => {
+            let (coin_list, rates) =
+                tokio::join!(get_coins_cached(url, opts), get_rates_cached(url, opts));
+            let usd_per_unit = match rates {
+                Ok(rates) => match usd_rate(&rates, symbol) {
+                    Ok(rate) => Some(rate),
+                    Err(reason) => {
+                        eprintln!("Warning: {}, showing prices in USD", reason);
+                        None
+                    }
+                },
+                Err(e) => {
+                    eprintln!(
+                        "Warning: couldn't fetch rates ({}), showing prices in USD",
+                        e
+                    );
+                    None
+                }
+            };
+            (coin_list.map_err(no_network)?, usd_per_unit)
+        }
+        None => (get_coins_cached(url, opts).await.map_err(no_network)?, None),
+    };
+    if coin_list.data.is_empty() {
+        return Err("the coin list is empty, the API may be having an outage".into());
+    }
+    let coins: Vec<&Cryptocurrency> = select_coins(&coin_list, &opts.coins)
+        .into_iter()
+        .filter(|coin| matches_pattern(coin, opts) && in_wanted_category(&coin.id, opts))
+        .collect();
+    let unlisted: Vec<&String> = opts
+        .coins
+        .iter()
+        .filter(|requested| find_coin(&coin_list, requested).is_none())
+        .filter(|requested| in_wanted_category(requested, opts))
+        .collect();
+    let none_listed = coins.is_empty();
+    let volumes: Vec<Option<f64>> = coins
+        .iter()
+        .map(|coin| parse_price(coin.volumeUsd24Hr.as_ref()?))
+        .collect();
+    let relative_volumes = normalize_volumes(&volumes);
+    let interval = if opts.interval_auto {
+        auto_interval(opts.window.start_ms, opts.window.end_ms, TARGET_POINTS)
+    } else {
+        INTERVAL
+    };
+    let overrides = opts.intervals.values().map(String::as_str);
+    let mut intervals: Vec<&str> = std::iter::once(interval).chain(overrides).collect();
+    intervals.sort_unstable();
+    intervals.dedup();
+    for interval in intervals {
+        let needed = history_pieces_needed(opts.window, interval);
+        if needed > MAX_HISTORY_PIECES {
+            eprintln!(
+                "Warning: {} points over this window take {} requests per coin, \
+                 so only the most recent {} are fetched",
+                interval, needed, MAX_HISTORY_PIECES
+            );
+        }
+    }
+    let mut infos = Vec::new();
+    // Once any history has arrived the network is evidently up, and later
+    // failures are reported per coin.
+    let mut reachable = false;
+    let mut emit = |mut info: CoinInfo, points: usize, interval: &str| -> io::Result<()> {
+        // Checked before any conversion, while prices are still in USD.
+        if !opts.stablecoins.keeps(&info) {
+            return Ok(());
+        }
+        // A wide history is only fetched from its most recent pieces on.
+        let start_ms = match opts.candles {
+            Some(_) => opts.window.start_ms,
+            None => history_windows(opts.window, interval)[0].start_ms,
+        };
+        info.coverage = coverage(points, start_ms, opts.window.end_ms, interval);
+        info.window_start = Some(start_ms);
+        info.window_end = Some(opts.window.end_ms);
+        if let Some(usd_per_unit) = usd_per_unit {
+            convert_prices(&mut info, usd_per_unit);
+        }
+        on_info(&info)?;
+        infos.push(info);
+        Ok(())
+    };
+    let mut failed = Vec::new();
+    let mut report = |id: &str, e: Box<dyn std::error::Error>| {
+        if opts.fail_fast {
+            return Err(e);
+        }
+        eprintln!("Error: {}", e);
+        failed.push(id.to_string());
+        Ok(())
+    };
+    let mut skipped = Vec::new();
+    let mut skip_uncached = |id: &str| {
+        eprintln!("Warning: {} isn't in the cache, skipping it", id);
+        skipped.push(id.to_string());
+    };
+    // Fetched concurrently, but handed on in list order so the output
+    // doesn't depend on which response arrives first.
+    let mut fetches = stream::iter(coins.iter().enumerate())
+        .map(|(i, coin)| async move {
+            let interval = coin_interval(opts, &coin.id, Some(&coin.symbol)).unwrap_or(interval);
+            let fetched = fetch_stats(url, &coin.id, interval, opts).await;
+            (i, *coin, interval, fetched)
+        })
+        .buffered(opts.concurrency.max(1));
+    while let Some((i, coin, interval, fetched)) = fetches.next().await {
+        match fetched {
+            Err(e) if !reachable && e.is_connect() => return Err(no_network(e)),
+            Err(FetchError::OfflineCacheMiss(_)) if opts.best_effort_offline => {
+                skip_uncached(&coin.id)
+            }
+            Ok(Fetched {
+                data: (stats, points, raw),
+                source,
+            }) => {
+                reachable = true;
+                match coin_info(stats, &coin.id) {
+                    Ok(mut info) => {
+                        info.raw = raw;
+                        info.source = Some(source);
+                        info.volume_usd_24h = volumes[i];
+                        info.relative_volume = relative_volumes[i];
+                        info.rank = coin.rank.parse().ok();
+                        info.symbol = Some(coin.symbol.clone());
+                        info.supply = coin.supply.as_deref().and_then(parse_price);
+                        info.max_supply = coin.maxSupply.as_deref().and_then(parse_price);
+                        info.supply_percent = info
+                            .supply
+                            .and_then(|supply| supply_percent(supply, info.max_supply));
+                        emit(info, points, interval)?;
+                    }
+                    Err(e) => report(&coin.id, e)?,
+                }
+            }
+            Err(e) => report(&coin.id, e.into())?,
+        }
+    }
+    for requested in unlisted {
+        let interval = coin_interval(opts, requested, None).unwrap_or(interval);
+        match get_coin_data_any_case(url, requested, interval, opts).await {
+            Err(e) if !reachable && e.is_connect() => return Err(no_network(e)),
+            Err(FetchError::OfflineCacheMiss(_)) if opts.best_effort_offline => {
+                skip_uncached(requested)
+            }
+            Err(e @ FetchError::OfflineCacheMiss(_)) => report(requested, e.into())?,
+            Ok((id, Fetched { data, source })) => {
+                reachable = true;
+                let raw = opts.keep_raw.then(|| data.clone());
+                let data = until_window_end(data, opts.window);
+                let points = data.data.len();
+                match get_coin_info(data, &id).await {
+                    Ok(info) => emit(
+                        CoinInfo {
+                            raw,
+                            source: Some(source),
+                            ..info
+                        },
+                        points,
+                        interval,
+                    )?,
+                    Err(e) => report(requested, e)?,
+                }
+            }
+            // Only a 404 says the id is wrong; anything else is reported as is.
+            Err(e) if e.is_not_found() => report(
+                requested,
+                match closest_id(&coin_list, requested) {
+                    Some(id) => format!(
+                        "no coin with id or symbol '{}' ({}), did you mean '{}'?",
+                        requested, e, id
+                    ),
+                    None => format!("no coin with id or symbol '{}' ({})", requested, e),
+                }
+                .into(),
+            )?,
+            Err(e) => report(requested, e.into())?,
+        }
+    }
+    if !skipped.is_empty() {
+        eprintln!(
+            "Warning: skipped {} coin(s) missing from the cache",
+            skipped.len()
+        );
+    }
+    if none_listed && infos.is_empty() {
+        return Err("none of the requested coins were found".into());
+    }
+    Ok(RunReport {
+        infos,
+        skipped_offline: skipped,
+        failed,
+        currency: usd_per_unit
+            .and(opts.convert.as_ref())
+            .map(|code| code.to_uppercase()),
+    })
+}
+
+/// How the shared client keeps connections around between requests.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionOptions {
+    /// Idle connections are closed after this long.
+    pub idle_timeout: Duration,
+    /// At most this many idle connections are kept per host.
+    pub max_idle_per_host: usize,
+    /// How often an HTTP/2 connection is pinged to keep it alive.
+    pub keep_alive_interval: Duration,
+    /// How long a TCP connection sits idle before the OS starts probing
+    /// it; None leaves the OS's own setting.
+    pub tcp_keepalive: Option<Duration>,
+    /// Redirects followed per request before it fails.
+    pub max_redirects: usize,
+    /// Fail rather than follow a redirect to another host.
+    pub same_host_redirects: bool,
+}
+
+impl Default for ConnectionOptions {
+    fn default() -> Self {
+        ConnectionOptions {
+            idle_timeout: Duration::from_secs(90),
+            max_idle_per_host: DEFAULT_CONNECTIONS_PER_HOST,
+            keep_alive_interval: Duration::from_secs(30),
+            tcp_keepalive: Some(Duration::from_secs(60)),
+            max_redirects: 5,
+            same_host_redirects: false,
+        }
+    }
+}
+
+/// A client that sends `api_key` as a bearer token and gives up on requests
+/// that take longer than `timeout`. HTTP/2 is negotiated over TLS where the
+/// server offers it, so concurrent fetches share one connection.
+pub fn build_client(
+    api_key: Option<&str>,
+    timeout: Option<Duration>,
+    connection: &ConnectionOptions,
+) -> Result<reqwest::Client, Box<dyn std::error::Error>> {
+    let mut headers = reqwest::header::HeaderMap::new();
+    if let Some(key) = api_key {
+        let mut value = reqwest::header::HeaderValue::from_str(&format!("Bearer {}", key))?;
+        value.set_sensitive(true);
+        headers.insert(reqwest::header::AUTHORIZATION, value);
+    }
+    let mut builder = reqwest::Client::builder()
+        .default_headers(headers)
+        .pool_idle_timeout(connection.idle_timeout)
+        .pool_max_idle_per_host(connection.max_idle_per_host)
+        .tcp_keepalive(connection.tcp_keepalive)
+        .dns_resolver(Arc::new(Resolver))
+        .http2_keep_alive_interval(connection.keep_alive_interval)
+        .http2_keep_alive_while_idle(true)
+        .http2_adaptive_window(true)
+        .redirect(redirect_policy(connection));
+    if let Some(timeout) = timeout {
+        builder = builder.timeout(timeout);
+    }
+    Ok(builder.build()?)
+}
+
+fn redirect_policy(connection: &ConnectionOptions) -> reqwest::redirect::Policy {
+    let (max, same_host) = (connection.max_redirects, connection.same_host_redirects);
+    reqwest::redirect::Policy::custom(move |attempt| {
+        // previous() holds the original url and every redirect before this one.
+        if attempt.previous().len() > max {
+            let message = format!("more than {} redirects", max);
+            return attempt.error(message);
+        }
+        let from = attempt.previous()[0].host_str().map(str::to_string);
+        if same_host && attempt.url().host_str() != from.as_deref() {
+            let message = format!(
+                "redirected from {} to another host, {}",
+                from.unwrap_or_default(),
+                attempt.url()
+            );
+            return attempt.error(message);
+        }
+        attempt.follow()
+    })
+}
+
+// Fetches the smallest page of /assets and returns how long it took.
+pub async fn healthcheck(client: &reqwest::Client, url: &str) -> Result<Duration, reqwest::Error> {
+    let started = Instant::now();
+    client
+        .get(format!("{}/assets?limit=1", url))
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<CryptoList>()
+        .await?;
+    Ok(started.elapsed())
+}
+
+/// POSTs `metrics`, in the Prometheus text format, to the pushgateway at
+/// `gateway` under the `job` label.
+pub async fn push_metrics(
+    client: &reqwest::Client,
+    gateway: &str,
+    job: &str,
+    metrics: String,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut url = reqwest::Url::parse(gateway)
+        .map_err(|e| format!("'{}' isn't a pushgateway URL ({})", gateway, e))?;
+    url.path_segments_mut()
+        .map_err(|()| format!("'{}' isn't a pushgateway URL", gateway))?
+        .pop_if_empty()
+        .extend(["metrics", "job", job]);
+    client
+        .post(url.clone())
+        .header(reqwest::header::CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(metrics)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(|e| format!("pushing metrics to {} failed: {}", url, e))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httpmock::Method::{GET, POST};
+    use httpmock::MockServer;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    // A listing entry named after its id, with the id upper cased as its
+    // symbol.
+    fn listed(id: &str, rank: &str) -> Cryptocurrency {
+        Cryptocurrency {
+            id: id.to_string(),
+            rank: rank.to_string(),
+            symbol: id.to_uppercase(),
+            name: id.to_string(),
+            volumeUsd24Hr: None,
+            supply: None,
+            maxSupply: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_coins() {
+        let mock_server = MockServer::start();
+        let mock = mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/assets");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(
+                    r#"{
+                "data": [
+                    {
+                        "id": "bitcoin",
+                        "rank": "1",
+                        "symbol": "BTC",
+                        "name": "Bitcoin"
+                    },
+                    {
+                        "id": "ethereum",
+                        "rank": "2",
+                        "symbol": "ETH",
+                        "name": "Ethereum"
+                    }
+                ]
+            }"#,
+                );
+        });
+        let coin_list = get_coins(
+            &reqwest::Client::new(),
+            &mock_server.url("/v2"),
+            &Retry::default(),
+            &BodyLimits::default(),
+        )
+        .await;
+        match &coin_list {
+            Ok(_) => {}
+            Err(e) => println!("Error: {}", e),
+        }
+        assert!(coin_list.is_ok());
+        let coin_list = coin_list.unwrap();
+        assert_eq!(coin_list.data.len(), 2);
+        assert_eq!(coin_list.data[0].id, "bitcoin");
+        assert_eq!(coin_list.data[0].rank, "1");
+        assert_eq!(coin_list.data[0].symbol, "BTC");
+        assert_eq!(coin_list.data[0].name, "Bitcoin");
+        assert_eq!(coin_list.data[1].id, "ethereum");
+        assert_eq!(coin_list.data[1].rank, "2");
+        assert_eq!(coin_list.data[1].symbol, "ETH");
+        assert_eq!(coin_list.data[1].name, "Ethereum");
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_get_coin_data() {
+        let mock_server = MockServer::start();
+        let mock = mock_server.mock(|when, then| {
+            when.method(GET).path_contains("/v2/assets/bitcoin/history");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(
+                    r#"{
+                "data": [
+                    {
+                        "time": 1356998400000,
+                        "priceUsd": "13.8"
+                    },
+                    {
+                        "time": 1357084800000,
+                        "priceUsd": "13.98"
+                    }
+                ]
+            }"#,
+                );
+        });
+        let coin_data = get_coin_data(
+            &reqwest::Client::new(),
+            &mock_server.url("/v2"),
+            "bitcoin",
+            "d1",
+            Window::default(),
+            &Retry::default(),
+            &BodyLimits::default(),
+        )
+        .await;
+        assert!(coin_data.is_ok());
+        let coin_data = coin_data.unwrap();
+        assert_eq!(coin_data.data.len(), 2);
+        assert_eq!(coin_data.data[0].time, 1356998400000);
+        assert_eq!(coin_data.data[0].priceUsd, "13.8");
+        assert_eq!(coin_data.data[1].time, 1357084800000);
+        assert_eq!(coin_data.data[1].priceUsd, "13.98");
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_get_coin_data_rejects_malformed_json() {
+        let mock_server = MockServer::start();
+        let mock = mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/assets/bitcoin/history");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(
+                    r#"{
+                "data": [
+                    {
+                        "time": 1356998400000
+                        "priceUsd": "13.8",
+                    },
+                ]
+            }"#,
+                );
+        });
+        let err = get_coin_data(
+            &reqwest::Client::new(),
+            &mock_server.url("/v2"),
+            "bitcoin",
+            "d1",
+            Window::default(),
+            &Retry::default(),
+            &BodyLimits::default(),
+        )
+        .await
+        .unwrap_err();
+        // A syntax error, unlike a truncated body, isn't worth retrying.
+        assert!(matches!(&err, FetchError::Parse(e) if e.is_syntax()));
+        assert!(err.to_string().starts_with("unexpected response body"));
+        mock.assert_hits(1);
+    }
+
+    #[tokio::test]
+    async fn test_run_empty_coin_list() {
+        let mock_server = MockServer::start();
+        let mock = mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/assets");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{"data": []}"#);
+        });
+        let result = run(&mock_server.url("/v2"), &RunOptions::default(), &mut |_| {
+            Ok(())
+        })
+        .await;
+        assert!(result.is_err());
+        let err = result.unwrap_err();
+        assert!(err.to_string().contains("coin list is empty"));
+        mock.assert();
+    }
+
+    #[test]
+    fn test_jittered_delay_within_bounds() {
+        let mut rng = rand::thread_rng();
+        for attempt in 0..6 {
+            let max = backoff_delay(attempt);
+            let samples: Vec<Duration> = (0..1000)
+                .map(|_| jittered_delay(attempt, &mut rng))
+                .collect();
+            assert!(samples.iter().all(|d| *d <= max));
+            assert!(samples.iter().any(|d| *d != samples[0]));
+        }
+        assert_eq!(backoff_delay(0), Duration::from_millis(250));
+        assert_eq!(backoff_delay(30), Duration::from_millis(MAX_RETRY_DELAY_MS));
+    }
+
+    #[test]
+    fn test_resolve_symbol_prefers_higher_rank() {
+        let coin = |id: &str, rank: &str, symbol: &str| Cryptocurrency {
+            symbol: symbol.to_string(),
+            ..listed(id, rank)
+        };
+        let coin_list = CryptoList {
+            data: vec![
+                coin("bitcoin", "1", "BTC"),
+                coin("ethereum", "2", "ETH"),
+                coin("uniswap", "20", "UNI"),
+                coin("unicorn-token", "9", "UNI"),
+            ],
+        };
+        assert_eq!(resolve_symbol(&coin_list, "BTC"), Some("bitcoin"));
+        assert_eq!(resolve_symbol(&coin_list, "btc"), Some("bitcoin"));
+        assert_eq!(resolve_symbol(&coin_list, "UNI"), Some("unicorn-token"));
+        assert_eq!(resolve_symbol(&coin_list, "DOGE"), None);
+
+        let wanted = ["ETH".to_string(), "bitcoin".to_string()];
+        let selected: Vec<&str> = select_coins(&coin_list, &wanted)
+            .iter()
+            .map(|coin| coin.id.as_str())
+            .collect();
+        assert_eq!(selected, vec!["ethereum", "bitcoin"]);
+    }
+
+    #[test]
+    fn test_coverage_sparse_and_dense() {
+        let day = interval_millis("d1").unwrap();
+        let start = 1_000_000;
+        let end = start + 10 * day;
+        assert_eq!(coverage(10, start, end, "d1"), Some(1.0));
+        assert_eq!(coverage(2, start, end, "d1"), Some(0.2));
+        assert_eq!(coverage(12, start, end, "d1"), Some(1.0));
+        assert_eq!(coverage(2, start, end, "h1").map(|c| c < 0.01), Some(true));
+        assert_eq!(coverage(2, start, end, "w1"), None);
+        assert_eq!(coverage(2, end, start, "d1"), None);
+    }
+
+    #[tokio::test]
+    async fn test_get_candles() {
+        let mock_server = MockServer::start();
+        let mock = mock_server.mock(|when, then| {
+            when.method(GET)
+                .path("/v2/candles")
+                .query_param("exchange", "poloniex")
+                .query_param("interval", "d1")
+                .query_param("baseId", "ethereum")
+                .query_param("quoteId", "bitcoin");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(
+                    r#"{
+                "data": [
+                    {
+                        "open": "0.0741",
+                        "high": "0.0745",
+                        "low": "0.0732",
+                        "close": "0.0739",
+                        "volume": "1024.5",
+                        "period": 1530014400000
+                    }
+                ],
+                "timestamp": 1530032400000
+            }"#,
+                );
+        });
+        let candles = get_candles(
+            &reqwest::Client::new(),
+            &mock_server.url("/v2"),
+            "poloniex",
+            "d1",
+            "ethereum",
+            "bitcoin",
+            Window::default(),
+            &Retry::default(),
+            &BodyLimits::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            candles,
+            vec![Candle {
+                open: 0.0741,
+                high: 0.0745,
+                low: 0.0732,
+                close: 0.0739,
+                volume: 1024.5,
+                period: 1530014400000,
+            }]
+        );
+        assert_eq!(
+            candle_prices(&candles, PriceSource::Close).data[0].priceUsd,
+            "0.0739"
+        );
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_offline_cache_miss_makes_no_request() {
+        let mock_server = MockServer::start();
+        let mock = mock_server.mock(|when, then| {
+            when.method(GET).path_contains("/v2/assets/bitcoin/history");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{"data": []}"#);
+        });
+        let dir = tempfile::tempdir().unwrap();
+        let opts = RunOptions {
+            cache: Some(Cache::new(dir.path())),
+            offline: true,
+            ..Default::default()
+        };
+        let result = get_coin_data_cached(&mock_server.url("/v2"), "bitcoin", "d1", &opts).await;
+        assert!(matches!(result, Err(FetchError::OfflineCacheMiss(key)) if key == "bitcoin.d1"));
+        mock.assert_hits(0);
+    }
+
+    #[tokio::test]
+    async fn test_not_modified_history_uses_cached_body() {
+        let mock_server = MockServer::start();
+        let mut first = mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/assets/bitcoin/history");
+            then.status(200)
+                .header("content-type", "application/json")
+                .header("etag", "\"v1\"")
+                .body(r#"{"data": [{"priceUsd": "100", "time": 1}]}"#);
+        });
+        let dir = tempfile::tempdir().unwrap();
+        let opts = RunOptions {
+            cache: Some(Cache::new(dir.path())),
+            cache_ttl: Duration::ZERO,
+            ..Default::default()
+        };
+        let url = mock_server.url("/v2");
+        let fetched = get_coin_data_cached(&url, "bitcoin", "d1", &opts)
+            .await
+            .unwrap();
+        assert_eq!(fetched.source, Source::Network);
+        first.assert();
+        first.delete();
+        let cache = opts.cache.as_ref().unwrap();
+        assert_eq!(
+            cache.validators("bitcoin.d1").and_then(|v| v.etag),
+            Some("\"v1\"".to_string())
+        );
+
+        let not_modified = mock_server.mock(|when, then| {
+            when.method(GET)
+                .path("/v2/assets/bitcoin/history")
+                .header("if-none-match", "\"v1\"");
+            then.status(304);
+        });
+        let again = get_coin_data_cached(&url, "bitcoin", "d1", &opts)
+            .await
+            .unwrap();
+        not_modified.assert();
+        assert_eq!(again.source, Source::Cache);
+        assert_eq!(again.data, fetched.data);
+        assert_eq!(again.data.data[0].priceUsd, "100");
+    }
+
+    #[tokio::test]
+    async fn test_connect_failure_short_circuits_run() {
+        // Nothing listens on port 1, so every request is refused.
+        let url = "http://127.0.0.1:1";
+        let retry = || Retry {
+            max_attempts: 1,
+            ..Retry::new(Some(1))
+        };
+        let err = run(
+            url,
+            &RunOptions {
+                retry: retry(),
+                ..Default::default()
+            },
+            &mut |_| Ok(()),
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().starts_with("no network connectivity"));
+
+        // With the list cached, the first history fails instead and the
+        // remaining coins are never reported.
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::new(dir.path());
+        let list = CryptoList {
+            data: vec![
+                listed("bitcoin", "1"),
+                listed("ethereum", "2"),
+                listed("tether", "3"),
+            ],
+        };
+        cache.write(cache::ASSETS_KEY, &list).unwrap();
+        let opts = RunOptions {
+            cache: Some(cache),
+            cache_ttl: Duration::from_secs(3600),
+            retry: retry(),
+            concurrency: 1,
+            ..Default::default()
+        };
+        let mut seen = 0;
+        let err = run(url, &opts, &mut |_| {
+            seen += 1;
+            Ok(())
+        })
+        .await
+        .unwrap_err();
+        assert!(err.to_string().starts_with("no network connectivity"));
+        assert_eq!(seen, 0);
+    }
+
+    fn large_history(points: u64) -> CoinData {
+        CoinData {
+            data: (0..points)
+                .map(|i| PriceData {
+                    priceUsd: format!("{}.{}", 100 + (i * 7919) % 1000, i % 100),
+                    time: 1_000_000 + i * 60_000,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_history_stream_buffers_one_element() {
+        let mut data = large_history(10_000);
+        // A repeated timestamp mid-stream keeps its last price.
+        data.data.insert(
+            5_000,
+            PriceData {
+                priceUsd: "0.5".to_string(),
+                time: data.data[4_999].time,
+            },
+        );
+        let body = serde_json::to_vec(&data).unwrap();
+        let mut history = HistoryStream::default();
+        let chunk_size = 512;
+        for chunk in body.chunks(chunk_size) {
+            history.feed(chunk).unwrap();
+            // At most one chunk plus a partial element is ever held as text,
+            // and each point parsed costs only its price and time.
+            assert!(history.buf.len() < chunk_size + 64);
+            assert_eq!(history.stats.held_bytes(), history.stats.series.len() * 16);
+        }
+        let (stats, points) = history.finish().unwrap();
+        assert_eq!(points, 10_001);
+        assert_eq!(stats, PriceStats::from_prices(&dedupe_by_time(data.data)));
+
+        let mut truncated = HistoryStream::default();
+        truncated.feed(&body[..body.len() / 2]).unwrap();
+        assert!(truncated.finish().unwrap_err().is_eof());
+    }
+
+    #[tokio::test]
+    async fn test_streamed_history_buffer_is_capped() {
+        let mock_server = MockServer::start();
+        mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/assets/bitcoin/history");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(format!(r#"{{"data": [{{"priceUsd": "{}"#, "1".repeat(4096)));
+        });
+        let limits = BodyLimits {
+            max_bytes: 1024,
+            ..Default::default()
+        };
+        let url = mock_server.url("/v2/assets/bitcoin/history");
+        let err = get_price_stats_streamed(&reqwest::Client::new(), &url, &limits)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, FetchError::ResponseTooLarge { limit: 1024, .. }),
+            "{:?}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn test_streamed_history_is_capped_by_what_it_holds() {
+        let data = large_history(50_000);
+        let body = serde_json::to_string(&data).unwrap();
+        let mock_server = MockServer::start();
+        mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/assets/bitcoin/history");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(&body);
+        });
+        let url = mock_server.url("/v2/assets/bitcoin/history");
+        let fetch = |max_bytes: usize| {
+            let (client, url) = (reqwest::Client::new(), url.clone());
+            async move {
+                let limits = BodyLimits {
+                    max_bytes,
+                    ..Default::default()
+                };
+                get_price_stats_streamed(&client, &url, &limits).await
+            }
+        };
+        // The series is 16 bytes a point, well under the body's size, so a
+        // cap between the two only stops a history with more points.
+        let held = 50_000 * 16;
+        assert!(held * 2 < body.len());
+        let (stats, points) = fetch(held + 1024).await.unwrap();
+        assert_eq!((stats.count, points), (50_000, 50_000));
+        let err = fetch(held / 2).await.unwrap_err();
+        assert!(
+            matches!(err, FetchError::ResponseTooLarge { .. }),
+            "{:?}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn test_minute_history_is_streamed() {
+        let data = large_history(50_000);
+        let mock_server = MockServer::start();
+        let mock = mock_server.mock(|when, then| {
+            when.method(GET)
+                .path("/v2/assets/bitcoin/history")
+                .query_param("interval", "m1");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(serde_json::to_vec(&data).unwrap());
+        });
+        let opts = RunOptions {
+            window: Window {
+                start_ms: 0,
+                end_ms: MAX_HISTORY_POINTS * 60_000,
+            },
+            ..Default::default()
+        };
+        assert!(streams_history("m1", &opts));
+        let (stats, points, raw) = fetch_stats(&mock_server.url("/v2"), "bitcoin", "m1", &opts)
+            .await
+            .unwrap()
+            .data;
+        mock.assert();
+        assert_eq!(points, 50_000);
+        assert!(raw.is_none());
+        let expected = PriceStats::from_prices(&data.data);
+        assert_eq!(stats.min, expected.min);
+        assert_eq!(stats.max, expected.max);
+        assert_eq!(stats.count, expected.count);
+        assert_eq!(stats.mean(), expected.mean());
+    }
+
+    #[tokio::test]
+    async fn test_empty_history_retried_when_enabled() {
+        let mock_server = MockServer::start();
+        let path = "/v2/assets/bitcoin/history";
+        let empty = || {
+            mock_server.mock(|when, then| {
+                when.method(GET).path(path);
+                then.status(200)
+                    .header("content-type", "application/json")
+                    .body(r#"{"data": []}"#);
+            })
+        };
+        let url = mock_server.url("/v2");
+
+        let mut first = empty();
+        let data = fetch_history(&url, "bitcoin", "d1", &RunOptions::default())
+            .await
+            .unwrap()
+            .data;
+        assert!(data.data.is_empty());
+        first.assert_hits(1);
+        first.delete();
+
+        let opts = RunOptions {
+            retry_empty: true,
+            ..Default::default()
+        };
+        let second = empty();
+        // Swap in a populated history once the empty one has been served,
+        // while the fetch waits to retry.
+        let swap = async {
+            while second.hits_async().await < 1 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            second.delete_async().await;
+            mock_server
+                .mock_async(|when, then| {
+                    when.method(GET).path(path);
+                    then.status(200)
+                        .header("content-type", "application/json")
+                        .body(r#"{"data": [{"priceUsd": "100", "time": 1}]}"#);
+                })
+                .await
+        };
+        let (data, populated) = tokio::join!(fetch_history(&url, "bitcoin", "d1", &opts), swap);
+        assert_eq!(data.unwrap().data.data.len(), 1);
+        populated.assert_hits(1);
+    }
+
+    #[tokio::test]
+    async fn test_history_after_window_end_is_excluded() {
+        let mock_server = MockServer::start();
+        let mock = mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/assets/bitcoin/history");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(
+                    r#"{"data": [
+                        {"priceUsd": "10", "time": 1000},
+                        {"priceUsd": "20", "time": 2000},
+                        {"priceUsd": "99", "time": 3000}
+                    ]}"#,
+                );
+        });
+        let as_of = clock::FixedClock::at_millis(2000);
+        let opts = RunOptions {
+            window: Window {
+                start_ms: 0,
+                end_ms: millis_since_epoch(as_of.now()),
+            },
+            ..Default::default()
+        };
+        let (stats, points, _) = fetch_stats(&mock_server.url("/v2"), "bitcoin", "d1", &opts)
+            .await
+            .unwrap()
+            .data;
+        mock.assert();
+        assert_eq!(points, 2);
+        assert_eq!(stats.last, Some(20.0));
+        assert_eq!(stats.max, 20.0);
+    }
+
+    #[tokio::test]
+    async fn test_fixtures_match_the_http_path() {
+        let assets = r#"{"data": [{"id": "bitcoin", "rank": "1", "symbol": "BTC", "name": "Bitcoin", "volumeUsd24Hr": "1000"}]}"#;
+        let history = r#"{"data": [{"priceUsd": "10", "time": 1}, {"priceUsd": "30", "time": 2}, {"priceUsd": "20", "time": 3}]}"#;
+        let mock_server = MockServer::start();
+        let mocks = [
+            ("/v2/assets", assets),
+            ("/v2/assets/bitcoin/history", history),
+        ]
+        .map(|(path, body)| {
+            mock_server.mock(|when, then| {
+                when.method(GET).path(path);
+                then.status(200)
+                    .header("content-type", "application/json")
+                    .body(body);
+            })
+        });
+        let over_http = run(&mock_server.url("/v2"), &RunOptions::default(), &mut |_| {
+            Ok(())
+        })
+        .await
+        .unwrap()
+        .infos;
+        for mock in &mocks {
+            mock.assert();
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join(fixtures::ASSETS_FILE), assets).unwrap();
+        std::fs::write(
+            dir.path().join(fixtures::history_file("bitcoin", "d1")),
+            history,
+        )
+        .unwrap();
+        let opts = RunOptions {
+            fixtures: Some(FileProvider::new(dir.path())),
+            ..Default::default()
+        };
+        // Nothing listens here, so any request would fail the run.
+        let from_files = run("http://127.0.0.1:1", &opts, &mut |_| Ok(()))
+            .await
+            .unwrap()
+            .infos;
+        assert_eq!(
+            serde_json::to_value(&from_files).unwrap(),
+            serde_json::to_value(&over_http).unwrap()
+        );
+        assert_eq!(from_files[0].history, over_http[0].history);
+
+        let missing = FileProvider::new(dir.path())
+            .read::<CoinData>(&fixtures::history_file("ethereum", "d1"))
+            .unwrap_err();
+        assert!(matches!(missing, FetchError::Fixture(..)));
+    }
+
+    #[tokio::test]
+    async fn test_cache_entries_complete_when_run_is_cut_short() {
+        let mock_server = MockServer::start();
+        mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/assets");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(
+                    r#"{"data": [
+                        {"id": "bitcoin", "rank": "1", "symbol": "BTC", "name": "Bitcoin"},
+                        {"id": "ethereum", "rank": "2", "symbol": "ETH", "name": "Ethereum"}
+                    ]}"#,
+                );
+        });
+        let history = r#"{"data": [{"priceUsd": "10", "time": 1}]}"#;
+        mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/assets/bitcoin/history");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(history);
+        });
+        // Ethereum never answers in time, so the run is dropped mid-batch.
+        mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/assets/ethereum/history");
+            then.status(200)
+                .delay(Duration::from_secs(30))
+                .body(history);
+        });
+        let dir = tempfile::tempdir().unwrap();
+        let opts = RunOptions {
+            cache: Some(Cache::new(dir.path())),
+            cache_ttl: Duration::from_secs(3600),
+            concurrency: 2,
+            ..Default::default()
+        };
+        let url = mock_server.url("/v2");
+        let cut_short =
+            tokio::time::timeout(Duration::from_secs(1), run(&url, &opts, &mut |_| Ok(()))).await;
+        assert!(cut_short.is_err());
+
+        let cache = opts.cache.as_ref().unwrap();
+        let index = cache.index();
+        assert_eq!(
+            index.keys().collect::<Vec<_>>(),
+            vec![cache::ASSETS_KEY, "bitcoin.d1"]
+        );
+        let cached: CoinData = cache.read("bitcoin.d1").unwrap();
+        assert_eq!(cached.data[0].priceUsd, "10");
+        let leftovers = std::fs::read_dir(dir.path())
+            .unwrap()
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name();
+                name.to_string_lossy().ends_with(".tmp")
+            })
+            .count();
+        assert_eq!(leftovers, 0);
+    }
+
+    #[tokio::test]
+    async fn test_throttle_caps_download_rate() {
+        let body = serde_json::to_string(&CryptoList {
+            data: (0..1_000)
+                .map(|i| Cryptocurrency {
+                    id: format!("coin-{}", i),
+                    rank: i.to_string(),
+                    symbol: format!("C{}", i),
+                    name: format!("Coin number {}", i),
+                    volumeUsd24Hr: Some("1234567.89".to_string()),
+                    supply: None,
+                    maxSupply: None,
+                })
+                .collect(),
+        })
+        .unwrap();
+        let mock_server = MockServer::start();
+        mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/assets");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(&body);
+        });
+        let bytes_per_sec = body.len() as u64 * 2;
+        let limits = BodyLimits {
+            throttle: Some(Throttle::new(bytes_per_sec)),
+            ..Default::default()
+        };
+        let started = Instant::now();
+        let coins = get_coins(
+            &reqwest::Client::new(),
+            &mock_server.url("/v2"),
+            &Retry::default(),
+            &limits,
+        )
+        .await
+        .unwrap();
+        assert_eq!(coins.data.len(), 1_000);
+        // The whole body at twice its size per second takes half a second.
+        assert!(started.elapsed() >= Duration::from_millis(450));
+    }
+
+    #[test]
+    fn test_seeded_retry_backoff_is_reproducible() {
+        let sequence = |retry: Retry| -> Vec<Duration> {
+            (0..8).map(|attempt| retry.next_delay(attempt)).collect()
+        };
+        assert_eq!(
+            sequence(Retry::new(Some(42))),
+            sequence(Retry::new(Some(42)))
+        );
+        assert_ne!(
+            sequence(Retry::new(Some(42))),
+            sequence(Retry::new(Some(7)))
+        );
+    }
+
+    #[test]
+    fn test_normalize_volumes() {
+        let relative = normalize_volumes(&[Some(1e9), Some(1e6), Some(1e3), None, Some(0.0)]);
+        let expected = [Some(1.0), Some(0.5), Some(0.0), None, None];
+        for (actual, expected) in relative.iter().zip(expected) {
+            match (actual, expected) {
+                (Some(actual), Some(expected)) => assert!((actual - expected).abs() < 1e-9),
+                (actual, expected) => assert_eq!(*actual, expected),
+            }
+        }
+        assert_eq!(
+            normalize_volumes(&[Some(5.0), Some(5.0)]),
+            vec![Some(1.0), Some(1.0)]
+        );
+        assert_eq!(render::volume_fill(relative[0]), '█');
+        assert_eq!(render::volume_fill(relative[1]), '▓');
+        assert_eq!(render::volume_fill(relative[2]), '▒');
+        assert_eq!(render::volume_fill(None), '█');
+    }
+
+    #[tokio::test]
+    async fn test_offline_rejects_stale_coin_list() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::new(dir.path());
+        let coin_list = CryptoList { data: Vec::new() };
+        cache.write(cache::ASSETS_KEY, &coin_list).unwrap();
+        let day_ago = SystemTime::now() - Duration::from_secs(25 * 3600);
+        let day_ago = day_ago.duration_since(UNIX_EPOCH).unwrap().as_millis();
+        std::fs::write(
+            dir.path().join("index.json"),
+            format!(r#"{{"assets": {}}}"#, day_ago),
+        )
+        .unwrap();
+        let opts = RunOptions {
+            cache: Some(cache),
+            offline: true,
+            max_list_age: Some(Duration::from_secs(24 * 3600)),
+            ..Default::default()
+        };
+        let result = get_coins_cached("http://127.0.0.1:1", &opts).await;
+        match result {
+            Err(FetchError::StaleCache { key, age }) => {
+                assert_eq!(key, "assets");
+                assert!(age >= Duration::from_secs(25 * 3600));
+            }
+            other => panic!("expected a stale cache error, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_converts_prices_and_survives_rates_failure() {
+        let mock_server = MockServer::start();
+        let assets = mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/assets");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{"data": [{"id": "bitcoin", "rank": "1", "symbol": "BTC", "name": "Bitcoin"}]}"#);
+        });
+        let history = mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/assets/bitcoin/history");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{"data": [{"priceUsd": "10.0", "time": 1}, {"priceUsd": "20.0", "time": 2}]}"#);
+        });
+        let mut rates = mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/rates");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{"data": [{"id": "euro", "symbol": "EUR", "rateUsd": "2.0"}]}"#);
+        });
+        let opts = RunOptions {
+            convert: Some("eur".to_string()),
+            ..Default::default()
+        };
+        let infos = run(&mock_server.url("/v2"), &opts, &mut |_| Ok(()))
+            .await
+            .unwrap()
+            .infos;
+        assert_eq!(infos[0].current_price, 10.0);
+        assert_eq!(infos[0].all_time_low, 5.0);
+        rates.assert();
+
+        rates.delete();
+        let rates = mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/rates");
+            then.status(404);
+        });
+        let infos = run(&mock_server.url("/v2"), &opts, &mut |_| Ok(()))
+            .await
+            .unwrap()
+            .infos;
+        assert_eq!(infos[0].current_price, 20.0);
+        assert_eq!(infos[0].all_time_low, 10.0);
+        rates.assert();
+        assets.assert_hits(2);
+        history.assert_hits(2);
+    }
+
+    #[tokio::test]
+    async fn test_run_many_coins_with_mixed_outcomes() {
+        let mock_server = MockServer::start();
+        let assets = mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/assets");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(
+                    r#"{"data": [
+                        {"id": "bitcoin", "rank": "1", "symbol": "BTC", "name": "Bitcoin"},
+                        {"id": "ethereum", "rank": "2", "symbol": "ETH", "name": "Ethereum"},
+                        {"id": "tether", "rank": "3", "symbol": "USDT", "name": "Tether"},
+                        {"id": "solana", "rank": "4", "symbol": "SOL", "name": "Solana"},
+                        {"id": "dogecoin", "rank": "5", "symbol": "DOGE", "name": "Dogecoin"},
+                        {"id": "cardano", "rank": "6", "symbol": "ADA", "name": "Cardano"}
+                    ]}"#,
+                );
+        });
+        let history = |id: &str, delay_ms: u64, prices: &[&str]| {
+            let body = serde_json::to_string(&CoinData {
+                data: prices
+                    .iter()
+                    .enumerate()
+                    .map(|(i, price)| PriceData {
+                        priceUsd: price.to_string(),
+                        time: i as u64,
+                    })
+                    .collect(),
+            })
+            .unwrap();
+            mock_server.mock(|when, then| {
+                when.method(GET).path(format!("/v2/assets/{}/history", id));
+                then.status(200)
+                    .header("content-type", "application/json")
+                    .delay(Duration::from_millis(delay_ms))
+                    .body(body);
+            })
+        };
+        // The first coin answers last, so list order has to be restored.
+        let bitcoin = history("bitcoin", 300, &["100", "300", "200"]);
+        let tether = history("tether", 0, &["1.0", "1.0"]);
+        let cardano = history("cardano", 100, &["0.5"]);
+        // Dogecoin's history has no usable prices at all.
+        let dogecoin = history("dogecoin", 0, &["n/a"]);
+        let ethereum = mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/assets/ethereum/history");
+            then.status(500);
+        });
+        let solana = mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/assets/solana/history");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{"error": "unknown"}"#);
+        });
+
+        let opts = RunOptions {
+            retry: Retry {
+                max_attempts: 1,
+                ..Retry::new(Some(1))
+            },
+            concurrency: 4,
+            ..Default::default()
+        };
+        let mut seen = Vec::new();
+        let report = run(&mock_server.url("/v2"), &opts, &mut |info| {
+            seen.push(info.name.clone());
+            Ok(())
+        })
+        .await
+        .unwrap();
+        // The failures are reported in list order too.
+        assert_eq!(report.failed, ["ethereum", "solana", "dogecoin"]);
+        assert!(report.skipped_offline.is_empty());
+        let infos = report.infos;
+
+        let names: Vec<&str> = infos.iter().map(|info| info.name.as_str()).collect();
+        assert_eq!(names, ["bitcoin", "tether", "cardano"]);
+        assert_eq!(seen, names);
+        assert_eq!(infos[0].all_time_high, 300.0);
+        assert_eq!(infos[0].current_price, 200.0);
+        assert_eq!(infos[0].rank, Some(1));
+        assert_eq!(infos[1].points, 2);
+        assert_eq!(infos[2].rank, Some(6));
+        assets.assert();
+        for mock in [bitcoin, tether, cardano, dogecoin, ethereum, solana] {
+            mock.assert();
+        }
+    }
+
+    #[test]
+    fn test_auto_interval_targets_point_count() {
+        let day = 24 * 60 * 60 * 1000;
+        // m5 gives 288 points a day, the nearest to 500
+        assert_eq!(auto_interval(0, day, TARGET_POINTS), "m5");
+        // d1 gives 730 points over two years, h12 twice that
+        assert_eq!(auto_interval(0, 730 * day, TARGET_POINTS), "d1");
+        assert_eq!(expected_points(0, day, "m5"), Some(288));
+    }
+
+    #[tokio::test]
+    async fn test_unlisted_id_retries_in_lowercase() {
+        let mock_server = MockServer::start();
+        mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/assets");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{"data": [{"id": "ethereum", "rank": "2", "symbol": "ETH", "name": "Ethereum"}]}"#);
+        });
+        // httpmock compares paths case-insensitively, so the two ids are
+        // told apart by hand.
+        let upper = mock_server.mock(|when, then| {
+            when.method(GET)
+                .matches(|req| req.path == "/v2/assets/Bitcoin/history");
+            then.status(404);
+        });
+        let lower = mock_server.mock(|when, then| {
+            when.method(GET)
+                .matches(|req| req.path == "/v2/assets/bitcoin/history");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{"data": [{"priceUsd": "10.0", "time": 1}, {"priceUsd": "20.0", "time": 2}]}"#);
+        });
+        let opts = RunOptions {
+            coins: vec!["Bitcoin".to_string()],
+            ..Default::default()
+        };
+        let infos = run(&mock_server.url("/v2"), &opts, &mut |_| Ok(()))
+            .await
+            .unwrap()
+            .infos;
+        assert_eq!(infos.len(), 1);
+        assert_eq!(infos[0].name, "bitcoin");
+        upper.assert();
+        lower.assert();
+    }
+
+    #[tokio::test]
+    async fn test_only_a_missing_id_is_called_unknown() {
+        let mock_server = MockServer::start();
+        mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/assets");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{"data": [{"id": "ethereum", "rank": "2", "symbol": "ETH", "name": "Ethereum"}]}"#);
+        });
+        mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/assets/etherum/history");
+            then.status(404);
+        });
+        mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/assets/solana/history");
+            then.status(503);
+        });
+        let error = |coin: &str| {
+            let opts = RunOptions {
+                coins: vec![coin.to_string()],
+                fail_fast: true,
+                retry: Retry {
+                    max_attempts: 1,
+                    ..Retry::new(Some(1))
+                },
+                ..Default::default()
+            };
+            let url = mock_server.url("/v2");
+            async move {
+                run(&url, &opts, &mut |_| Ok(()))
+                    .await
+                    .unwrap_err()
+                    .to_string()
+            }
+        };
+        let missing = error("etherum").await;
+        assert!(
+            missing.starts_with("no coin with id or symbol 'etherum'"),
+            "{}",
+            missing
+        );
+        assert!(missing.ends_with("did you mean 'ethereum'?"), "{}", missing);
+        let outage = error("solana").await;
+        assert!(!outage.contains("no coin"), "{}", outage);
+        assert!(outage.contains("503"), "{}", outage);
+    }
+
+    #[test]
+    fn test_closest_id_suggests_typo_fix() {
+        let coin_list = CryptoList {
+            data: vec![
+                listed("bitcoin", "1"),
+                listed("ethereum", "2"),
+                listed("tether", "3"),
+            ],
+        };
+        assert_eq!(edit_distance("etherum", "ethereum"), 1);
+        assert_eq!(closest_id(&coin_list, "Etherum"), Some("ethereum"));
+        assert_eq!(closest_id(&coin_list, "dogecoin"), None);
+    }
+
+    #[tokio::test]
+    async fn test_build_client_sends_api_key() {
+        let mock_server = MockServer::start();
+        let mock = mock_server.mock(|when, then| {
+            when.method(GET)
+                .path("/v2/assets")
+                .header("authorization", "Bearer secret");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{"data": []}"#);
+        });
+        let client = build_client(
+            Some("secret"),
+            Some(Duration::from_secs(5)),
+            &ConnectionOptions::default(),
+        )
+        .unwrap();
+        get_coins(
+            &client,
+            &mock_server.url("/v2"),
+            &Retry::default(),
+            &BodyLimits::default(),
+        )
+        .await
+        .unwrap();
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_truncated_body_is_a_retryable_parse_error() {
+        let mock_server = MockServer::start();
+        let mock = mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/assets");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{"data": [{"id": "bitcoin", "rank": "1", "sym"#);
+        });
+        let err = get_coins(
+            &reqwest::Client::new(),
+            &mock_server.url("/v2"),
+            &Retry::new(Some(1)),
+            &BodyLimits::default(),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(&err, FetchError::Parse(e) if e.is_eof()));
+        assert!(err.is_retryable());
+        mock.assert_hits(MAX_ATTEMPTS as usize);
+
+        let wrong_shape = serde_json::from_str::<CryptoList>(r#"{"data": 1}"#).unwrap_err();
+        assert!(!FetchError::Parse(wrong_shape).is_retryable());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_reports_cache_hit_and_miss() {
+        let mock_server = MockServer::start();
+        let mock = mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/assets/bitcoin/history");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{"data": [{"priceUsd": "100", "time": 1}]}"#);
+        });
+        let dir = tempfile::tempdir().unwrap();
+        let opts = RunOptions {
+            cache: Some(Cache::new(dir.path())),
+            cache_ttl: Duration::from_secs(60),
+            ..Default::default()
+        };
+        let url = mock_server.url("/v2");
+        let fetch = || async {
+            fetch_stats(&url, "bitcoin", "d1", &opts)
+                .await
+                .unwrap()
+                .source
+        };
+        assert_eq!(fetch().await, Source::Network);
+        assert_eq!(fetch().await, Source::Cache);
+        mock.assert_hits(1);
+
+        let info = |source| CoinInfo {
+            source: Some(source),
+            ..Default::default()
+        };
+        let infos = [
+            info(Source::Network),
+            info(Source::Cache),
+            info(Source::Cache),
+            info(Source::Network),
+        ];
+        assert_eq!(cache_hit_rate(&infos), Some(0.5));
+        assert_eq!(cache_hit_rate(&[CoinInfo::default()]), None);
+    }
+
+    #[tokio::test]
+    async fn test_push_metrics_posts_to_job() {
+        let mock_server = MockServer::start();
+        let body = "# TYPE coin_price gauge\ncoin_price{coin=\"bitcoin\"} 100\n";
+        let mock = mock_server.mock(|when, then| {
+            when.method(POST)
+                .path("/metrics/job/coin_data")
+                .header("content-type", "text/plain; version=0.0.4")
+                .body(body);
+            then.status(200);
+        });
+        let client = reqwest::Client::new();
+        push_metrics(
+            &client,
+            &mock_server.base_url(),
+            "coin_data",
+            body.to_string(),
+        )
+        .await
+        .unwrap();
+        mock.assert();
+
+        let failing = mock_server.mock(|when, then| {
+            when.method(POST).path("/metrics/job/broken");
+            then.status(500);
+        });
+        let e = push_metrics(&client, &mock_server.base_url(), "broken", body.to_string())
+            .await
+            .unwrap_err();
+        failing.assert();
+        assert!(e.to_string().starts_with("pushing metrics to "), "{}", e);
+        assert!(e.to_string().contains("500"), "{}", e);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_body_rejected() {
+        let body = format!(
+            r#"{{"data": [{}]}}"#,
+            r#"{"priceUsd": "1", "time": 1},"#.repeat(100)
+        );
+        let mock_server = MockServer::start();
+        mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/assets/bitcoin/history");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(&body);
+        });
+        let limits = BodyLimits {
+            max_bytes: 1024,
+            ..Default::default()
+        };
+        let err = get_coin_data(
+            &reqwest::Client::new(),
+            &mock_server.url("/v2"),
+            "bitcoin",
+            "d1",
+            Window::default(),
+            &Retry::default(),
+            &limits,
+        )
+        .await
+        .unwrap_err();
+        assert!(
+            matches!(err, FetchError::ResponseTooLarge { limit: 1024, .. }),
+            "{:?}",
+            err
+        );
+        assert!(!err.is_retryable());
+    }
+
+    #[tokio::test]
+    async fn test_config_interval_overrides_global() {
+        let mock_server = MockServer::start();
+        mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/assets");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{"data": [{"id": "bitcoin", "rank": "1", "symbol": "BTC", "name": "Bitcoin"}, {"id": "pepe", "rank": "2", "symbol": "PEPE", "name": "Pepe"}]}"#);
+        });
+        let history = |id: &str, interval: &str| {
+            mock_server.mock(|when, then| {
+                when.method(GET)
+                    .path(format!("/v2/assets/{}/history", id))
+                    .query_param("interval", interval);
+                then.status(200)
+                    .header("content-type", "application/json")
+                    .body(r#"{"data": [{"priceUsd": "1.0", "time": 1}, {"priceUsd": "2.0", "time": 2}]}"#);
+            })
+        };
+        let bitcoin = history("bitcoin", INTERVAL);
+        let pepe = history("pepe", "h1");
+        let config = config::Config::parse("[intervals]\npepe = \"h1\"\n").unwrap();
+        let opts = RunOptions {
+            intervals: config.intervals,
+            // Short enough for one h1 request.
+            window: Window {
+                start_ms: 0,
+                end_ms: 30 * 24 * 60 * 60 * 1000,
+            },
+            ..Default::default()
+        };
+        let infos = run(&mock_server.url("/v2"), &opts, &mut |_| Ok(()))
+            .await
+            .unwrap()
+            .infos;
+        assert_eq!(infos.len(), 2);
+        bitcoin.assert();
+        pepe.assert();
+    }
+
+    #[tokio::test]
+    async fn test_best_effort_offline_skips_uncached_coins() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::new(dir.path());
+        let list = CryptoList {
+            data: vec![
+                listed("bitcoin", "1"),
+                listed("ethereum", "2"),
+                listed("tether", "3"),
+            ],
+        };
+        cache.write(cache::ASSETS_KEY, &list).unwrap();
+        let history: CoinData = serde_json::from_str(
+            r#"{"data": [{"priceUsd": "1", "time": 1}, {"priceUsd": "2", "time": 2}]}"#,
+        )
+        .unwrap();
+        for id in ["bitcoin", "tether"] {
+            cache
+                .write(&cache::history_key(id, INTERVAL), &history)
+                .unwrap();
+        }
+        let mut opts = RunOptions {
+            cache: Some(cache),
+            offline: true,
+            best_effort_offline: true,
+            ..Default::default()
+        };
+        // Nothing listens here, so any request would fail.
+        let url = "http://127.0.0.1:1";
+        let report = run(url, &opts, &mut |_| Ok(())).await.unwrap();
+        let names: Vec<&str> = report.infos.iter().map(|info| info.name.as_str()).collect();
+        assert_eq!(names, ["bitcoin", "tether"]);
+        assert_eq!(report.skipped_offline, ["ethereum"]);
+        assert!(report.failed.is_empty());
+
+        // Without it the miss is reported like any other error, and the
+        // cached coins are still shown.
+        opts.best_effort_offline = false;
+        let report = run(url, &opts, &mut |_| Ok(())).await.unwrap();
+        assert_eq!(report.infos.len(), 2);
+        assert!(report.skipped_offline.is_empty());
+        assert_eq!(report.failed, ["ethereum"]);
+
+        opts.fail_fast = true;
+        let err = run(url, &opts, &mut |_| Ok(())).await.unwrap_err();
+        assert!(err.to_string().contains("ethereum.d1"), "{}", err);
+    }
+
+    #[test]
+    fn test_supply_percent_capped_and_uncapped() {
+        let list: CryptoList = serde_json::from_str(
+            r#"{"data": [
+                {"id": "bitcoin", "rank": "1", "symbol": "BTC", "name": "Bitcoin", "supply": "19500000.0", "maxSupply": "21000000.0"},
+                {"id": "ethereum", "rank": "2", "symbol": "ETH", "name": "Ethereum", "supply": "120000000.0", "maxSupply": null}
+            ]}"#,
+        )
+        .unwrap();
+        let percent = |coin: &Cryptocurrency| {
+            let supply = parse_price(coin.supply.as_deref()?)?;
+            supply_percent(supply, coin.maxSupply.as_deref().and_then(parse_price))
+        };
+        let bitcoin = percent(&list.data[0]).unwrap();
+        assert!((bitcoin - 92.857).abs() < 1e-3, "{}", bitcoin);
+        assert_eq!(percent(&list.data[1]), None);
+        assert_eq!(supply_percent(100.0, Some(0.0)), None);
+    }
+
+    #[tokio::test]
+    async fn test_dns_failure_is_retryable() {
+        struct NoSuchHost;
+        impl reqwest::dns::Resolve for NoSuchHost {
+            fn resolve(&self, _: hyper::client::connect::dns::Name) -> reqwest::dns::Resolving {
+                let e = io::Error::new(io::ErrorKind::NotFound, "no such host");
+                Box::pin(async move { Err(Box::new(DnsError(e)) as _) })
+            }
+        }
+        let client = reqwest::Client::builder()
+            .dns_resolver(Arc::new(NoSuchHost))
+            .build()
+            .unwrap();
+        let e = client
+            .get("http://coin-data.test/v2/assets")
+            .send()
+            .await
+            .unwrap_err();
+        assert!(is_dns_error(&e), "{:?}", e);
+        assert!(FetchError::Http(e).is_retryable());
+
+        // A refused connection got past the lookup.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let e = client
+            .get(format!("http://{}/v2/assets", addr))
+            .send()
+            .await
+            .unwrap_err();
+        assert!(e.is_connect() && !is_dns_error(&e), "{:?}", e);
+    }
+
+    #[test]
+    fn test_history_cache_key_covers_the_window() {
+        let key = |start_ms, end_ms| {
+            let opts = RunOptions {
+                window: Window { start_ms, end_ms },
+                ..Default::default()
+            };
+            history_cache_key("bitcoin", "d1", &opts)
+        };
+        assert_eq!(
+            history_cache_key("bitcoin", "d1", &RunOptions::default()),
+            "bitcoin.d1"
+        );
+        assert_eq!(key(1000, 5000), "bitcoin.d1.1000-5000");
+        assert_ne!(key(1000, 5000), key(2000, 6000));
+    }
+
+    #[tokio::test]
+    async fn test_incremental_fetch_appends_to_cached_history() {
+        let mock_server = MockServer::start();
+        let history = |points: &[(u64, &str)]| CoinData {
+            data: points
+                .iter()
+                .map(|(time, price)| PriceData {
+                    priceUsd: price.to_string(),
+                    time: *time,
+                })
+                .collect(),
+        };
+        let tail = mock_server.mock(|when, then| {
+            when.method(GET)
+                .path("/v2/assets/bitcoin/history")
+                .query_param("start", "3000")
+                .query_param("end", "10000");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(
+                    serde_json::to_string(&history(&[(3000, "31"), (4000, "40"), (5000, "50")]))
+                        .unwrap(),
+                );
+        });
+        let dir = tempfile::tempdir().unwrap();
+        let opts = RunOptions {
+            cache: Some(Cache::new(dir.path())),
+            cache_ttl: Duration::ZERO,
+            window: Window {
+                start_ms: 1500,
+                end_ms: 10_000,
+            },
+            incremental: true,
+            ..Default::default()
+        };
+        let key = history_cache_key("bitcoin", "d1", &opts);
+        let cache = opts.cache.as_ref().unwrap();
+        cache
+            .write(&key, &history(&[(1000, "10"), (2000, "20"), (3000, "30")]))
+            .unwrap();
+
+        let fetched = get_coin_data_cached(&mock_server.url("/v2"), "bitcoin", "d1", &opts)
+            .await
+            .unwrap();
+        tail.assert();
+        let expected = history(&[(2000, "20"), (3000, "31"), (4000, "40"), (5000, "50")]);
+        assert_eq!(fetched.data, expected);
+        assert_eq!(cache.read::<CoinData>(&key), Some(expected));
+        // The next refresh's window has moved on, but finds the same entry.
+        let later = RunOptions {
+            window: Window {
+                start_ms: 2500,
+                end_ms: 11_000,
+            },
+            ..opts
+        };
+        assert_eq!(history_cache_key("bitcoin", "d1", &later), key);
+    }
+
+    #[tokio::test]
+    async fn test_tuned_client_reuses_pool() {
+        let mock_server = MockServer::start();
+        let mock = mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/assets");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{"data": []}"#);
+        });
+        let connection = ConnectionOptions {
+            idle_timeout: Duration::from_secs(1),
+            max_idle_per_host: 1,
+            keep_alive_interval: Duration::from_secs(1),
+            tcp_keepalive: Some(Duration::from_secs(15)),
+            ..ConnectionOptions::default()
+        };
+        let client = build_client(None, Some(Duration::from_secs(5)), &connection).unwrap();
+        let url = mock_server.url("/v2");
+        let (retry, limits) = (Retry::default(), BodyLimits::default());
+        let fetch = || get_coins(&client, &url, &retry, &limits);
+        let results = futures::future::join_all((0..4).map(|_| fetch())).await;
+        assert!(results.iter().all(Result::is_ok));
+        // Again over whatever connection the pool kept.
+        fetch().await.unwrap();
+        mock.assert_hits(5);
+    }
+
+    #[tokio::test]
+    async fn test_run_uses_injected_client() {
+        let mock_server = MockServer::start();
+        let assets = mock_server.mock(|when, then| {
+            when.method(GET)
+                .path("/v2/assets")
+                .header("x-client", "injected");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{"data": [{"id": "bitcoin", "rank": "1", "symbol": "BTC", "name": "Bitcoin"}]}"#);
+        });
+        let history = mock_server.mock(|when, then| {
+            when.method(GET)
+                .path("/v2/assets/bitcoin/history")
+                .header("x-client", "injected");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{"data": [{"priceUsd": "10.0", "time": 1}, {"priceUsd": "20.0", "time": 2}]}"#);
+        });
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert("x-client", "injected".parse().unwrap());
+        let opts = RunOptions {
+            client: reqwest::Client::builder()
+                .default_headers(headers)
+                .build()
+                .unwrap(),
+            ..Default::default()
+        };
+        let infos = run(&mock_server.url("/v2"), &opts, &mut |_| Ok(()))
+            .await
+            .unwrap()
+            .infos;
+        assert_eq!(infos[0].current_price, 20.0);
+        assets.assert();
+        history.assert();
+    }
+
+    // Collects what a fmt subscriber writes, for asserting on spans.
+    #[derive(Clone, Default)]
+    struct Captured(std::sync::Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch_is_traced_with_coin_interval_and_url() {
+        let mock_server = MockServer::start();
+        mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/assets/bitcoin/history");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{"data": [{"priceUsd": "10.0", "time": 1}]}"#);
+        });
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_ansi(false)
+            .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let url = mock_server.url("/v2");
+        get_coin_data(
+            &reqwest::Client::new(),
+            &url,
+            "bitcoin",
+            "d1",
+            Window::default(),
+            &Retry::default(),
+            &BodyLimits::default(),
+        )
+        .await
+        .unwrap();
+        let logs = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        let span = format!(
+            "get_coin_data{{coin=bitcoin interval=d1 url={}}}",
+            history_url(&url, "bitcoin", "d1", Window::default())
+        );
+        assert!(
+            logs.lines()
+                .any(|line| line.contains(&span) && line.contains(" close ")),
+            "{}",
+            logs
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fail_fast_stops_at_first_error() {
+        let mock_server = MockServer::start();
+        mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/assets");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(
+                    r#"{"data": [
+                        {"id": "bitcoin", "rank": "1", "symbol": "BTC", "name": "Bitcoin"},
+                        {"id": "ethereum", "rank": "2", "symbol": "ETH", "name": "Ethereum"}
+                    ]}"#,
+                );
+        });
+        let bitcoin = mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/assets/bitcoin/history");
+            then.status(404);
+        });
+        let ethereum = mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/assets/ethereum/history");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{"data": [{"priceUsd": "10.0", "time": 1}]}"#);
+        });
+        let url = mock_server.url("/v2");
+        let mut opts = RunOptions {
+            fail_fast: true,
+            concurrency: 1,
+            ..Default::default()
+        };
+        assert!(run(&url, &opts, &mut |_| Ok(())).await.is_err());
+        bitcoin.assert_hits(1);
+        ethereum.assert_hits(0);
+
+        opts.fail_fast = false;
+        let infos = run(&url, &opts, &mut |_| Ok(())).await.unwrap().infos;
+        assert_eq!(infos.len(), 1);
+        assert_eq!(infos[0].name, "ethereum");
+        bitcoin.assert_hits(2);
+    }
+
+    #[tokio::test]
+    async fn test_redirects_are_capped() {
+        let mock_server = MockServer::start();
+        // /r3 -> /r2 -> /r1 -> /r0, which answers.
+        for hop in 1..=3 {
+            let location = mock_server.url(format!("/r{}", hop - 1));
+            mock_server.mock(|when, then| {
+                when.method(GET).path(format!("/r{}", hop));
+                then.status(302).header("location", location.as_str());
+            });
+        }
+        mock_server.mock(|when, then| {
+            when.method(GET).path("/r0");
+            then.status(200).body("ok");
+        });
+        let client = |connection: ConnectionOptions| build_client(None, None, &connection).unwrap();
+        let capped = client(ConnectionOptions {
+            max_redirects: 2,
+            ..ConnectionOptions::default()
+        });
+        let body = capped.get(mock_server.url("/r2")).send().await.unwrap();
+        assert_eq!(body.text().await.unwrap(), "ok");
+        let e = capped.get(mock_server.url("/r3")).send().await.unwrap_err();
+        assert!(e.is_redirect(), "{:?}", e);
+        let e = FetchError::from(e);
+        assert!(!e.is_retryable());
+        assert!(e.to_string().ends_with(": more than 2 redirects"), "{}", e);
+
+        let elsewhere = mock_server.url("/r0").replace("127.0.0.1", "localhost");
+        mock_server.mock(|when, then| {
+            when.method(GET).path("/away");
+            then.status(302).header("location", elsewhere.as_str());
+        });
+        let same_host = client(ConnectionOptions {
+            same_host_redirects: true,
+            ..ConnectionOptions::default()
+        });
+        let e = same_host
+            .get(mock_server.url("/away"))
+            .send()
+            .await
+            .unwrap_err();
+        assert!(e.is_redirect(), "{:?}", e);
+        assert!(same_host.get(mock_server.url("/r1")).send().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_coins_regex_filters_by_symbol() {
+        let mock_server = MockServer::start();
+        mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/assets");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(
+                    r#"{"data": [
+                        {"id": "tether", "rank": "3", "symbol": "USDT", "name": "Tether"},
+                        {"id": "bitcoin", "rank": "1", "symbol": "BTC", "name": "Bitcoin"},
+                        {"id": "usd-coin", "rank": "5", "symbol": "USDC", "name": "USD Coin"},
+                        {"id": "binance-usd", "rank": "9", "symbol": "BUSD", "name": "Binance USD"}
+                    ]}"#,
+                );
+        });
+        let histories = mock_server.mock(|when, then| {
+            when.method(GET).path_contains("/history");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{"data": [{"priceUsd": "1.0", "time": 1}]}"#);
+        });
+        let url = mock_server.url("/v2");
+        let names = |infos: Vec<CoinInfo>| -> Vec<String> {
+            infos.into_iter().map(|info| info.name).collect()
+        };
+        let mut opts = RunOptions {
+            coins_regex: Some(regex::Regex::new("^USD").unwrap()),
+            ..Default::default()
+        };
+        let infos = run(&url, &opts, &mut |_| Ok(())).await.unwrap().infos;
+        assert_eq!(names(infos), ["tether", "usd-coin"]);
+        histories.assert_hits(2);
+
+        opts.coins_regex = Some(regex::Regex::new("^Bi").unwrap());
+        opts.regex_matches_names = true;
+        let infos = run(&url, &opts, &mut |_| Ok(())).await.unwrap().infos;
+        assert_eq!(names(infos), ["bitcoin", "binance-usd"]);
+    }
+
+    #[tokio::test]
+    async fn test_category_filters_before_fetching() {
+        let mock_server = MockServer::start();
+        mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/assets");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(
+                    r#"{"data": [
+                        {"id": "bitcoin", "rank": "1", "symbol": "BTC", "name": "Bitcoin"},
+                        {"id": "uniswap", "rank": "20", "symbol": "UNI", "name": "Uniswap"}
+                    ]}"#,
+                );
+        });
+        let histories = mock_server.mock(|when, then| {
+            when.method(GET).path_contains("/history");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{"data": [{"priceUsd": "1.0", "time": 1}]}"#);
+        });
+        let opts = RunOptions {
+            category: Some("defi".to_string()),
+            ..Default::default()
+        };
+        let infos = run(&mock_server.url("/v2"), &opts, &mut |_| Ok(()))
+            .await
+            .unwrap()
+            .infos;
+        let names: Vec<String> = infos.into_iter().map(|info| info.name).collect();
+        assert_eq!(names, ["uniswap"]);
+        histories.assert_hits(1);
+    }
+
+    #[tokio::test]
+    async fn test_host_limit_serializes_requests() {
+        let mock_server = MockServer::start();
+        let delay = Duration::from_millis(100);
+        let mock = mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/assets");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{"data": []}"#)
+                .delay(delay);
+        });
+        let client = build_client(None, None, &ConnectionOptions::default()).unwrap();
+        let (url, retry) = (mock_server.url("/v2"), Retry::default());
+        let limits = BodyLimits {
+            per_host: Some(HostLimit::new(1)),
+            ..BodyLimits::default()
+        };
+        let started = Instant::now();
+        let results =
+            futures::future::join_all((0..3).map(|_| get_coins(&client, &url, &retry, &limits)))
+                .await;
+        assert!(results.iter().all(Result::is_ok));
+        // One at a time, so each waits for the one before it.
+        assert!(started.elapsed() >= 3 * delay, "{:?}", started.elapsed());
+        mock.assert_hits(3);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_stops_further_fetches() {
+        let mock_server = MockServer::start();
+        mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/assets");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(
+                    r#"{"data": [
+                        {"id": "bitcoin", "rank": "1", "symbol": "BTC", "name": "Bitcoin"},
+                        {"id": "ethereum", "rank": "2", "symbol": "ETH", "name": "Ethereum"}
+                    ]}"#,
+                );
+        });
+        let history = r#"{"data": [{"priceUsd": "10", "time": 1}]}"#;
+        let bitcoin = mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/assets/bitcoin/history");
+            then.status(200)
+                .header("content-type", "application/json")
+                .delay(Duration::from_secs(5))
+                .body(history);
+        });
+        let ethereum = mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/assets/ethereum/history");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(history);
+        });
+        let opts = RunOptions {
+            concurrency: 1,
+            ..Default::default()
+        };
+        let cancel = opts.cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            cancel.cancel();
+        });
+        let started = Instant::now();
+        let result = run(&mock_server.url("/v2"), &opts, &mut |_| Ok(())).await;
+        assert!(result.unwrap_err().is::<Cancelled>());
+        assert!(started.elapsed() < Duration::from_secs(2));
+        bitcoin.assert_hits(1);
+        ethereum.assert_hits(0);
+    }
+
+    #[tokio::test]
+    async fn test_price_source_drives_candle_stats() {
+        let mock_server = MockServer::start();
+        mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/assets");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{"data": [{"id": "ethereum", "rank": "2", "symbol": "ETH", "name": "Ethereum"}]}"#);
+        });
+        mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/candles");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(
+                    r#"{"data": [
+                        {"open": "10", "high": "15", "low": "8", "close": "12", "volume": "1", "period": 1},
+                        {"open": "12", "high": "20", "low": "11", "close": "18", "volume": "1", "period": 2}
+                    ]}"#,
+                );
+        });
+        let url = &mock_server.url("/v2");
+        let stats = |price| async move {
+            let opts = RunOptions {
+                candles: Some(CandleSource {
+                    exchange: "binance".to_string(),
+                    quote_id: "tether".to_string(),
+                    price,
+                }),
+                ..Default::default()
+            };
+            let infos = run(url, &opts, &mut |_| Ok(())).await.unwrap().infos;
+            let info = &infos[0];
+            (info.all_time_low, info.all_time_high, info.current_price)
+        };
+        assert_eq!(stats(PriceSource::Close).await, (12.0, 18.0, 18.0));
+        assert_eq!(stats(PriceSource::Open).await, (10.0, 12.0, 12.0));
+        assert_eq!(stats(PriceSource::High).await, (15.0, 20.0, 20.0));
+        assert_eq!(stats(PriceSource::Low).await, (8.0, 11.0, 11.0));
+    }
+
+    #[tokio::test]
+    async fn test_rates_without_a_usable_rate_are_skipped() {
+        let rates = r#"{"data": [
+            {"id": "british-pound-sterling", "symbol": "GBP", "rateUsd": null},
+            {"id": "some-token", "symbol": "TKN"},
+            {"id": "euro", "symbol": "EUR", "rateUsd": "2.0"}
+        ]}"#;
+        let mock_server = MockServer::start();
+        mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/assets");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{"data": [{"id": "bitcoin", "rank": "1", "symbol": "BTC", "name": "Bitcoin"}]}"#);
+        });
+        mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/assets/bitcoin/history");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{"data": [{"priceUsd": "10.0", "time": 1}, {"priceUsd": "20.0", "time": 2}]}"#);
+        });
+        mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/rates");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(rates);
+        });
+        let opts = RunOptions {
+            convert: Some("EUR".to_string()),
+            ..Default::default()
+        };
+        let infos = run(&mock_server.url("/v2"), &opts, &mut |_| Ok(()))
+            .await
+            .unwrap()
+            .infos;
+        assert_eq!(infos[0].current_price, 10.0);
+
+        let rates: RateList = serde_json::from_str(rates).unwrap();
+        assert_eq!(usd_rate(&rates, "eur"), Ok(2.0));
+        assert_eq!(
+            usd_rate(&rates, "GBP"),
+            Err("GBP is listed without a usable rate".to_string())
+        );
+        assert!(usd_rate(&rates, "TKN").is_err());
+        assert_eq!(usd_rate(&rates, "JPY"), Err("no rate for JPY".to_string()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retries_stop_at_total_duration() {
+        let budget = Duration::from_millis(800);
+        let retry = Retry {
+            max_attempts: 50,
+            ..Retry::new(Some(3))
+        }
+        .with_max_total_retry_duration(budget);
+        // Replays the backoff the same seed gives with_retry.
+        let replay = Retry::new(Some(3));
+        let (mut backoff, mut retries) = (Duration::ZERO, 0);
+        loop {
+            let delay = replay.next_delay(retries);
+            if backoff + delay > budget {
+                break;
+            }
+            backoff += delay;
+            retries += 1;
+        }
+        assert!(retries > 1);
+
+        let started = tokio::time::Instant::now();
+        let mut attempts = 0;
+        let result = with_retry(&retry, || {
+            attempts += 1;
+            async { serde_json::from_str::<CoinData>("{").map_err(FetchError::Parse) }
+        })
+        .await;
+        assert!(matches!(result, Err(FetchError::Parse(e)) if e.is_eof()));
+        assert_eq!(attempts, retries + 1);
+        assert_eq!(started.elapsed(), backoff);
+    }
+
+    #[tokio::test]
+    async fn test_wide_window_is_fetched_in_pieces() {
+        let minute = 60_000;
+        let span = MAX_HISTORY_POINTS * minute;
+        let window = Window {
+            start_ms: 0,
+            end_ms: 2 * span + span / 2,
+        };
+        let mock_server = MockServer::start();
+        let piece = |start: u64, end: u64, times: [u64; 2]| {
+            mock_server.mock(move |when, then| {
+                when.method(GET)
+                    .path("/v2/assets/bitcoin/history")
+                    .query_param("interval", "m1")
+                    .query_param("start", start.to_string())
+                    .query_param("end", end.to_string());
+                then.status(200)
+                    .header("content-type", "application/json")
+                    .body(format!(
+                        r#"{{"data": [{{"priceUsd": "1", "time": {}}}, {{"priceUsd": "2", "time": {}}}]}}"#,
+                        times[0], times[1]
+                    ));
+            })
+        };
+        let pieces = [
+            piece(0, span, [0, span]),
+            piece(span, 2 * span, [span, 2 * span]),
+            piece(2 * span, window.end_ms, [2 * span, window.end_ms]),
+        ];
+        let opts = RunOptions {
+            window,
+            ..Default::default()
+        };
+        assert!(streams_history("m1", &opts));
+        let (stats, points) =
+            get_price_stats_in_pieces(&mock_server.url("/v2"), "bitcoin", "m1", &opts)
+                .await
+                .unwrap();
+        assert_eq!(stats.times, [0, span, 2 * span, window.end_ms]);
+        assert_eq!(points, 4);
+        // The boundary points keep the later piece's price, as buffered.
+        assert_eq!(stats.series, [1.0, 1.0, 1.0, 2.0]);
+        let data = get_coin_data(
+            &opts.client,
+            &mock_server.url("/v2"),
+            "bitcoin",
+            "m1",
+            window,
+            &opts.retry,
+            &opts.body_limits,
+        )
+        .await
+        .unwrap();
+        for piece in &pieces {
+            piece.assert_hits(2);
+        }
+        let times: Vec<u64> = data.data.iter().map(|point| point.time).collect();
+        assert_eq!(times, [0, span, 2 * span, window.end_ms]);
+        assert_eq!(history_windows(Window::default(), "d1").len(), 1);
+    }
+
+    #[test]
+    fn test_wide_windows_keep_the_most_recent_pieces() {
+        let span = MAX_HISTORY_POINTS * 60_000;
+        let window = Window {
+            start_ms: 0,
+            end_ms: 100 * span,
+        };
+        assert_eq!(history_pieces_needed(window, "m1"), 100);
+        let pieces = history_windows(window, "m1");
+        assert_eq!(pieces.len() as u64, MAX_HISTORY_PIECES);
+        assert_eq!(pieces[0].start_ms, (100 - MAX_HISTORY_PIECES) * span);
+        assert_eq!(pieces.last().unwrap().end_ms, window.end_ms);
+        assert_eq!(history_pieces_needed(window, "h1"), 2);
+    }
+
+    #[tokio::test]
+    async fn test_wide_window_pieces_are_revalidated_separately() {
+        let span = MAX_HISTORY_POINTS * 60_000;
+        let window = Window {
+            start_ms: 0,
+            end_ms: 2 * span,
+        };
+        let mock_server = MockServer::start();
+        let piece = |start: u64, end: u64| {
+            mock_server.mock(move |when, then| {
+                when.method(GET)
+                    .path("/v2/assets/bitcoin/history")
+                    .query_param("start", start.to_string())
+                    .query_param("end", end.to_string());
+                then.status(200)
+                    .header("content-type", "application/json")
+                    .header("etag", format!("\"{}\"", start))
+                    .body(format!(
+                        r#"{{"data": [{{"priceUsd": "1", "time": {}}}, {{"priceUsd": "2", "time": {}}}]}}"#,
+                        start, end
+                    ));
+            })
+        };
+        let mut first = [piece(0, span), piece(span, 2 * span)];
+        let dir = tempfile::tempdir().unwrap();
+        let opts = RunOptions {
+            cache: Some(Cache::new(dir.path())),
+            cache_ttl: Duration::ZERO,
+            window,
+            ..Default::default()
+        };
+        let url = mock_server.url("/v2");
+        let fetched = get_coin_data_cached(&url, "bitcoin", "m1", &opts)
+            .await
+            .unwrap();
+        assert_eq!(fetched.source, Source::Network);
+        let times: Vec<u64> = fetched.data.data.iter().map(|point| point.time).collect();
+        assert_eq!(times, [0, span, 2 * span]);
+        for piece in &mut first {
+            piece.assert();
+            piece.delete();
+        }
+
+        let not_modified: Vec<_> = [0, span]
+            .into_iter()
+            .map(|start| {
+                mock_server.mock(move |when, then| {
+                    when.method(GET)
+                        .path("/v2/assets/bitcoin/history")
+                        .query_param("start", start.to_string())
+                        .header("if-none-match", format!("\"{}\"", start));
+                    then.status(304);
+                })
+            })
+            .collect();
+        let again = get_coin_data_cached(&url, "bitcoin", "m1", &opts)
+            .await
+            .unwrap();
+        for mock in &not_modified {
+            mock.assert();
+        }
+        assert_eq!(again.source, Source::Cache);
+        assert_eq!(again.data, fetched.data);
+
+        let offline = RunOptions {
+            offline: true,
+            ..opts
+        };
+        let cached = get_coin_data_cached(&url, "bitcoin", "m1", &offline)
+            .await
+            .unwrap();
+        assert_eq!(cached.data, fetched.data);
+    }
+
+    #[tokio::test]
+    async fn test_run_records_window_bounds() {
+        let mock_server = MockServer::start();
+        mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/assets");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{"data": [{"id": "bitcoin", "rank": "1", "symbol": "BTC", "name": "Bitcoin"}]}"#);
+        });
+        mock_server.mock(|when, then| {
+            when.method(GET)
+                .path("/v2/assets/bitcoin/history")
+                .query_param("start", "86400000")
+                .query_param("end", "864000000");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{"data": [{"priceUsd": "10.0", "time": 172800000}, {"priceUsd": "20.0", "time": 259200000}]}"#);
+        });
+        let opts = RunOptions {
+            window: Window {
+                start_ms: 86_400_000,
+                end_ms: 864_000_000,
+            },
+            ..Default::default()
+        };
+        let infos = run(&mock_server.url("/v2"), &opts, &mut |_| Ok(()))
+            .await
+            .unwrap()
+            .infos;
+        assert_eq!(infos[0].window_start, Some(86_400_000));
+        assert_eq!(infos[0].window_end, Some(864_000_000));
+        let json = serde_json::to_value(&infos[0]).unwrap();
+        assert_eq!(json["window_start"], 86_400_000);
+        assert_eq!(json["window_end"], 864_000_000);
+        assert_eq!(json["all_time_high"], 20.0);
+    }
+
+    #[tokio::test]
+    async fn test_clamped_window_reports_the_fetched_span() {
+        let span = MAX_HISTORY_POINTS * 60_000;
+        let mock_server = MockServer::start();
+        mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/assets");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{"data": [{"id": "bitcoin", "rank": "1", "symbol": "BTC", "name": "Bitcoin"}]}"#);
+        });
+        let history = mock_server.mock(|when, then| {
+            when.method(GET).path("/v2/assets/bitcoin/history");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{"data": [{"priceUsd": "10.0", "time": 1}]}"#);
+        });
+        let opts = RunOptions {
+            window: Window {
+                start_ms: 0,
+                end_ms: 30 * span,
+            },
+            intervals: [("bitcoin".to_string(), "m1".to_string())].into(),
+            ..Default::default()
+        };
+        let info = run(&mock_server.url("/v2"), &opts, &mut |_| Ok(()))
+            .await
+            .unwrap()
+            .infos
+            .remove(0);
+        history.assert_hits(MAX_HISTORY_PIECES as usize);
+        let start = (30 - MAX_HISTORY_PIECES) * span;
+        assert_eq!(info.window_start, Some(start));
+        assert_eq!(info.coverage, coverage(1, start, 30 * span, "m1"));
+        assert!(info.coverage > coverage(1, 0, 30 * span, "m1"));
+    }
+}
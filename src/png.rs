@@ -0,0 +1,61 @@
+use crate::CoinInfo;
+use plotters::prelude::*;
+use std::path::Path;
+
+const WIDTH: u32 = 320;
+const HEIGHT: u32 = 120;
+
+/// Draws `info`'s price history as a small line chart and saves it as a PNG.
+///
+/// The y axis spans the window's low to high; a flat series gets a unit of
+/// padding either side so it still has a range to be drawn in. Only every
+/// `sample`th point is drawn, see render::sample.
+pub fn write_chart(
+    info: &CoinInfo,
+    path: &Path,
+    sample: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (low, high) = if info.all_time_high > info.all_time_low {
+        (info.all_time_low, info.all_time_high)
+    } else {
+        (info.all_time_low - 1.0, info.all_time_high + 1.0)
+    };
+    let history = crate::render::sample(&info.history, sample);
+    let steps = history.len().saturating_sub(1).max(1) as f64;
+    let root = BitMapBackend::new(path, (WIDTH, HEIGHT)).into_drawing_area();
+    root.fill(&WHITE)?;
+    let mut chart = ChartBuilder::on(&root)
+        .margin(4)
+        .build_cartesian_2d(0.0..steps, low..high)?;
+    chart.draw_series(LineSeries::new(
+        history
+            .iter()
+            .enumerate()
+            .map(|(i, price)| (i as f64, *price)),
+        &BLACK,
+    ))?;
+    root.present()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_chart_produces_png() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bitcoin.png");
+        let info = CoinInfo {
+            name: "bitcoin".to_string(),
+            all_time_high: 30.0,
+            all_time_low: 10.0,
+            current_price: 20.0,
+            history: vec![10.0, 30.0, 25.0, 20.0],
+            ..Default::default()
+        };
+        write_chart(&info, &path, 1).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        assert!(bytes.starts_with(b"\x89PNG\r\n\x1a\n"));
+    }
+}
@@ -0,0 +1,2386 @@
+use crate::clock::{humanize_duration, Clock};
+use crate::{CoinData, CoinDelta, CoinInfo, Portfolio, Window};
+use chrono::{TimeZone, Utc};
+use serde_json::{Map, Value};
+use std::io::{self, Write};
+use std::ops::Range;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+const MAX_PRICE_DECIMALS: usize = 12;
+// Minor-unit decimals for currencies that don't use two (ISO 4217).
+const CURRENCY_DECIMALS: [(&str, usize); 13] = [
+    ("BHD", 3),
+    ("CLP", 0),
+    ("IQD", 3),
+    ("ISK", 0),
+    ("JOD", 3),
+    ("JPY", 0),
+    ("KRW", 0),
+    ("KWD", 3),
+    ("LYD", 3),
+    ("OMR", 3),
+    ("PYG", 0),
+    ("TND", 3),
+    ("VND", 0),
+];
+const SPARK_LEVELS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+const SPARKLINE_WIDTH: usize = 40;
+/// Columns a coin name may take in the table and bar output.
+pub const MAX_NAME_WIDTH: usize = 24;
+
+/// Default rank tier boundaries: top 10, 11-50, 51-100 and the rest.
+pub const DEFAULT_TIERS: [u32; 3] = [10, 50, 100];
+
+/// Turns a batch of coins into the text for one output format.
+pub trait Renderer {
+    fn render(&self, infos: &[CoinInfo]) -> String;
+
+    /// True when rendering coins one at a time gives the same output as
+    /// rendering the whole batch, so each can be written as soon as it is
+    /// fetched.
+    fn streams(&self) -> bool {
+        false
+    }
+}
+
+pub struct BarRenderer {
+    pub style: BarStyle,
+}
+
+impl Renderer for BarRenderer {
+    fn render(&self, infos: &[CoinInfo]) -> String {
+        let lines = infos.iter().map(|info| bar_line(info, &self.style));
+        if !self.style.show_rank {
+            return lines.map(|line| line + "\n").collect();
+        }
+        rank_labels(infos)
+            .into_iter()
+            .zip(lines)
+            .map(|(rank, line)| format!("{} {}\n", rank, line))
+            .collect()
+    }
+
+    // Rank labels are aligned to the widest rank in the batch, which isn't
+    // known until every coin has been fetched.
+    fn streams(&self) -> bool {
+        !self.style.show_rank
+    }
+}
+
+/// Columns a table may show, in any order.
+pub const TABLE_COLUMNS: [&str; 8] = [
+    "rank", "name", "symbol", "price", "low", "high", "change", "bar",
+];
+/// The columns a table shows unless told otherwise.
+pub const DEFAULT_COLUMNS: [&str; 5] = ["name", "price", "low", "high", "change"];
+
+/// Checks each requested column against TABLE_COLUMNS, keeping the order.
+pub fn select_columns(requested: &[String]) -> Result<Vec<&'static str>, String> {
+    requested
+        .iter()
+        .map(|column| {
+            let column = column.trim();
+            TABLE_COLUMNS
+                .into_iter()
+                .find(|known| known.eq_ignore_ascii_case(column))
+                .ok_or_else(|| {
+                    format!(
+                        "unknown column '{}', valid columns are: {}",
+                        column,
+                        TABLE_COLUMNS.join(", ")
+                    )
+                })
+        })
+        .collect()
+}
+
+pub struct TableRenderer {
+    pub price_decimals: Option<usize>,
+    /// The currency prices are in, for its decimals; None for USD.
+    pub currency: Option<String>,
+    /// Put each coin's rank in front of its row, unless columns already
+    /// has a rank column.
+    pub show_rank: bool,
+    /// Names from TABLE_COLUMNS, in the order they are drawn.
+    pub columns: Vec<&'static str>,
+    /// What the bar column is drawn with.
+    pub theme: Theme,
+}
+
+impl TableRenderer {
+    fn cell(&self, info: &CoinInfo, column: &str) -> String {
+        let price =
+            |value: f64| display_price(value, self.price_decimals, self.currency.as_deref());
+        match column {
+            "rank" => info
+                .rank
+                .map(|rank| format!("#{}", rank))
+                .unwrap_or_default(),
+            "name" => truncate_name(&info.name, MAX_NAME_WIDTH),
+            "symbol" => info.symbol.clone().unwrap_or_default(),
+            "price" => price(info.current_price),
+            "low" => price(info.all_time_low),
+            "high" => price(info.all_time_high),
+            "change" => format!("{:+.2}%", info.window_change_percent),
+            "bar" => block_bar(position_percent(info), &self.theme),
+            _ => unreachable!("select_columns only accepts TABLE_COLUMNS"),
+        }
+    }
+}
+
+impl Renderer for TableRenderer {
+    fn render(&self, infos: &[CoinInfo]) -> String {
+        let header: Vec<String> = self
+            .columns
+            .iter()
+            .map(|column| column.to_uppercase())
+            .collect();
+        let rows: Vec<Vec<String>> = infos
+            .iter()
+            .map(|info| {
+                self.columns
+                    .iter()
+                    .map(|column| self.cell(info, column))
+                    .collect()
+            })
+            .collect();
+        let mut widths = vec![0; self.columns.len()];
+        for row in std::iter::once(&header).chain(&rows) {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(visible_width(cell));
+            }
+        }
+        let lines = std::iter::once(&header)
+            .chain(&rows)
+            .map(|row| table_row(row, &widths, &self.columns));
+        if !self.show_rank || self.columns.contains(&"rank") {
+            return lines.map(|line| line + "\n").collect();
+        }
+        let labels = rank_labels(infos);
+        let blank = " ".repeat(labels.first().map_or(0, |label| label.chars().count()));
+        std::iter::once(blank)
+            .chain(labels)
+            .zip(lines)
+            .map(|(rank, line)| format!("{} {}\n", rank, line))
+            .collect()
+    }
+}
+
+// "#1", "#12", ... right aligned to the widest rank in the batch, with
+// blanks for coins whose rank couldn't be parsed.
+fn rank_labels(infos: &[CoinInfo]) -> Vec<String> {
+    let labels: Vec<String> = infos
+        .iter()
+        .map(|info| {
+            info.rank
+                .map(|rank| format!("#{}", rank))
+                .unwrap_or_default()
+        })
+        .collect();
+    let width = labels.iter().map(String::len).max().unwrap_or(0);
+    labels
+        .into_iter()
+        .map(|label| format!("{:>width$}", label, width = width))
+        .collect()
+}
+
+/// Sorts `infos` by rank, unranked coins last, and splits them at each of
+/// `bounds` into labelled runs: "Top 10", "11-50", ..., "101+". Tiers with
+/// no coins are left out.
+pub fn rank_tiers(infos: &mut [CoinInfo], bounds: &[u32]) -> Vec<(String, Range<usize>)> {
+    infos.sort_by_key(|info| info.rank.unwrap_or(u32::MAX));
+    let mut bounds = bounds.to_vec();
+    bounds.sort_unstable();
+    bounds.dedup();
+    let mut tiers = Vec::new();
+    let mut start = 0;
+    let mut lower = 0;
+    for upper in bounds {
+        let len = infos[start..]
+            .iter()
+            .take_while(|info| info.rank.is_some_and(|rank| rank <= upper))
+            .count();
+        let label = if lower == 0 {
+            format!("Top {}", upper)
+        } else {
+            format!("{}-{}", lower + 1, upper)
+        };
+        if len > 0 {
+            tiers.push((label, start..start + len));
+        }
+        start += len;
+        lower = upper;
+    }
+    if start < infos.len() {
+        tiers.push((format!("{}+", lower + 1), start..infos.len()));
+    }
+    tiers
+}
+
+// Display width, leaving out the colour codes a themed bar carries.
+fn visible_width(cell: &str) -> usize {
+    let mut width = 0;
+    let mut rest = cell;
+    while let Some(start) = rest.find('\x1b') {
+        width += rest[..start].width();
+        rest = rest[start..]
+            .find('m')
+            .map_or("", |end| &rest[start + end + 1..]);
+    }
+    width + rest.width()
+}
+
+// Text columns are left aligned and the numbers right aligned.
+fn table_row(cells: &[String], widths: &[usize], columns: &[&str]) -> String {
+    cells
+        .iter()
+        .zip(widths)
+        .zip(columns)
+        .map(|((cell, width), column)| {
+            // Padded by display width, which `{:<width$}` doesn't know about.
+            let padding = " ".repeat(width.saturating_sub(visible_width(cell)));
+            if matches!(*column, "name" | "symbol" | "bar") {
+                format!("{}{}", cell, padding)
+            } else {
+                format!("{}{}", padding, cell)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("  ")
+        .trim_end()
+        .to_string()
+}
+
+pub struct JsonRenderer {
+    pub fields: Vec<String>,
+    /// Indent the output for reading rather than piping.
+    pub pretty: bool,
+}
+
+impl Renderer for JsonRenderer {
+    fn render(&self, infos: &[CoinInfo]) -> String {
+        let json = render_json(infos, &self.fields);
+        if !self.pretty {
+            return json.to_string() + "\n";
+        }
+        serde_json::to_string_pretty(&json).expect("a Value always serializes") + "\n"
+    }
+}
+
+pub struct CsvRenderer {
+    pub fields: Vec<String>,
+}
+
+impl Renderer for CsvRenderer {
+    fn render(&self, infos: &[CoinInfo]) -> String {
+        render_csv(infos, &self.fields)
+    }
+}
+
+/// Placeholders a --template may use.
+pub const TEMPLATE_FIELDS: [&str; 10] = [
+    "name", "symbol", "rank", "price", "low", "high", "change", "position", "bar", "points",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+enum Segment {
+    Text(String),
+    Field(&'static str),
+}
+
+/// A line format like `"{rank} {symbol} {price} {bar} {change}"`, checked
+/// against TEMPLATE_FIELDS when parsed. `{{` and `}}` are literal braces.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Template, String> {
+        let mut segments = Vec::new();
+        let mut text = String::new();
+        let mut chars = template.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let end = rest
+                        .find('}')
+                        .ok_or_else(|| format!("unclosed '{{' in template '{}'", template))?;
+                    let name = &rest[..end];
+                    let field = TEMPLATE_FIELDS
+                        .into_iter()
+                        .find(|field| *field == name)
+                        .ok_or_else(|| {
+                            format!(
+                                "unknown placeholder {{{}}}; known ones are {}",
+                                name,
+                                TEMPLATE_FIELDS.join(", ")
+                            )
+                        })?;
+                    if !text.is_empty() {
+                        segments.push(Segment::Text(std::mem::take(&mut text)));
+                    }
+                    segments.push(Segment::Field(field));
+                    chars = rest[end + 1..].chars();
+                }
+                '}' => return Err(format!("unmatched '}}' in template '{}'", template)),
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            segments.push(Segment::Text(text));
+        }
+        Ok(Template { segments })
+    }
+}
+
+/// One line per coin in a user-given Template.
+pub struct TemplateRenderer {
+    pub template: Template,
+    pub price_decimals: Option<usize>,
+    pub currency: Option<String>,
+    /// What {bar} is drawn with.
+    pub theme: Theme,
+}
+
+impl TemplateRenderer {
+    fn field(&self, info: &CoinInfo, field: &str) -> String {
+        let price =
+            |value: f64| display_price(value, self.price_decimals, self.currency.as_deref());
+        let position = position_percent(info);
+        match field {
+            "name" => info.name.clone(),
+            "symbol" => info.symbol.clone().unwrap_or_default(),
+            "rank" => info.rank.map(|rank| rank.to_string()).unwrap_or_default(),
+            "price" => price(info.current_price),
+            "low" => price(info.all_time_low),
+            "high" => price(info.all_time_high),
+            "change" => format!("{:+.2}%", info.window_change_percent),
+            "position" => position.map_or("-".to_string(), |p| format!("{:.2}%", p)),
+            "bar" => block_bar(position, &self.theme),
+            "points" => info.points.to_string(),
+            _ => unreachable!("Template::parse only accepts TEMPLATE_FIELDS"),
+        }
+    }
+}
+
+impl Renderer for TemplateRenderer {
+    fn render(&self, infos: &[CoinInfo]) -> String {
+        let mut out = String::new();
+        for info in infos {
+            for segment in &self.template.segments {
+                match segment {
+                    Segment::Text(text) => out.push_str(text),
+                    Segment::Field(field) => out.push_str(&self.field(info, field)),
+                }
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    fn streams(&self) -> bool {
+        true
+    }
+}
+
+// A bar in `theme` filled up to `position`, or "-" without one.
+fn block_bar(position: Option<f64>, theme: &Theme) -> String {
+    position.map_or("-".to_string(), |p| {
+        let filled = ((p / 2.0) as usize).min(BAR_CELLS);
+        let cells = theme.fill.to_string().repeat(filled)
+            + &theme.empty.to_string().repeat(BAR_CELLS - filled);
+        paint(&cells, theme.color)
+    })
+}
+
+// Where the current price sits between the low and the high, in percent,
+// or None when the range is empty. Nothing here assumes prices are
+// positive; a price outside the range is clamped to its nearer end.
+fn position_percent(info: &CoinInfo) -> Option<f64> {
+    let range = info.all_time_high - info.all_time_low;
+    if !(range > 0.0 && range.is_finite()) {
+        return None;
+    }
+    let percentage = (info.current_price - info.all_time_low) * 100.0 / range;
+    Some(percentage.clamp(0.0, 100.0))
+}
+
+/// A sentence saying what a coin's bar shows, e.g. "bitcoin: current
+/// $30000.00 is 42.00% of the way from ATL $60.00 to ATH $69000.00 over
+/// 365 days". Prices are in `currency` when given, otherwise dollars.
+pub fn explain(info: &CoinInfo, window: Window, currency: Option<&str>) -> String {
+    let price = |value: f64| match currency {
+        Some(currency) => format!("{} {}", format_price_in(value, Some(currency)), currency),
+        None => format!("${}", format_price(value)),
+    };
+    let range = format!(
+        "ATL {} to ATH {} over {}",
+        price(info.all_time_low),
+        price(info.all_time_high),
+        describe_span(window.end_ms.saturating_sub(window.start_ms))
+    );
+    let current = price(info.current_price);
+    match position_percent(info) {
+        Some(position) => format!(
+            "{}: current {} is {:.2}% of the way from {}",
+            info.name, current, position, range
+        ),
+        None => format!(
+            "{}: current {} can't be placed on the range from {}",
+            info.name, current, range
+        ),
+    }
+}
+
+// "3 days", "12 hours" or "30 minutes", rounded down to the largest unit
+// that fits at least once.
+fn describe_span(millis: u64) -> String {
+    let minutes = millis / 60_000;
+    let (count, unit) = if minutes >= 24 * 60 {
+        (minutes / (24 * 60), "day")
+    } else if minutes >= 60 {
+        (minutes / 60, "hour")
+    } else {
+        (minutes, "minute")
+    };
+    format!("{} {}{}", count, unit, if count == 1 { "" } else { "s" })
+}
+
+/// Each coin's window change next to its spread over the benchmark, as set
+/// by relative_to_benchmark.
+pub struct RelativeRenderer {
+    pub benchmark: String,
+}
+
+impl Renderer for RelativeRenderer {
+    fn render(&self, infos: &[CoinInfo]) -> String {
+        let names: Vec<String> = infos
+            .iter()
+            .map(|info| truncate_name(&info.name, MAX_NAME_WIDTH))
+            .collect();
+        let width = names.iter().map(|name| name.width()).max().unwrap_or(0);
+        infos
+            .iter()
+            .zip(names)
+            .map(|(info, name)| {
+                let padding = " ".repeat(width - name.width());
+                let change = format!("{:+.2}%", info.window_change_percent);
+                let spread = info
+                    .relative_change_percent
+                    .map_or("-".to_string(), |spread| format!("{:+.2}%", spread));
+                format!(
+                    "{}{}  {:>9}  {:>9} vs {}\n",
+                    name, padding, change, spread, self.benchmark
+                )
+            })
+            .collect()
+    }
+}
+
+/// Names of the events NdjsonStatsRenderer emits for every coin, in order.
+/// Downstream consumers route on these, so they must not change.
+pub const STAT_METRICS: [&str; 11] = [
+    "price",
+    "ath",
+    "ath_time",
+    "atl",
+    "atl_time",
+    "change_percent",
+    "average_price",
+    "coverage",
+    "points",
+    "volume_usd_24h",
+    "rank",
+];
+
+/// One `{"coin","metric","value"}` line per statistic per coin. Metrics a
+/// coin has no value for are still emitted, with a null value.
+pub struct NdjsonStatsRenderer;
+
+impl Renderer for NdjsonStatsRenderer {
+    fn render(&self, infos: &[CoinInfo]) -> String {
+        let mut out = String::new();
+        for info in infos {
+            for (metric, value) in STAT_METRICS.iter().zip(stat_values(info)) {
+                let event = serde_json::json!({
+                    "coin": info.name,
+                    "metric": metric,
+                    "value": value,
+                });
+                out.push_str(&event.to_string());
+                out.push('\n');
+            }
+        }
+        out
+    }
+
+    fn streams(&self) -> bool {
+        true
+    }
+}
+
+// What each of STAT_METRICS means, for OpenMetrics' HELP lines.
+const STAT_HELP: [&str; 11] = [
+    "Current price",
+    "Highest price in the window",
+    "When the highest price was, in unix millis",
+    "Lowest price in the window",
+    "When the lowest price was, in unix millis",
+    "Change over the window in percent",
+    "Average price over the window",
+    "Share of the window the history covers",
+    "History points fetched",
+    "Volume over the last 24 hours in USD",
+    "Market cap rank",
+];
+
+// Values matching STAT_METRICS position for position.
+fn stat_values(info: &CoinInfo) -> [Value; 11] {
+    [
+        Value::from(info.current_price),
+        Value::from(info.all_time_high),
+        Value::from(info.all_time_high_time),
+        Value::from(info.all_time_low),
+        Value::from(info.all_time_low_time),
+        Value::from(info.window_change_percent),
+        Value::from(info.average_price),
+        Value::from(info.coverage),
+        Value::from(info.points),
+        Value::from(info.volume_usd_24h),
+        Value::from(info.rank),
+    ]
+}
+
+/// The STAT_METRICS as Prometheus gauges in the text exposition format,
+/// named `coin_<metric>` and labelled with the coin. Missing values are
+/// left out.
+pub struct PrometheusRenderer {
+    /// Follow the stricter OpenMetrics text format instead: every metric
+    /// also gets a HELP line, and the output ends with `# EOF`.
+    pub openmetrics: bool,
+}
+
+impl Renderer for PrometheusRenderer {
+    fn render(&self, infos: &[CoinInfo]) -> String {
+        let values: Vec<[Value; 11]> = infos.iter().map(stat_values).collect();
+        let mut out = String::new();
+        for (i, metric) in STAT_METRICS.iter().enumerate() {
+            out.push_str(&format!("# TYPE coin_{} gauge\n", metric));
+            if self.openmetrics {
+                out.push_str(&format!("# HELP coin_{} {}\n", metric, STAT_HELP[i]));
+            }
+            for (info, values) in infos.iter().zip(&values) {
+                if let Some(value) = values[i].as_f64() {
+                    out.push_str(&format!(
+                        "coin_{}{{coin=\"{}\"}} {}\n",
+                        metric,
+                        escape_label(&info.name),
+                        value
+                    ));
+                }
+            }
+        }
+        if self.openmetrics {
+            out.push_str("# EOF\n");
+        }
+        out
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+const SVG_WIDTH: f64 = 600.0;
+const SVG_CHART_HEIGHT: f64 = 200.0;
+const SVG_MARGIN: f64 = 20.0;
+
+/// A line chart of each coin's price history, stacked in one SVG document.
+pub struct SvgRenderer {
+    /// Draw every this many points; see sample.
+    pub sample: usize,
+}
+
+impl Renderer for SvgRenderer {
+    fn render(&self, infos: &[CoinInfo]) -> String {
+        let height = SVG_CHART_HEIGHT * infos.len() as f64;
+        let mut out = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" viewBox=\"0 0 {} {}\">\n",
+            SVG_WIDTH, height, SVG_WIDTH, height
+        );
+        for (i, info) in infos.iter().enumerate() {
+            out.push_str(&svg_chart(info, SVG_CHART_HEIGHT * i as f64, self.sample));
+        }
+        out.push_str("</svg>\n");
+        out
+    }
+}
+
+fn svg_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// The y axis spans the window's low to high, so the series fills the chart.
+fn svg_chart(info: &CoinInfo, top: f64, every: usize) -> String {
+    let plot_width = SVG_WIDTH - 2.0 * SVG_MARGIN;
+    let plot_height = SVG_CHART_HEIGHT - 2.0 * SVG_MARGIN;
+    let range = info.all_time_high - info.all_time_low;
+    let history = sample(&info.history, every);
+    let steps = history.len().saturating_sub(1).max(1) as f64;
+    let path: Vec<String> = history
+        .iter()
+        .enumerate()
+        .map(|(i, price)| {
+            let x = SVG_MARGIN + plot_width * i as f64 / steps;
+            let share = if range > 0.0 {
+                (price - info.all_time_low) / range
+            } else {
+                0.5
+            };
+            let y = top + SVG_MARGIN + plot_height * (1.0 - share);
+            format!("{}{:.1},{:.1}", if i == 0 { "M" } else { "L" }, x, y)
+        })
+        .collect();
+    format!(
+        "<text x=\"{}\" y=\"{:.1}\" font-size=\"12\">{} {}–{}</text>\n<path d=\"{}\" fill=\"none\" stroke=\"black\"/>\n",
+        SVG_MARGIN,
+        top + SVG_MARGIN - 6.0,
+        svg_escape(&info.name),
+        format_price(info.all_time_low),
+        format_price(info.all_time_high),
+        path.join(" ")
+    )
+}
+
+/// Every `every`th price, for charts of dense histories. The last price is
+/// always kept so a chart ends at the current price; 0 and 1 keep them all.
+/// Only drawing uses this: the stats are computed from the full history.
+pub fn sample(prices: &[f64], every: usize) -> Vec<f64> {
+    let every = every.max(1);
+    let mut sampled: Vec<f64> = prices.iter().copied().step_by(every).collect();
+    if !prices.len().saturating_sub(1).is_multiple_of(every) {
+        sampled.extend(prices.last());
+    }
+    sampled
+}
+
+/// The history squeezed into `width` block characters, one per bucket of
+/// points, each showing the bucket's last price.
+pub fn sparkline(prices: &[f64], width: usize) -> String {
+    let low = prices.iter().copied().fold(f64::INFINITY, f64::min);
+    let high = prices.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let buckets = width.min(prices.len());
+    (1..=buckets)
+        .map(|bucket| {
+            let price = prices[bucket * prices.len() / buckets - 1];
+            let share = if high > low {
+                (price - low) / (high - low)
+            } else {
+                0.5
+            };
+            SPARK_LEVELS[((share * (SPARK_LEVELS.len() - 1) as f64).round()) as usize]
+        })
+        .collect()
+}
+
+// " on 2023-01-02", followed by " (3 months ago)" when given a clock.
+fn format_date(millis: Option<u64>, clock: Option<&dyn Clock>) -> String {
+    let Some(millis) = millis else {
+        return String::new();
+    };
+    let Some(date) = Utc.timestamp_millis_opt(millis as i64).single() else {
+        return String::new();
+    };
+    match clock {
+        Some(clock) => format!(
+            " on {} ({})",
+            date.format("%Y-%m-%d"),
+            humanize_duration(millis, clock.now())
+        ),
+        None => format!(" on {}", date.format("%Y-%m-%d")),
+    }
+}
+
+// ▲ for a rise, ▼ for a fall and = for no change.
+fn arrow(change: f64) -> char {
+    if change > 0.0 {
+        '▲'
+    } else if change < 0.0 {
+        '▼'
+    } else {
+        '='
+    }
+}
+
+/// A line per position, "bitcoin  0.5  30000.00  15000.00" for amount,
+/// price and value, then the total.
+pub fn render_portfolio(portfolio: &Portfolio, currency: Option<&str>) -> String {
+    let price = |value: f64| format_price_in(value, currency);
+    let mut rows: Vec<Vec<String>> = portfolio
+        .positions
+        .iter()
+        .map(|position| {
+            vec![
+                truncate_name(&position.name, MAX_NAME_WIDTH),
+                position.amount.to_string(),
+                price(position.price),
+                price(position.value),
+            ]
+        })
+        .collect();
+    rows.push(vec![
+        "total".to_string(),
+        String::new(),
+        String::new(),
+        price(portfolio.total),
+    ]);
+    let mut widths = [0; 4];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.width());
+        }
+    }
+    let columns = ["name", "amount", "price", "value"];
+    rows.iter()
+        .map(|row| table_row(row, &widths, &columns) + "\n")
+        .collect()
+}
+
+/// One line per delta from diff_runs, e.g. "bitcoin ▲ +1000.00 (+5.00%)
+/// rank ▲2".
+pub fn render_diff(deltas: &[CoinDelta]) -> String {
+    let mut out = String::new();
+    for delta in deltas {
+        let line = match delta {
+            CoinDelta::Added(name) => format!("+ {} (new)", name),
+            CoinDelta::Removed(name) => format!("- {} (gone)", name),
+            CoinDelta::Changed {
+                name,
+                price_change,
+                price_change_percent,
+                rank_change,
+            } => {
+                let mut line = format!(
+                    "{} {} {}{}",
+                    name,
+                    arrow(*price_change),
+                    if *price_change < 0.0 { "-" } else { "+" },
+                    format_price(price_change.abs())
+                );
+                if let Some(percent) = price_change_percent {
+                    line.push_str(&format!(" ({:+.2}%)", percent));
+                }
+                if let Some(places) = rank_change.filter(|places| *places != 0) {
+                    line.push_str(&format!(" rank {}{}", arrow(places as f64), places.abs()));
+                }
+                line
+            }
+        };
+        out.push_str(&line);
+        out.push('\n');
+    }
+    out
+}
+
+/// Every stat for one coin. Prices take `price_decimals` when given,
+/// otherwise `currency`'s decimals (None for USD). With a clock, the high's
+/// and low's dates also say how long ago they were.
+pub fn render_detail(
+    info: &CoinInfo,
+    price_decimals: Option<usize>,
+    currency: Option<&str>,
+    clock: Option<&dyn Clock>,
+) -> String {
+    let or_dash = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+    let price = |value: f64| display_price(value, price_decimals, currency);
+    let rows = [
+        ("price", price(info.current_price)),
+        (
+            "high",
+            price(info.all_time_high) + &format_date(info.all_time_high_time, clock),
+        ),
+        (
+            "low",
+            price(info.all_time_low) + &format_date(info.all_time_low_time, clock),
+        ),
+        ("change", format!("{:+.2}%", info.window_change_percent)),
+        (
+            "volatility",
+            or_dash(crate::volatility(&info.history).map(|v| format!("{:.2}%", v))),
+        ),
+        (
+            "sharpe",
+            or_dash(info.sharpe_ratio.map(|ratio| format!("{:.2}", ratio))),
+        ),
+        (
+            "rsi",
+            or_dash(crate::rsi(&info.history, crate::RSI_PERIOD).map(|v| format!("{:.1}", v))),
+        ),
+        (
+            "trend",
+            or_dash(crate::trend(&info.history).map(String::from)),
+        ),
+        ("history", sparkline(&info.history, SPARKLINE_WIDTH)),
+    ];
+    let mut out = format!("{}\n", info.name);
+    for (label, value) in rows {
+        out.push_str(&format!("  {:<12}{}\n", label, value));
+    }
+    out
+}
+
+/// The detail view of `long` plus a bar of where its price sits in the long
+/// range and a sparkline of `recent`, the same coin at a finer `interval`
+/// over a shorter span. Prices are formatted as in render_detail.
+pub fn render_overlay(
+    long: &CoinInfo,
+    interval: &str,
+    recent: &CoinInfo,
+    price_decimals: Option<usize>,
+    currency: Option<&str>,
+    clock: Option<&dyn Clock>,
+) -> String {
+    let price = |value: f64| display_price(value, price_decimals, currency);
+    let recent_label = format!("last {}", interval);
+    let rows = [
+        (
+            "range",
+            block_bar(position_percent(long), &Theme::default()),
+        ),
+        (
+            recent_label.as_str(),
+            format!(
+                "{} {}–{}",
+                sparkline(&recent.history, SPARKLINE_WIDTH),
+                price(recent.all_time_low),
+                price(recent.all_time_high)
+            ),
+        ),
+    ];
+    let mut out = render_detail(long, price_decimals, currency, clock);
+    for (label, value) in rows {
+        out.push_str(&format!("  {:<12}{}\n", label, value));
+    }
+    out
+}
+
+// Flushes after every coin so a consumer reading a pipe sees each line as
+// soon as the coin is fetched rather than when the buffer fills up.
+pub fn write_coin<W: Write>(
+    out: &mut W,
+    renderer: &dyn Renderer,
+    info: &CoinInfo,
+) -> io::Result<()> {
+    out.write_all(renderer.render(std::slice::from_ref(info)).as_bytes())?;
+    out.flush()
+}
+
+// Rounds to two decimal places, half away from zero. A tiny negative rounds
+// to 0.0 rather than -0.0, so it never prints as "-0".
+fn round2(x: f64) -> f64 {
+    let rounded = (x * 100.0).round() / 100.0;
+    if rounded == 0.0 {
+        0.0
+    } else {
+        rounded
+    }
+}
+
+// A range narrower than this fraction of the price is too flat for the bar
+// to say anything: a cell is 2% of the range, so it would be showing noise.
+const FLAT_RANGE: f64 = 1e-4;
+const FLAT_LABEL: &str = "≈flat";
+const FLAT_TRACK: char = '─';
+
+// Near flat, but not exactly: that gets its own message.
+fn is_near_flat(upper: f64, lower: f64) -> bool {
+    let range = (upper - lower).abs();
+    let scale = upper.abs().max(lower.abs());
+    range != 0.0 && range < scale * FLAT_RANGE
+}
+
+fn draw_bar_graph(
+    upper: f64,
+    lower: f64,
+    current: f64,
+    symbol: &str,
+    fill: char,
+    empty: char,
+) -> String {
+    let range = upper - lower;
+    if range == 0.0 {
+        return "Upper and lower value are the same.".to_string();
+    }
+    if is_near_flat(upper, lower) {
+        return format!(
+            "{:>11}|{}|{}",
+            FLAT_LABEL,
+            FLAT_TRACK.to_string().repeat(BAR_CELLS),
+            symbol
+        );
+    }
+    let formatted_percentage = round2((current - lower) * 100.0 / range);
+    if !(0.0..=100.0).contains(&formatted_percentage) {
+        return "Current value is not within the specified range.".to_string();
+    }
+    let bar = (formatted_percentage as i32) / 2;
+    let formatted_percentage = format!("{:>10}", formatted_percentage);
+    let padding = 50 - bar;
+    format!(
+        "{}|{}{}|{}",
+        format_args!("{}%", formatted_percentage),
+        fill.to_string().repeat(bar as usize),
+        empty.to_string().repeat(padding as usize),
+        symbol
+    )
+}
+
+fn bar_line(info: &CoinInfo, style: &BarStyle) -> String {
+    let theme = &style.theme;
+    let fill = if style.shade_by_volume {
+        volume_fill(info.relative_volume)
+    } else {
+        theme.fill
+    };
+    // A linked coin is shown by its symbol, falling back to the id for
+    // coins that weren't listed.
+    let name = match (style.links, &info.symbol) {
+        (true, symbol) => hyperlink(
+            symbol.as_deref().unwrap_or(&info.name),
+            &format!("https://coincap.io/assets/{}", info.name),
+        ),
+        (false, _) => truncate_name(&info.name, MAX_NAME_WIDTH),
+    };
+    let currency = style.currency.as_deref();
+    let mut price = display_price(info.current_price, style.price_decimals, currency);
+    if style.show_supply {
+        price.push_str(&supply_label(info));
+    }
+    match style.baseline(info) {
+        // Break-even sits mid-bar, with a total loss at the left end and a
+        // doubling (or better) at the right.
+        Some(baseline) => {
+            let upper = 2.0 * baseline;
+            let current = info.current_price.clamp(0.0, upper);
+            let bar = draw_bar_graph(
+                upper,
+                0.0,
+                current,
+                &format!(
+                    "{} {} ({:+.2}% vs {})",
+                    name,
+                    price,
+                    gain_percent(info.current_price, baseline),
+                    display_price(baseline, style.price_decimals, currency)
+                ),
+                fill,
+                theme.empty,
+            );
+            paint_cells(&bar, theme.color)
+        }
+        None => {
+            let (upper, lower) = (info.all_time_high, info.all_time_low);
+            let bar = draw_bar_graph(
+                upper,
+                lower,
+                info.current_price,
+                &format!("{} {}", name, price),
+                fill,
+                theme.empty,
+            );
+            let bar = match (style.ticks, info.recent_low, info.recent_high) {
+                (true, Some(low), Some(high)) => overlay_ticks(&bar, upper, lower, low, high),
+                _ => bar,
+            };
+            paint_cells(&bar, theme.color)
+        }
+    }
+}
+
+/// The characters and colour a bar is drawn with.
+#[derive(Clone, Copy, Debug)]
+pub struct Theme {
+    pub name: &'static str,
+    pub fill: char,
+    pub empty: char,
+    /// The ANSI colour for a bar cell this far along the bar, as a
+    /// percentage, if the theme has one.
+    pub color: fn(f64) -> Option<String>,
+}
+
+impl Theme {
+    /// The same characters without the colour, for output that isn't
+    /// going to a terminal.
+    pub fn plain(self) -> Theme {
+        Theme {
+            color: no_color,
+            ..self
+        }
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        THEMES[0]
+    }
+}
+
+fn no_color(_: f64) -> Option<String> {
+    None
+}
+
+// Green at the low end of the range through to red at the high end.
+fn heat_color(percentage: f64) -> Option<String> {
+    let hot = (percentage / 100.0).clamp(0.0, 1.0);
+    let red = (255.0 * hot).round() as u8;
+    let green = (255.0 * (1.0 - hot)).round() as u8;
+    Some(format!("\x1b[38;2;{};{};0m", red, green))
+}
+
+/// The built-in themes --theme picks from; the first is the default.
+pub const THEMES: [Theme; 4] = [
+    Theme {
+        name: "classic",
+        fill: '█',
+        empty: '░',
+        color: no_color,
+    },
+    Theme {
+        name: "dots",
+        fill: '●',
+        empty: '○',
+        color: no_color,
+    },
+    Theme {
+        name: "blocks",
+        fill: '▓',
+        empty: '▒',
+        color: no_color,
+    },
+    Theme {
+        name: "heat",
+        fill: '█',
+        empty: '░',
+        color: heat_color,
+    },
+];
+
+pub fn theme(name: &str) -> Option<Theme> {
+    THEMES
+        .iter()
+        .find(|theme| theme.name.eq_ignore_ascii_case(name))
+        .copied()
+}
+
+// Colours the bar's cells, between its first two '|', with paint.
+fn paint_cells(bar: &str, color: fn(f64) -> Option<String>) -> String {
+    let Some(start) = bar.find('|') else {
+        return bar.to_string();
+    };
+    let Some(end) = bar[start + 1..].find('|').map(|end| start + 1 + end) else {
+        return bar.to_string();
+    };
+    format!(
+        "{}{}{}",
+        &bar[..=start],
+        paint(&bar[start + 1..end], color),
+        &bar[end..]
+    )
+}
+
+// Colours each cell by how far along the bar it sits, so a coloured theme
+// draws a gradient. Themes without a colour leave the cells as they are.
+fn paint(cells: &str, color: fn(f64) -> Option<String>) -> String {
+    let last = cells.chars().count().saturating_sub(1).max(1) as f64;
+    let mut painted = String::new();
+    for (i, cell) in cells.chars().enumerate() {
+        let Some(code) = color(i as f64 * 100.0 / last) else {
+            return cells.to_string();
+        };
+        painted.push_str(&code);
+        painted.push(cell);
+    }
+    painted + "\x1b[0m"
+}
+
+const BAR_CELLS: usize = 50;
+const RECENT_LOW_TICK: char = '[';
+const RECENT_HIGH_TICK: char = ']';
+// Both ticks in one cell, when the recent range is narrower than a cell.
+const RECENT_BOTH_TICK: char = '┃';
+
+// The bar cell, 0..BAR_CELLS, that `value` falls in.
+fn bar_cell(value: f64, upper: f64, lower: f64) -> usize {
+    let percentage = (value - lower) * 100.0 / (upper - lower);
+    ((percentage / 2.0).max(0.0) as usize).min(BAR_CELLS - 1)
+}
+
+// Marks where the recent low and high sit on a bar drawn by
+// draw_bar_graph. Bars that couldn't be drawn are left alone.
+fn overlay_ticks(bar: &str, upper: f64, lower: f64, recent_low: f64, recent_high: f64) -> String {
+    let mut chars: Vec<char> = bar.chars().collect();
+    let Some(start) = chars.iter().position(|c| *c == '|') else {
+        return bar.to_string();
+    };
+    if upper == lower || is_near_flat(upper, lower) || chars.len() <= start + BAR_CELLS {
+        return bar.to_string();
+    }
+    let low = bar_cell(recent_low, upper, lower);
+    let high = bar_cell(recent_high, upper, lower);
+    if low == high {
+        chars[start + 1 + low] = RECENT_BOTH_TICK;
+    } else {
+        chars[start + 1 + low] = RECENT_LOW_TICK;
+        chars[start + 1 + high] = RECENT_HIGH_TICK;
+    }
+    chars.into_iter().collect()
+}
+
+/// Percent gained (or lost, when negative) going from `baseline` to `current`.
+pub fn gain_percent(current: f64, baseline: f64) -> f64 {
+    (current - baseline) / baseline * 100.0
+}
+
+#[derive(Debug, Default)]
+pub struct BarStyle {
+    pub price_decimals: Option<usize>,
+    /// The currency prices are in, for its decimals; None for USD.
+    pub currency: Option<String>,
+    pub shade_by_volume: bool,
+    pub show_rank: bool,
+    pub links: bool,
+    /// Entry prices by coin id or symbol; a coin with one is drawn against
+    /// it instead of its low and high.
+    pub baselines: Vec<(String, f64)>,
+    /// Mark the last 30 days' low and high on each bar.
+    pub ticks: bool,
+    pub theme: Theme,
+    /// Follow the price with the circulating supply and its share of the
+    /// maximum.
+    pub show_supply: bool,
+}
+
+impl BarStyle {
+    fn baseline(&self, info: &CoinInfo) -> Option<f64> {
+        self.baselines
+            .iter()
+            .find(|(coin, _)| {
+                coin.eq_ignore_ascii_case(&info.name)
+                    || info
+                        .symbol
+                        .as_deref()
+                        .is_some_and(|symbol| coin.eq_ignore_ascii_case(symbol))
+            })
+            .map(|(_, price)| *price)
+    }
+}
+
+/// Cuts `name` to at most `max_width` terminal columns, ending it with `…`
+/// when anything was cut. Whole grapheme clusters are kept or dropped, so
+/// accents, emoji and wide characters are never split.
+pub fn truncate_name(name: &str, max_width: usize) -> String {
+    if name.width() <= max_width {
+        return name.to_string();
+    }
+    let mut truncated = String::new();
+    let mut width = 0;
+    for grapheme in name.graphemes(true) {
+        let grapheme_width = grapheme.width();
+        // Leaves a column for the ellipsis.
+        if width + grapheme_width >= max_width {
+            break;
+        }
+        truncated.push_str(grapheme);
+        width += grapheme_width;
+    }
+    truncated.push('…');
+    truncated
+}
+
+// An OSC 8 escape, which terminals that support it render as a clickable
+// link showing `text`.
+fn hyperlink(text: &str, url: &str) -> String {
+    format!("\x1b]8;;{}\x1b\\{}\x1b]8;;\x1b\\", url, text)
+}
+
+// Lighter fills for thinly traded coins so their extremes stand out less.
+pub fn volume_fill(relative_volume: Option<f64>) -> char {
+    match relative_volume {
+        Some(v) if v < 1.0 / 3.0 => '▒',
+        Some(v) if v < 2.0 / 3.0 => '▓',
+        _ => '█',
+    }
+}
+
+// " supply 19.50M of 21.00M (92.9%)", or "(uncapped)" for coins without
+// a maximum. Empty when the coin list gave no supply.
+fn supply_label(info: &CoinInfo) -> String {
+    let Some(supply) = info.supply else {
+        return String::new();
+    };
+    match (info.max_supply, info.supply_percent) {
+        (Some(max), Some(percent)) => format!(
+            " supply {} of {} ({:.1}%)",
+            format_quantity(supply),
+            format_quantity(max),
+            percent
+        ),
+        _ => format!(" supply {} (uncapped)", format_quantity(supply)),
+    }
+}
+
+// Large counts with a K/M/B/T suffix: 19500000 -> "19.50M".
+fn format_quantity(value: f64) -> String {
+    for (scale, suffix) in [(1e12, "T"), (1e9, "B"), (1e6, "M"), (1e3, "K")] {
+        if value.abs() >= scale {
+            return format!("{:.2}{}", value / scale, suffix);
+        }
+    }
+    format!("{:.0}", value)
+}
+
+/// The decimals amounts in `code` are written with: 0 for JPY, 3 for BHD,
+/// and 2 for USD or any currency not in the table.
+pub fn currency_decimals(code: &str) -> usize {
+    CURRENCY_DECIMALS
+        .iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(code))
+        .map_or(2, |(_, decimals)| *decimals)
+}
+
+// The currency's own decimals from 1 upwards, three significant digits
+// below that, so both $60,000 and $0.00002 stay readable.
+fn price_decimals(value: f64, unit_decimals: usize) -> usize {
+    let magnitude = value.abs();
+    if magnitude >= 1.0 || magnitude == 0.0 || !magnitude.is_finite() {
+        return unit_decimals;
+    }
+    (((-magnitude.log10()).floor() as usize) + 3).clamp(unit_decimals, MAX_PRICE_DECIMALS)
+}
+
+pub fn format_price(value: f64) -> String {
+    format_price_in(value, None)
+}
+
+/// Like format_price, rounded to `currency`'s decimals; None means USD.
+pub fn format_price_in(value: f64, currency: Option<&str>) -> String {
+    let unit_decimals = currency.map_or(2, currency_decimals);
+    format!("{:.*}", price_decimals(value, unit_decimals), value)
+}
+
+// A fixed number of decimals when one was asked for, otherwise by magnitude.
+fn display_price(value: f64, decimals: Option<usize>, currency: Option<&str>) -> String {
+    match decimals {
+        Some(decimals) => format!("{:.*}", decimals, value),
+        None => format_price_in(value, currency),
+    }
+}
+
+/// CoinInfo fields that are only serialized when set. json/csv output
+/// leaves them out unless --fields names them or a flag asks for them.
+pub const OPTIONAL_FIELDS: [&str; 6] = [
+    "recent_high",
+    "recent_low",
+    "relative_change_percent",
+    "sharpe_ratio",
+    "window_start",
+    "window_end",
+];
+
+// The field names CoinInfo always serializes with, in declaration order,
+// followed by `extra`.
+pub fn default_fields(extra: &[&str]) -> Vec<String> {
+    let mut fields = match serde_json::to_value(CoinInfo::default()) {
+        Ok(Value::Object(map)) => map.keys().cloned().collect(),
+        _ => Vec::new(),
+    };
+    fields.extend(extra.iter().map(|field| field.to_string()));
+    fields
+}
+
+pub fn known_fields() -> Vec<String> {
+    default_fields(&OPTIONAL_FIELDS)
+}
+
+pub fn select_fields(requested: &[String]) -> Result<Vec<String>, String> {
+    let known = known_fields();
+    requested
+        .iter()
+        .map(|field| field.trim().to_string())
+        .map(|field| {
+            if known.contains(&field) {
+                Ok(field)
+            } else {
+                Err(format!(
+                    "unknown field '{}', valid fields are: {}",
+                    field,
+                    known.join(", ")
+                ))
+            }
+        })
+        .collect()
+}
+
+fn project(info: &CoinInfo, fields: &[String]) -> Map<String, Value> {
+    let mut all = match serde_json::to_value(info) {
+        Ok(Value::Object(map)) => map,
+        _ => Map::new(),
+    };
+    fields
+        .iter()
+        .filter_map(|field| all.remove(field).map(|value| (field.clone(), value)))
+        .collect()
+}
+
+fn render_json(infos: &[CoinInfo], fields: &[String]) -> Value {
+    let rows = infos
+        .iter()
+        .map(|info| Value::Object(project(info, fields)))
+        .collect();
+    Value::Array(rows)
+}
+
+fn csv_cell(value: &Value) -> String {
+    let text = match value {
+        Value::String(s) => s.clone(),
+        Value::Null => String::new(),
+        other => other.to_string(),
+    };
+    if text.contains([',', '"', '\n']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text
+    }
+}
+
+fn render_csv(infos: &[CoinInfo], fields: &[String]) -> String {
+    let mut out = fields.join(",");
+    out.push('\n');
+    for info in infos {
+        let row = project(info, fields);
+        let cells: Vec<String> = fields
+            .iter()
+            .map(|field| row.get(field).map(csv_cell).unwrap_or_default())
+            .collect();
+        out.push_str(&cells.join(","));
+        out.push('\n');
+    }
+    out
+}
+
+// Coin id -> its history as fetched, in the API's own shape.
+pub fn render_raw_json(infos: &[CoinInfo]) -> String {
+    let raw: Map<String, Value> = infos
+        .iter()
+        .filter_map(|info| {
+            let data = serde_json::to_value(info.raw.as_ref()?).ok()?;
+            Some((info.name.clone(), data))
+        })
+        .collect();
+    Value::Object(raw).to_string() + "\n"
+}
+
+pub fn render_raw_csv(infos: &[CoinInfo]) -> String {
+    let mut out = String::from("coin,time,priceUsd\n");
+    for info in infos {
+        let Some(CoinData { data }) = &info.raw else {
+            continue;
+        };
+        for point in data {
+            out.push_str(&format!(
+                "{},{},{}\n",
+                csv_cell(&Value::String(info.name.clone())),
+                point.time,
+                csv_cell(&Value::String(point.priceUsd.clone()))
+            ));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_json_projects_requested_fields() {
+        let infos = vec![CoinInfo {
+            name: "bitcoin".to_string(),
+            all_time_high: 13.98,
+            all_time_low: 13.8,
+            current_price: 13.9,
+            ..Default::default()
+        }];
+        let fields = select_fields(&["name".to_string(), "current_price".to_string()]).unwrap();
+        let json = render_json(&infos, &fields);
+        let row = json[0].as_object().unwrap();
+        assert_eq!(
+            row.keys().collect::<Vec<_>>(),
+            vec!["name", "current_price"]
+        );
+        assert_eq!(row["name"], "bitcoin");
+        assert_eq!(row["current_price"], 13.9);
+        assert_eq!(
+            render_csv(&infos, &fields),
+            "name,current_price\nbitcoin,13.9\n"
+        );
+    }
+
+    #[test]
+    fn test_unset_optional_fields_are_left_out() {
+        let info = CoinInfo {
+            name: "bitcoin".to_string(),
+            ..Default::default()
+        };
+        let json = serde_json::to_value(&info).unwrap();
+        assert!(json.get("recent_high").is_none());
+        assert!(known_fields().contains(&"recent_high".to_string()));
+        assert!(!default_fields(&[]).contains(&"recent_high".to_string()));
+
+        let info = CoinInfo {
+            recent_high: Some(61.0),
+            ..info
+        };
+        let csv = render_csv(&[info], &default_fields(&["recent_high"]));
+        assert!(csv.lines().next().unwrap().ends_with(",recent_high"));
+        assert!(csv.lines().nth(1).unwrap().ends_with(",61.0"));
+    }
+
+    #[test]
+    fn test_select_fields_rejects_unknown_field() {
+        let err = select_fields(&["name".to_string(), "colour".to_string()]).unwrap_err();
+        assert!(err.contains("unknown field 'colour'"));
+        assert!(err.contains("name, all_time_high, all_time_low, current_price"));
+    }
+
+    // Records what had been written at each flush.
+    #[derive(Default)]
+    struct FlushRecorder {
+        buffer: Vec<u8>,
+        flushed: Vec<String>,
+    }
+
+    impl Write for FlushRecorder {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.buffer.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushed
+                .push(String::from_utf8_lossy(&self.buffer).into_owned());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_write_coin_flushes_each_coin() {
+        let coin = |name: &str| CoinInfo {
+            name: name.to_string(),
+            all_time_high: 20.0,
+            all_time_low: 10.0,
+            current_price: 15.0,
+            ..Default::default()
+        };
+        let mut sink = FlushRecorder::default();
+        let renderer = BarRenderer {
+            style: BarStyle::default(),
+        };
+        write_coin(&mut sink, &renderer, &coin("bitcoin")).unwrap();
+        let renderer = BarRenderer {
+            style: BarStyle {
+                price_decimals: Some(0),
+                ..Default::default()
+            },
+        };
+        write_coin(&mut sink, &renderer, &coin("ethereum")).unwrap();
+        assert_eq!(sink.flushed.len(), 2);
+        assert!(sink.flushed[0].ends_with("|bitcoin 15.00\n"));
+        assert!(!sink.flushed[0].contains("ethereum"));
+        assert!(sink.flushed[1].ends_with("|ethereum 15\n"));
+    }
+
+    #[test]
+    fn test_format_price_by_magnitude() {
+        assert_eq!(format_price(60123.456), "60123.46");
+        assert_eq!(format_price(1.5), "1.50");
+        assert_eq!(format_price(0.4567), "0.457");
+        assert_eq!(format_price(0.00912), "0.00912");
+        assert_eq!(format_price(0.0000213), "0.0000213");
+        assert_eq!(format_price(0.0), "0.00");
+    }
+
+    #[test]
+    fn test_each_renderer_gives_distinct_output() {
+        let infos = vec![
+            CoinInfo {
+                name: "bitcoin".to_string(),
+                all_time_high: 69000.0,
+                all_time_low: 13.8,
+                current_price: 23000.0,
+                window_change_percent: 166566.67,
+                ..Default::default()
+            },
+            CoinInfo {
+                name: "ethereum".to_string(),
+                all_time_high: 4800.0,
+                all_time_low: 0.43,
+                current_price: 1600.0,
+                window_change_percent: 372000.0,
+                ..Default::default()
+            },
+        ];
+        let fields = known_fields();
+        let renderers: Vec<Box<dyn Renderer>> = vec![
+            Box::new(BarRenderer {
+                style: BarStyle::default(),
+            }),
+            Box::new(TableRenderer {
+                price_decimals: None,
+                currency: None,
+                show_rank: false,
+                columns: DEFAULT_COLUMNS.to_vec(),
+                theme: Theme::default(),
+            }),
+            Box::new(JsonRenderer {
+                fields: fields.clone(),
+                pretty: false,
+            }),
+            Box::new(CsvRenderer { fields }),
+        ];
+        let outputs: Vec<String> = renderers.iter().map(|r| r.render(&infos)).collect();
+        for (i, output) in outputs.iter().enumerate() {
+            assert!(!output.is_empty());
+            assert!(output.contains("ethereum"));
+            assert!(outputs[i + 1..].iter().all(|other| other != output));
+        }
+        let table: Vec<&str> = outputs[1].lines().collect();
+        assert_eq!(table[0], "NAME         PRICE    LOW      HIGH       CHANGE");
+        assert_eq!(table[2], "ethereum   1600.00  0.430   4800.00  +372000.00%");
+    }
+
+    #[test]
+    fn test_rank_prefix_aligns_to_widest_rank() {
+        let coin = |name: &str, rank: u32| CoinInfo {
+            name: name.to_string(),
+            all_time_high: 20.0,
+            all_time_low: 10.0,
+            current_price: 15.0,
+            rank: Some(rank),
+            ..Default::default()
+        };
+        let infos = vec![coin("bitcoin", 1), coin("chainlink", 12)];
+        let bars = BarRenderer {
+            style: BarStyle {
+                show_rank: true,
+                ..Default::default()
+            },
+        };
+        assert!(!bars.streams());
+        let output = bars.render(&infos);
+        let lines: Vec<&str> = output.lines().collect();
+        assert!(lines[0].starts_with(" #1 "));
+        assert!(lines[1].starts_with("#12 "));
+        assert_eq!(lines[0].find('|'), lines[1].find('|'));
+
+        let table = TableRenderer {
+            price_decimals: None,
+            currency: None,
+            show_rank: true,
+            columns: DEFAULT_COLUMNS.to_vec(),
+            theme: Theme::default(),
+        }
+        .render(&infos);
+        let lines: Vec<&str> = table.lines().collect();
+        assert!(lines[0].starts_with("    NAME"));
+        assert!(lines[1].starts_with(" #1 bitcoin"));
+        assert!(lines[2].starts_with("#12 chainlink"));
+    }
+
+    #[test]
+    fn test_svg_path_has_a_point_per_price() {
+        let info = CoinInfo {
+            name: "bitcoin".to_string(),
+            all_time_high: 30.0,
+            all_time_low: 10.0,
+            current_price: 20.0,
+            history: vec![10.0, 30.0, 25.0, 20.0],
+            ..Default::default()
+        };
+        let svg = SvgRenderer { sample: 1 }.render(std::slice::from_ref(&info));
+        assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\""));
+        let path = svg
+            .lines()
+            .find(|line| line.starts_with("<path d=\""))
+            .unwrap();
+        assert_eq!(path.matches('M').count() + path.matches('L').count(), 4);
+        // the low sits on the bottom margin, the high on the top margin
+        assert!(path.contains("M20.0,180.0 L206.7,20.0"));
+
+        let sampled = SvgRenderer { sample: 2 }.render(&[info]);
+        let path = sampled
+            .lines()
+            .find(|line| line.starts_with("<path d=\""))
+            .unwrap();
+        assert_eq!(path.matches('M').count() + path.matches('L').count(), 3);
+    }
+
+    #[test]
+    fn test_links_only_when_enabled() {
+        let info = CoinInfo {
+            name: "bitcoin".to_string(),
+            all_time_high: 20.0,
+            all_time_low: 10.0,
+            current_price: 15.0,
+            ..Default::default()
+        };
+        let plain = bar_line(&info, &BarStyle::default());
+        assert!(!plain.contains('\x1b'));
+        assert!(plain.ends_with("|bitcoin 15.00"));
+        let linked = bar_line(
+            &info,
+            &BarStyle {
+                links: true,
+                ..Default::default()
+            },
+        );
+        assert!(linked.ends_with(
+            "|\x1b]8;;https://coincap.io/assets/bitcoin\x1b\\bitcoin\x1b]8;;\x1b\\ 15.00"
+        ));
+        let info = CoinInfo {
+            symbol: Some("BTC".to_string()),
+            ..info
+        };
+        let linked = bar_line(
+            &info,
+            &BarStyle {
+                links: true,
+                ..Default::default()
+            },
+        );
+        assert!(linked
+            .ends_with("|\x1b]8;;https://coincap.io/assets/bitcoin\x1b\\BTC\x1b]8;;\x1b\\ 15.00"));
+    }
+
+    #[test]
+    fn test_bar_against_baseline() {
+        assert_eq!(gain_percent(36000.0, 30000.0), 20.0);
+        assert_eq!(gain_percent(15000.0, 30000.0), -50.0);
+
+        let info = CoinInfo {
+            name: "bitcoin".to_string(),
+            symbol: Some("BTC".to_string()),
+            all_time_high: 69000.0,
+            all_time_low: 60.0,
+            current_price: 36000.0,
+            ..Default::default()
+        };
+        let style = BarStyle {
+            price_decimals: Some(0),
+            baselines: vec![("btc".to_string(), 30000.0)],
+            ..Default::default()
+        };
+        let line = bar_line(&info, &style);
+        assert!(line.starts_with("        60%|"));
+        assert!(line.ends_with("|bitcoin 36000 (+20.00% vs 30000)"));
+
+        let other = CoinInfo {
+            name: "ethereum".to_string(),
+            all_time_high: 69000.0,
+            all_time_low: 60.0,
+            current_price: 36000.0,
+            ..Default::default()
+        };
+        assert!(bar_line(&other, &style).ends_with("|ethereum 36000"));
+    }
+
+    #[test]
+    fn test_recent_range_ticks() {
+        let mut info = CoinInfo {
+            name: "bitcoin".to_string(),
+            all_time_high: 100.0,
+            all_time_low: 0.0,
+            current_price: 50.0,
+            recent_low: Some(20.0),
+            recent_high: Some(61.0),
+            ..Default::default()
+        };
+        let style = BarStyle {
+            ticks: true,
+            ..Default::default()
+        };
+        let cells = |line: String| -> Vec<char> {
+            let start = line.find('|').unwrap();
+            line[start + 1..].chars().take(BAR_CELLS).collect()
+        };
+        let bar = cells(bar_line(&info, &style));
+        assert_eq!(bar[10], RECENT_LOW_TICK);
+        assert_eq!(bar[30], RECENT_HIGH_TICK);
+        assert_eq!(bar.iter().filter(|c| **c == '█').count(), 24);
+
+        // Overlapping ticks share one marker, and the top of the range
+        // lands in the last cell.
+        info.recent_low = Some(99.0);
+        info.recent_high = Some(100.0);
+        let bar = cells(bar_line(&info, &style));
+        assert_eq!(bar[49], RECENT_BOTH_TICK);
+        assert!(!bar.contains(&RECENT_LOW_TICK));
+
+        let plain = cells(bar_line(&info, &BarStyle::default()));
+        assert!(!plain.contains(&RECENT_BOTH_TICK));
+    }
+
+    #[test]
+    fn test_render_detail_lists_every_stat() {
+        let info = CoinInfo {
+            name: "bitcoin".to_string(),
+            all_time_high: 30.0,
+            all_time_low: 10.0,
+            current_price: 20.0,
+            window_change_percent: 100.0,
+            all_time_high_time: Some(1357084800000),
+            all_time_low_time: Some(1356998400000),
+            sharpe_ratio: Some(12.5),
+            history: (0..20).map(|i| 10.0 + (i % 5) as f64 * 5.0).collect(),
+            ..Default::default()
+        };
+        let detail = render_detail(&info, None, None, None);
+        assert!(detail.starts_with("bitcoin\n"));
+        for label in [
+            "price",
+            "high",
+            "low",
+            "change",
+            "volatility",
+            "sharpe",
+            "rsi",
+            "trend",
+            "history",
+        ] {
+            assert!(
+                detail
+                    .lines()
+                    .any(|line| line.trim_start().starts_with(label)),
+                "missing {}",
+                label
+            );
+        }
+        assert!(detail.contains("30.00 on 2013-01-02"));
+        assert!(detail.contains("10.00 on 2013-01-01"));
+        assert!(detail.contains("12.50\n"));
+        assert!(!detail.contains(" -\n"));
+        let later = crate::clock::FixedClock::at_millis(1357084800000 + 100 * 86_400_000);
+        let detail = render_detail(&info, None, None, Some(&later));
+        assert!(
+            detail.contains("30.00 on 2013-01-02 (3 months ago)"),
+            "{}",
+            detail
+        );
+        assert_eq!(sparkline(&[1.0, 2.0, 3.0], 3), "▁▅█");
+    }
+
+    #[test]
+    fn test_raw_json_round_trips() {
+        let raw = CoinData {
+            data: vec![
+                crate::PriceData {
+                    priceUsd: "13.8".to_string(),
+                    time: 1356998400000,
+                },
+                crate::PriceData {
+                    priceUsd: "1,013.9".to_string(),
+                    time: 1357084800000,
+                },
+            ],
+        };
+        let infos = vec![CoinInfo {
+            name: "bitcoin".to_string(),
+            raw: Some(raw.clone()),
+            ..Default::default()
+        }];
+        let json: Value = serde_json::from_str(&render_raw_json(&infos)).unwrap();
+        let parsed: CoinData = serde_json::from_value(json["bitcoin"].clone()).unwrap();
+        assert_eq!(parsed, raw);
+        assert_eq!(
+            render_raw_csv(&infos),
+            "coin,time,priceUsd\nbitcoin,1356998400000,13.8\nbitcoin,1357084800000,\"1,013.9\"\n"
+        );
+    }
+
+    #[test]
+    fn test_ndjson_stats_one_event_per_metric() {
+        let infos = vec![
+            CoinInfo {
+                name: "bitcoin".to_string(),
+                all_time_high: 69000.0,
+                rank: Some(1),
+                ..Default::default()
+            },
+            CoinInfo {
+                name: "ethereum".to_string(),
+                ..Default::default()
+            },
+        ];
+        let out = NdjsonStatsRenderer.render(&infos);
+        let events: Vec<Value> = out
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(events.len(), 2 * STAT_METRICS.len());
+        for (coin, chunk) in ["bitcoin", "ethereum"]
+            .iter()
+            .zip(events.chunks(STAT_METRICS.len()))
+        {
+            let metrics: Vec<&str> = chunk
+                .iter()
+                .map(|event| {
+                    assert_eq!(event["coin"], *coin);
+                    event["metric"].as_str().unwrap()
+                })
+                .collect();
+            assert_eq!(metrics, STAT_METRICS);
+        }
+        assert_eq!(events[1]["value"], 69000.0);
+        assert_eq!(events[10]["value"], 1);
+        assert!(events[STAT_METRICS.len() + 10]["value"].is_null());
+    }
+
+    #[test]
+    fn test_rank_tiers_bucket_by_rank() {
+        let coin = |name: &str, rank: Option<u32>| CoinInfo {
+            name: name.to_string(),
+            rank,
+            ..Default::default()
+        };
+        let mut infos = vec![
+            coin("unranked", None),
+            coin("c", Some(51)),
+            coin("a", Some(1)),
+            coin("d", Some(250)),
+            coin("b", Some(10)),
+        ];
+        let tiers = rank_tiers(&mut infos, &DEFAULT_TIERS);
+        let names = |range: &Range<usize>| -> Vec<&str> {
+            infos[range.clone()]
+                .iter()
+                .map(|info| info.name.as_str())
+                .collect()
+        };
+        let buckets: Vec<(&str, Vec<&str>)> = tiers
+            .iter()
+            .map(|(label, range)| (label.as_str(), names(range)))
+            .collect();
+        assert_eq!(
+            buckets,
+            vec![
+                ("Top 10", vec!["a", "b"]),
+                ("51-100", vec!["c"]),
+                ("101+", vec!["d", "unranked"]),
+            ]
+        );
+
+        let tiers = rank_tiers(&mut infos, &[1]);
+        assert_eq!(tiers[0], ("Top 1".to_string(), 0..1));
+        assert_eq!(tiers[1], ("2+".to_string(), 1..5));
+    }
+
+    #[test]
+    fn test_truncate_name_on_grapheme_boundaries() {
+        assert_eq!(truncate_name("bitcoin", 10), "bitcoin");
+        assert_eq!(truncate_name("wrapped-bitcoin", 8), "wrapped…");
+        // Each of these characters is two columns wide.
+        let wide = truncate_name("比特币现金经典版", 7);
+        assert_eq!(wide, "比特币…");
+        assert_eq!(wide.width(), 7);
+        // "e" plus a combining accent is one grapheme and stays together.
+        let accented = truncate_name("cafe\u{301}-cafe\u{301}-token", 6);
+        assert_eq!(accented, "cafe\u{301}-…");
+        assert!(accented.width() <= 6);
+
+        let table = TableRenderer {
+            price_decimals: Some(0),
+            currency: None,
+            show_rank: false,
+            columns: DEFAULT_COLUMNS.to_vec(),
+            theme: Theme::default(),
+        }
+        .render(&[CoinInfo {
+            name: "a-coin-with-a-really-quite-long-name".to_string(),
+            ..Default::default()
+        }]);
+        let row = table.lines().nth(1).unwrap();
+        assert!(row.starts_with(&format!(
+            "{}  ",
+            truncate_name("a-coin-with-a-really-quite-long-name", MAX_NAME_WIDTH)
+        )));
+    }
+
+    #[test]
+    fn test_prometheus_gauges_per_metric() {
+        let infos = vec![
+            CoinInfo {
+                name: "bitcoin".to_string(),
+                current_price: 50000.5,
+                rank: Some(1),
+                ..Default::default()
+            },
+            CoinInfo {
+                name: "say \"hi\"".to_string(),
+                current_price: 2.0,
+                ..Default::default()
+            },
+        ];
+        let out = PrometheusRenderer { openmetrics: false }.render(&infos);
+        assert!(out.starts_with(
+            "# TYPE coin_price gauge\n\
+             coin_price{coin=\"bitcoin\"} 50000.5\n\
+             coin_price{coin=\"say \\\"hi\\\"\"} 2\n\
+             # TYPE coin_ath gauge\n"
+        ));
+        assert!(out.contains("# TYPE coin_rank gauge\ncoin_rank{coin=\"bitcoin\"} 1\n"));
+        assert_eq!(out.matches("# TYPE").count(), STAT_METRICS.len());
+        assert!(!out.contains("# HELP") && !out.contains("# EOF"));
+
+        let out = PrometheusRenderer { openmetrics: true }.render(&infos);
+        assert!(out.starts_with(
+            "# TYPE coin_price gauge\n\
+             # HELP coin_price Current price\n\
+             coin_price{coin=\"bitcoin\"} 50000.5\n"
+        ));
+        assert_eq!(out.matches("# HELP").count(), STAT_METRICS.len());
+        assert!(out.ends_with("coin_rank{coin=\"bitcoin\"} 1\n# EOF\n"));
+        assert_eq!(out.matches("# EOF").count(), 1);
+    }
+
+    #[test]
+    fn test_relative_renderer_shows_spread() {
+        let coin = |name: &str, change: f64, spread: f64| CoinInfo {
+            name: name.to_string(),
+            window_change_percent: change,
+            relative_change_percent: Some(spread),
+            ..Default::default()
+        };
+        let renderer = RelativeRenderer {
+            benchmark: "bitcoin".to_string(),
+        };
+        assert_eq!(
+            renderer.render(&[coin("ethereum", 25.0, 15.0), coin("doge", -5.0, -15.0)]),
+            "ethereum    +25.00%    +15.00% vs bitcoin\n\
+             doge         -5.00%    -15.00% vs bitcoin\n"
+        );
+    }
+
+    #[test]
+    fn test_themes_draw_distinct_bars() {
+        let info = CoinInfo {
+            name: "bitcoin".to_string(),
+            all_time_high: 100.0,
+            all_time_low: 0.0,
+            current_price: 50.0,
+            ..Default::default()
+        };
+        let bars: Vec<String> = THEMES
+            .iter()
+            .map(|theme| {
+                bar_line(
+                    &info,
+                    &BarStyle {
+                        theme: *theme,
+                        ..Default::default()
+                    },
+                )
+            })
+            .collect();
+        for (i, bar) in bars.iter().enumerate() {
+            assert!(bar.starts_with("        50%|"), "{}", bar);
+            assert!(bar.ends_with("|bitcoin 50.00"), "{}", bar);
+            assert!(
+                !bars[..i].contains(bar),
+                "{} repeats an earlier theme",
+                THEMES[i].name
+            );
+        }
+        assert_eq!(bars[0], bar_line(&info, &BarStyle::default()));
+        assert!(bars[1].contains(&"●".repeat(25)));
+        // Green at the left end of the bar through to red at the right.
+        assert!(bars[3].contains("|\x1b[38;2;0;255;0m█\x1b[38;2;5;250;0m█"));
+        assert!(bars[3].contains("\x1b[38;2;255;0;0m░\x1b[0m|"));
+        let plain = BarStyle {
+            theme: THEMES[3].plain(),
+            ..Default::default()
+        };
+        assert_eq!(bar_line(&info, &plain), bars[0]);
+        assert_eq!(theme("HEAT").map(|theme| theme.name), Some("heat"));
+        assert!(theme("neon").is_none());
+    }
+
+    #[test]
+    fn test_supply_shown_for_capped_and_uncapped() {
+        let style = BarStyle {
+            show_supply: true,
+            ..Default::default()
+        };
+        let coin = |supply: Option<f64>, max_supply: Option<f64>| CoinInfo {
+            name: "coin".to_string(),
+            all_time_high: 2.0,
+            all_time_low: 0.0,
+            current_price: 1.0,
+            supply,
+            max_supply,
+            supply_percent: supply.and_then(|supply| crate::supply_percent(supply, max_supply)),
+            ..Default::default()
+        };
+        assert!(
+            bar_line(&coin(Some(19_500_000.0), Some(21_000_000.0)), &style)
+                .ends_with("|coin 1.00 supply 19.50M of 21.00M (92.9%)")
+        );
+        assert!(bar_line(&coin(Some(120_000_000.0), None), &style)
+            .ends_with("|coin 1.00 supply 120.00M (uncapped)"));
+        assert!(bar_line(&coin(None, None), &style).ends_with("|coin 1.00"));
+    }
+
+    #[test]
+    fn test_template_substitutes_fields() {
+        let info = CoinInfo {
+            name: "bitcoin".to_string(),
+            symbol: Some("BTC".to_string()),
+            rank: Some(1),
+            all_time_high: 100.0,
+            all_time_low: 0.0,
+            current_price: 10.0,
+            window_change_percent: 5.0,
+            ..Default::default()
+        };
+        let renderer = TemplateRenderer {
+            template: Template::parse("#{rank} {symbol} {price} {bar} {change} {{{position}}}")
+                .unwrap(),
+            price_decimals: Some(0),
+            currency: None,
+            theme: Theme::default(),
+        };
+        assert_eq!(
+            renderer.render(std::slice::from_ref(&info)),
+            format!(
+                "#1 BTC 10 {}{} +5.00% {{10.00%}}\n",
+                "█".repeat(5),
+                "░".repeat(45)
+            )
+        );
+        let dots = TemplateRenderer {
+            template: Template::parse("{bar}").unwrap(),
+            theme: theme("dots").unwrap(),
+            ..renderer
+        };
+        assert_eq!(
+            dots.render(std::slice::from_ref(&info)),
+            format!("{}{}\n", "●".repeat(5), "○".repeat(45))
+        );
+        let heat = TemplateRenderer {
+            theme: theme("heat").unwrap(),
+            ..dots
+        };
+        let bar = heat.render(std::slice::from_ref(&info));
+        assert!(bar.starts_with("\x1b[38;2;0;255;0m█"), "{:?}", bar);
+        assert!(bar.ends_with("\x1b[38;2;255;0;0m░\x1b[0m\n"), "{:?}", bar);
+        let err = Template::parse("{price} {volume}").unwrap_err();
+        assert!(err.starts_with("unknown placeholder {volume}"), "{}", err);
+        assert!(Template::parse("{price").is_err());
+        assert!(Template::parse("price}").is_err());
+    }
+
+    #[test]
+    fn test_explain_bar_position() {
+        let info = CoinInfo {
+            name: "bitcoin".to_string(),
+            all_time_high: 200.0,
+            all_time_low: 100.0,
+            current_price: 142.0,
+            ..Default::default()
+        };
+        let day = 24 * 60 * 60 * 1000;
+        let window = Window {
+            start_ms: 0,
+            end_ms: 30 * day,
+        };
+        assert_eq!(
+            explain(&info, window, None),
+            "bitcoin: current $142.00 is 42.00% of the way from ATL $100.00 to ATH $200.00 over 30 days"
+        );
+        let flat = CoinInfo {
+            all_time_low: 142.0,
+            all_time_high: 142.0,
+            ..info
+        };
+        let hours = Window {
+            start_ms: 0,
+            end_ms: day / 2,
+        };
+        assert_eq!(
+            explain(&flat, hours, Some("EUR")),
+            "bitcoin: current 142.00 EUR can't be placed on the range from ATL 142.00 EUR to ATH 142.00 EUR over 12 hours"
+        );
+    }
+
+    #[test]
+    fn test_position_percent_negative_range() {
+        let info = |low: f64, high: f64, current_price: f64| CoinInfo {
+            all_time_low: low,
+            all_time_high: high,
+            current_price,
+            ..Default::default()
+        };
+        let position = position_percent(&info(-0.08, -0.01, -0.03)).unwrap();
+        assert!((position - 500.0 / 7.0).abs() < 1e-9, "{}", position);
+        assert_eq!(position_percent(&info(-0.08, -0.01, -0.2)), Some(0.0));
+        assert_eq!(position_percent(&info(-0.08, -0.01, 0.5)), Some(100.0));
+        assert_eq!(position_percent(&info(-0.01, -0.08, -0.03)), None);
+    }
+
+    #[test]
+    fn test_json_pretty_toggle() {
+        let infos = vec![CoinInfo {
+            name: "bitcoin".to_string(),
+            current_price: 13.9,
+            ..Default::default()
+        }];
+        let json = |pretty| {
+            JsonRenderer {
+                fields: vec!["name".to_string(), "current_price".to_string()],
+                pretty,
+            }
+            .render(&infos)
+        };
+        let compact = json(false);
+        assert_eq!(compact, "[{\"name\":\"bitcoin\",\"current_price\":13.9}]\n");
+        let pretty = json(true);
+        assert!(
+            pretty.contains("\n  {\n    \"name\": \"bitcoin\",\n"),
+            "{}",
+            pretty
+        );
+        let parsed: Value = serde_json::from_str(&pretty).unwrap();
+        assert_eq!(parsed, serde_json::from_str::<Value>(&compact).unwrap());
+    }
+
+    #[test]
+    fn test_prices_use_currency_decimals() {
+        assert_eq!(currency_decimals("jpy"), 0);
+        assert_eq!(currency_decimals("BHD"), 3);
+        assert_eq!(currency_decimals("XYZ"), 2);
+        assert_eq!(format_price_in(4_500_000.4, Some("JPY")), "4500000");
+        assert_eq!(format_price_in(30_000.5, Some("USD")), "30000.50");
+        assert_eq!(format_price_in(30_000.5, None), "30000.50");
+        assert_eq!(format_price_in(1.5, Some("BHD")), "1.500");
+        // Sub-unit prices keep their significant digits whatever the currency.
+        assert_eq!(format_price_in(0.0042, Some("JPY")), "0.00420");
+
+        let info = CoinInfo {
+            name: "bitcoin".to_string(),
+            all_time_high: 9_800_000.0,
+            all_time_low: 8_000.0,
+            current_price: 4_500_000.4,
+            ..Default::default()
+        };
+        let table = |currency: &str| TableRenderer {
+            price_decimals: None,
+            currency: Some(currency.to_string()),
+            show_rank: false,
+            columns: DEFAULT_COLUMNS.to_vec(),
+            theme: Theme::default(),
+        };
+        let yen = table("JPY").render(std::slice::from_ref(&info));
+        assert!(yen.contains("4500000  8000  9800000"), "{}", yen);
+        let dollars = table("USD").render(&[info]);
+        assert!(
+            dollars.contains("4500000.40  8000.00  9800000.00"),
+            "{}",
+            dollars
+        );
+    }
+
+    #[test]
+    fn test_round2() {
+        assert_eq!(round2(42.0), 42.0);
+        assert_eq!(round2(33.333_333), 33.33);
+        assert_eq!(round2(66.666_666), 66.67);
+        assert_eq!(round2(0.125), 0.13);
+        assert_eq!(round2(-1.005_1), -1.01);
+        assert_eq!(round2(99.999), 100.0);
+        assert_eq!(round2(-0.001).to_string(), "0");
+        assert_eq!(round2(f64::INFINITY), f64::INFINITY);
+        assert!(round2(f64::NAN).is_nan());
+    }
+
+    #[test]
+    fn test_render_diff_arrows() {
+        let deltas = [
+            CoinDelta::Changed {
+                name: "bitcoin".to_string(),
+                price_change: 1_000.0,
+                price_change_percent: Some(5.0),
+                rank_change: Some(0),
+            },
+            CoinDelta::Changed {
+                name: "solana".to_string(),
+                price_change: -2.0,
+                price_change_percent: Some(-10.0),
+                rank_change: Some(-2),
+            },
+            CoinDelta::Added("pepe".to_string()),
+            CoinDelta::Removed("litecoin".to_string()),
+        ];
+        assert_eq!(
+            render_diff(&deltas),
+            "bitcoin ▲ +1000.00 (+5.00%)\n\
+             solana ▼ -2.00 (-10.00%) rank ▼2\n\
+             + pepe (new)\n\
+             - litecoin (gone)\n"
+        );
+    }
+
+    #[test]
+    fn test_near_flat_range_shows_indicator() {
+        let info = |low: f64, high: f64| CoinInfo {
+            name: "usd-coin".to_string(),
+            all_time_low: low,
+            all_time_high: high,
+            current_price: (low + high) / 2.0,
+            recent_low: Some(low),
+            recent_high: Some(high),
+            ..Default::default()
+        };
+        let style = BarStyle {
+            ticks: true,
+            ..Default::default()
+        };
+        let flat = bar_line(&info(0.99999, 1.00001), &style);
+        assert_eq!(
+            flat,
+            format!("      ≈flat|{}|usd-coin 1.00", "─".repeat(BAR_CELLS))
+        );
+        // A narrow range that's still wide enough is drawn as usual.
+        let narrow = bar_line(&info(0.99, 1.01), &style);
+        assert!(narrow.starts_with("        50%|["), "{}", narrow);
+        assert!(!is_near_flat(1.0, 1.0));
+        assert!(is_near_flat(-1.0, -1.000_01));
+    }
+
+    #[test]
+    fn test_table_columns_follow_requested_order() {
+        let requested = ["rank", "Symbol", "price", "change", "bar"].map(String::from);
+        let renderer = TableRenderer {
+            price_decimals: None,
+            currency: None,
+            show_rank: false,
+            columns: select_columns(&requested).unwrap(),
+            theme: Theme::default(),
+        };
+        let info = CoinInfo {
+            name: "bitcoin".to_string(),
+            symbol: Some("BTC".to_string()),
+            rank: Some(1),
+            all_time_low: 10.0,
+            all_time_high: 30.0,
+            current_price: 20.0,
+            window_change_percent: 5.0,
+            ..Default::default()
+        };
+        let table = renderer.render(std::slice::from_ref(&info));
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines[0], "RANK  SYMBOL  PRICE  CHANGE  BAR");
+        assert_eq!(
+            lines[1],
+            format!(
+                "  #1  BTC     20.00  +5.00%  {}{}",
+                "█".repeat(25),
+                "░".repeat(BAR_CELLS - 25)
+            )
+        );
+        // A rank column already shows the rank.
+        let ranked = TableRenderer {
+            show_rank: true,
+            ..renderer
+        };
+        assert_eq!(ranked.render(std::slice::from_ref(&info)), table);
+        let dots = TableRenderer {
+            theme: theme("dots").unwrap(),
+            ..ranked
+        };
+        let table = dots.render(std::slice::from_ref(&info));
+        assert!(table.ends_with(&format!("{}{}\n", "●".repeat(25), "○".repeat(25))));
+        // Colour codes don't count towards a column's width.
+        let heat = TableRenderer {
+            show_rank: false,
+            theme: theme("heat").unwrap(),
+            columns: vec!["bar", "name"],
+            ..dots
+        };
+        let table = heat.render(&[info]);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines[0], format!("BAR{}  NAME", " ".repeat(BAR_CELLS - 3)));
+        assert!(lines[1].ends_with("\x1b[0m  bitcoin"), "{:?}", lines[1]);
+        let err = select_columns(&["price".to_string(), "volume".to_string()]).unwrap_err();
+        assert!(err.starts_with("unknown column 'volume', valid columns are: rank, name"));
+    }
+
+    #[test]
+    fn test_sample_keeps_every_nth_and_the_last() {
+        let prices: Vec<f64> = (0..10).map(f64::from).collect();
+        assert_eq!(sample(&prices, 3), [0.0, 3.0, 6.0, 9.0]);
+        assert_eq!(sample(&prices, 4), [0.0, 4.0, 8.0, 9.0]);
+        assert_eq!(sample(&prices, 100), [0.0, 9.0]);
+        assert_eq!(sample(&prices, 0).len(), 10);
+        assert!(sample(&[], 5).is_empty());
+    }
+
+    #[test]
+    fn test_overlay_adds_range_bar_and_recent_sparkline() {
+        let long = CoinInfo {
+            name: "bitcoin".to_string(),
+            all_time_high: 30.0,
+            all_time_low: 10.0,
+            current_price: 20.0,
+            history: vec![10.0, 30.0, 20.0],
+            ..Default::default()
+        };
+        let recent = CoinInfo {
+            name: "bitcoin".to_string(),
+            all_time_high: 21.0,
+            all_time_low: 19.0,
+            current_price: 20.0,
+            history: vec![19.0, 21.0, 20.0],
+            ..Default::default()
+        };
+        let overlay = render_overlay(&long, "h1", &recent, None, None, None);
+        let detail = render_detail(&long, None, None, None);
+        assert!(overlay.starts_with(&detail));
+        let extra: Vec<&str> = overlay.lines().skip(detail.lines().count()).collect();
+        assert_eq!(extra.len(), 2);
+        assert!(extra[0].starts_with(&format!("  range       {}", "█".repeat(25))));
+        assert_eq!(extra[1], "  last h1     ▁█▅ 19.00–21.00");
+
+        let overlay = render_overlay(&long, "h1", &recent, None, Some("JPY"), None);
+        assert!(overlay.contains("  price       20\n"), "{}", overlay);
+        assert!(overlay.ends_with("▁█▅ 19–21\n"), "{}", overlay);
+        let overlay = render_overlay(&long, "h1", &recent, Some(3), Some("JPY"), None);
+        assert!(overlay.contains("  high        30.000\n"), "{}", overlay);
+        assert!(overlay.ends_with("▁█▅ 19.000–21.000\n"), "{}", overlay);
+    }
+}
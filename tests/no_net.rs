@@ -0,0 +1,29 @@
+// Needs nothing from the net feature, so this also builds and runs under
+// --no-default-features, as CI does.
+use coin_data::render::{render_detail, sparkline};
+use coin_data::{get_coin_info, rsi, volatility, CoinData, PriceData};
+
+fn history(prices: &[&str]) -> CoinData {
+    CoinData {
+        data: prices
+            .iter()
+            .enumerate()
+            .map(|(i, price)| PriceData {
+                priceUsd: price.to_string(),
+                time: 1_356_998_400_000 + i as u64 * 86_400_000,
+            })
+            .collect(),
+    }
+}
+
+#[test]
+fn test_stats_without_fetching() {
+    let data = history(&["10", "12", "11", "15"]);
+    let info = futures::executor::block_on(get_coin_info(data, "coin")).unwrap();
+    assert_eq!(info.current_price, 15.0);
+    assert_eq!(info.all_time_low, 10.0);
+    assert!(volatility(&info.history).is_some());
+    assert_eq!(rsi(&info.history, 14), None);
+    assert_eq!(sparkline(&info.history, 4).chars().count(), 4);
+    assert!(render_detail(&info, None, None, None).contains("coin"));
+}